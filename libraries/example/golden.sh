@@ -0,0 +1,29 @@
+#!/bin/sh
+# Compare the traces of the example scenarios with their golden traces in golden/
+#
+# usage: ./golden.sh [--bless] [scenario names]
+# Without names all scenarios are checked, --bless accepts the new traces.
+UNTIL=${UNTIL:-300000}
+TIMEOUT=${TIMEOUT:-60}
+BLESS=
+if [ "$1" = "--bless" ]; then
+    BLESS=--bless
+    shift
+fi
+
+NAMES="$*"
+[ -z "$NAMES" ] && NAMES=$(ls scenarios | sed "s/\\.toml$//")
+
+cd ../../simulator && cargo build --release || exit 1
+cd golden && cargo build --release || exit 1
+cd ../../libraries/example/ && cargo build --release || exit 1
+
+FAILED=0
+for NAME in $NAMES; do
+    echo "Scenario $NAME"
+    ../../simulator/golden/target/release/peersim-golden $BLESS --golden golden/$NAME.trace \
+        --until $UNTIL --timeout $TIMEOUT --preload ../../simulator/target/release/libpeersim.so \
+        --output target/golden -- target/release/peer_simulator simulate --scenario scenarios/$NAME.toml || FAILED=1
+done
+
+exit $FAILED
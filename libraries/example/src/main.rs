@@ -5,10 +5,46 @@ extern crate tokio;
 #[macro_use]
 extern crate futures;
 extern crate gossip;
+extern crate scenario;
 
 mod simulate;
 
+use std::env;
+use std::fs;
+use std::process::{self, Command};
+use std::path::{Path, PathBuf};
+use std::os::unix::process::parent_id;
+
 use clap::{SubCommand, App, Arg};
+use scenario::{Scenario, SCENARIO_ENV, regions};
+
+/// Temporary scenario file of the regions preset started by the process `pid`
+fn regions_path(pid: u32) -> PathBuf {
+    env::temp_dir().join(format!("peersim-regions-{}.toml", pid))
+}
+
+/// Run this program again with the scenario at `path` in the environment and return its
+/// exit code
+///
+/// The simulator reads the scenario from the environment once it is loaded, so setting the
+/// variable from `main` could come after it was read. The new process has it from the start.
+fn relaunch(path: &Path) -> i32 {
+    let exe = env::current_exe().unwrap_or_else(|err| {
+        eprintln!("Could not find the running program: {}", err);
+        process::exit(1);
+    });
+
+    let status = Command::new(exe)
+        .args(env::args_os().skip(1))
+        .env(SCENARIO_ENV, path)
+        .status()
+        .unwrap_or_else(|err| {
+            eprintln!("Could not start the simulation: {}", err);
+            process::exit(1);
+        });
+
+    status.code().unwrap_or(1)
+}
 
 fn main() {
     let matches = App::new("Peer simulator")
@@ -19,8 +55,30 @@ fn main() {
             .arg(Arg::with_name("nodes")
                  .short("n")
                  .long("nodes")
-                 .required(true)
+                 .required_unless("scenario")
+                 .takes_value(true)
+            )
+            .arg(Arg::with_name("scenario")
+                 .short("s")
+                 .long("scenario")
+                 .help("TOML file describing the simulated network")
+                 .takes_value(true)
+            )
+            .arg(Arg::with_name("regions")
+                 .short("r")
+                 .long("regions")
+                 .help("spread the nodes over regions with real latencies: eu, na, sa, apac")
+                 .conflicts_with("scenario")
+                 .takes_value(true)
+                 .use_delimiter(true)
+                 .multiple(true)
+            )
+            .arg(Arg::with_name("only")
+                 .long("only")
+                 .help("start only these nodes of the scenario, the others run in other processes of a coordinator")
+                 .requires("scenario")
                  .takes_value(true)
+                 .multiple(true)
             )
         )
         .subcommand(SubCommand::with_name("display")
@@ -32,10 +90,62 @@ fn main() {
         ).get_matches();
 
     if let Some(matches) = matches.subcommand_matches("simulate") {
+        if let Some(path) = matches.value_of("scenario") {
+            let scenario = Scenario::from_file(path).unwrap_or_else(|err| {
+                eprintln!("{}", err);
+                process::exit(1);
+            });
+
+            // the simulator reads the same scenario through the environment
+            if env::var_os(SCENARIO_ENV).as_ref().map(Path::new) != Some(Path::new(path)) {
+                process::exit(relaunch(Path::new(path)));
+            }
+
+            let only = matches.values_of("only")
+                .map(|x| x.map(|x| x.to_string()).collect::<Vec<_>>());
+
+            simulate::start_scenario(scenario, only);
+
+            return;
+        }
+
         let num_nodes = matches.value_of("nodes")
             .and_then(|x| x.parse::<usize>().ok())
             .unwrap_or(100);
 
+        if let Some(names) = matches.values_of("regions") {
+            // started again by ourselves, with the preset already written for the simulator
+            let path = regions_path(parent_id());
+            if env::var_os(SCENARIO_ENV).map(PathBuf::from) == Some(path.clone()) {
+                let source = fs::read_to_string(&path).unwrap_or_else(|err| {
+                    eprintln!("Could not read {}: {}", path.display(), err);
+                    process::exit(1);
+                });
+
+                simulate::start_scenario(source.parse().unwrap(), None);
+
+                return;
+            }
+
+            let names = names.collect::<Vec<_>>();
+            let source = regions::preset(&names, num_nodes).unwrap_or_else(|err| {
+                eprintln!("{}", err);
+                process::exit(1);
+            });
+
+            // the simulator only reads scenarios from files
+            let path = regions_path(process::id());
+            if let Err(err) = fs::write(&path, &source) {
+                eprintln!("Could not write {}: {}", path.display(), err);
+                process::exit(1);
+            }
+
+            let code = relaunch(&path);
+            let _ = fs::remove_file(&path);
+
+            process::exit(code);
+        }
+
         simulate::start(num_nodes);
     }
 }
@@ -1,4 +1,5 @@
 use std::env::args;
+use std::cmp;
 use std::collections::HashMap;
 use std::time::{Duration, Instant};
 
@@ -7,6 +8,7 @@ use tokio::timer;
 use std::net::{SocketAddr, SocketAddrV4, Ipv4Addr};
 
 use rand::Rng;
+use scenario::Scenario;
 
 struct AddressSpace(HashMap<u32, ()>);
 
@@ -74,3 +76,52 @@ pub fn start(num_nodes: usize) {
 
     tokio::run(future::join_all(nodes).map(|_| ()).map_err(|_| ()));
 }
+
+/// Start all nodes of a scenario
+///
+/// The example only cares about the addresses, contacts and pushes. Join times, latencies and
+/// faults are applied by the simulator itself. With `only` just the named nodes and their
+/// pushes are started.
+pub fn start_scenario(scenario: Scenario, only: Option<Vec<String>>) {
+    let mut nodes: Vec<Box<dyn Future<Item=(), Error=()> + Send>> = Vec::new();
+    let mut writers = HashMap::new();
+
+    let started = |name: &String| only.as_ref().map(|x| x.contains(name)).unwrap_or(true);
+
+    for node in scenario.nodes().iter().filter(|x| started(&x.name)) {
+        let gossip = gossip::Gossip::new(
+            SocketAddr::from(node.addr), node.contact.map(SocketAddr::from), node.name.clone()
+        );
+
+        writers.insert(node.name.clone(), gossip.writer());
+
+        let name = node.name.clone();
+        let gossip = gossip.for_each(move |block| {
+                println!("New block in {}: {:?}", name, block);
+
+                Ok(())
+            }).into_future().map_err(|_| ());
+
+        nodes.push(Box::new(gossip));
+    }
+
+    for push in scenario.pushes.iter().filter(|x| started(&x.node)) {
+        let writer = writers[&push.node].clone();
+        let size = push.size;
+
+        let start = Instant::now() + Duration::from_micros(push.at);
+        let every = Duration::from_micros(cmp::max(push.every, 1));
+
+        let pushes = timer::Interval::new(start, every)
+            .take(push.count as u64)
+            .fold(0u8, move |i, _| {
+                writer.push(vec![i; size]);
+
+                Ok(i.wrapping_add(1))
+            }).map(|_| ()).map_err(|_| ());
+
+        nodes.push(Box::new(pushes));
+    }
+
+    tokio::run(future::join_all(nodes).map(|_| ()).map_err(|_| ()));
+}
@@ -50,7 +50,9 @@ impl GossipPush {
 
         for writer in peers.iter_mut() {
             writer.buffer(Packet::Push(data.clone()));
-            writer.poll_flush().unwrap();
+
+            // a peer which closed its connection doesn't get the block
+            let _ = writer.poll_flush();
         }
     }
 }
@@ -119,7 +119,10 @@ impl Future for Peer {
                 }
             },
 
-            Peer::WaitForJoin((mut read, write)) => {
+            Peer::WaitForJoin((mut read, mut write)) => {
+                // our own Join may still sit in the write buffer
+                write.poll_flush()?;
+
                 // Poll the underlying socket through the PeerCodec for a Join message. If one
                 // arrives, we can resolve the future.
                 match read.poll() {
@@ -321,7 +324,7 @@ impl PeerCodecWrite {
                         let _ = self.wr.split_to(n);
                         return Ok(Async::Ready(()));
                     },
-                    Ok(Async::NotReady) => {},
+                    Ok(Async::NotReady) => return Ok(Async::NotReady),
                     Err(err) => return Err(err)
                 }
             }
@@ -0,0 +1,415 @@
+//! Coordinator of a simulation spanning several processes
+//!
+//! The preload library simulates the network inside a single process. The coordinator owns
+//! the network state instead and every process started with
+//! `PEERSIM_COORDINATOR=<socket>` forwards its hooks to it. A process may run one or more
+//! nodes, so different binaries and gossip versions take part in the same network.
+//!
+//! ```text
+//! PEERSIM_SCENARIO=two_regions.toml peersim-coordinator /tmp/peersim.sock
+//! PEERSIM_COORDINATOR=/tmp/peersim.sock LD_PRELOAD=libpeersim.so \
+//!     peer_simulator simulate --scenario two_regions.toml --only "Node 0" "Node 1"
+//! ```
+//!
+//! Scenario, trace, checkpoints, exploration, pacing, the debugger and the metrics endpoint
+//! are configured for the coordinator with the same environment variables as for a single
+//! process.
+
+extern crate libc;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate bincode;
+extern crate scenario;
+
+// the modules are shared with the preload library, parts of them are only used there
+#[allow(dead_code)]
+#[path = "../../src/state.rs"]
+mod state;
+#[path = "../../src/dissect.rs"]
+mod dissect;
+#[path = "../../src/rng.rs"]
+mod rng;
+#[path = "../../src/debugger.rs"]
+mod debugger;
+#[path = "../../src/checkpoint.rs"]
+mod checkpoint;
+#[path = "../../src/explore.rs"]
+mod explore;
+#[allow(dead_code)]
+#[path = "../../src/queue.rs"]
+mod queue;
+#[allow(dead_code)]
+#[path = "../../src/remote.rs"]
+mod remote;
+#[path = "../../src/pace.rs"]
+mod pace;
+#[allow(dead_code)]
+#[path = "../../src/dns.rs"]
+mod dns;
+#[path = "../../src/nat.rs"]
+mod nat;
+#[allow(dead_code)]
+#[path = "../../src/clock.rs"]
+mod clock;
+#[allow(dead_code)]
+#[path = "../../src/output.rs"]
+mod output;
+#[path = "../../src/ports.rs"]
+mod ports;
+#[path = "../../src/backlog.rs"]
+mod backlog;
+#[path = "../../src/options.rs"]
+mod options;
+#[path = "../../src/byzantine.rs"]
+mod byzantine;
+#[path = "../../src/metrics.rs"]
+mod metrics;
+
+use std::env;
+use std::cmp;
+use std::fs;
+use std::process;
+use std::thread;
+use std::collections::HashMap;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use libc::c_int;
+
+use state::{Addr, EpollId, Fd, State};
+use debugger::Debugger;
+use remote::{Request, Response, read_message, write_message};
+
+/// Bits of a global file descriptor which hold the file descriptor of the process
+///
+/// Every process has its own file descriptor numbers, so the process id is put in front of
+/// them. This leaves room for 2047 processes with a million descriptors each.
+const FD_BITS: u32 = 20;
+
+fn global_fd(process: u32, fd: Fd) -> Fd {
+    (process << FD_BITS) as Fd | fd
+}
+
+fn process_of(fd: Fd) -> u32 {
+    (fd >> FD_BITS) as u32
+}
+
+struct Shared {
+    state: State,
+    /// An event was delivered and the receiving process hasn't consumed it yet
+    pending: bool,
+    /// The node running in each process, the state only keeps track of a single one
+    current: HashMap<u32, Option<Addr>>,
+    processes: u32,
+    debugger: Option<Debugger>
+}
+
+impl Shared {
+    /// Switch the state to the node of the process
+    fn enter(&mut self, process: u32) {
+        let current = self.current.get(&process).cloned().unwrap_or(None);
+        self.state.set_current_node(current);
+    }
+
+    fn leave(&mut self, process: u32) {
+        self.current.insert(process, self.state.current());
+    }
+
+    /// Answer of `epoll_wait`, with the simulated time and the node running now
+    fn epoll(&self, next: Option<(EpollId, c_int)>) -> Response {
+        Response::Epoll(next, self.state.time(), self.state.current())
+    }
+
+    /// The next notification or event for one of the file descriptors of the process
+    fn next_event(&mut self, process: u32) -> Option<(EpollId, c_int)> {
+        let mine = move |fd: Fd| process_of(fd) == process;
+
+        match self.state.next_epoll_notify_where(mine) {
+            Some(notify) => Some(notify),
+            None if !self.pending => {
+                let next_id = self.state.next_epoll_id_where(mine);
+
+                if next_id.is_some() {
+                    if let Some(ref mut debugger) = self.debugger {
+                        debugger.pause(&self.state);
+                    }
+
+                    // only data and connects wait for the process to consume them
+                    self.pending = !self.state.take_hang_up();
+                }
+
+                next_id
+            },
+            None => None
+        }
+    }
+}
+
+type Coordinator = Arc<(Mutex<Shared>, Condvar)>;
+
+fn handle(coordinator: &Coordinator, process: u32, req: Request) -> Response {
+    let (ref lock, ref cvar) = **coordinator;
+    let mut shared = lock.lock().unwrap();
+    let global = |fd| global_fd(process, fd);
+
+    shared.enter(process);
+
+    let res = match req {
+        Request::Bind(fd, addr) => Response::Bound(shared.state.bind(global(fd), addr)),
+        Request::SetOption(fd, option) => {
+            shared.state.set_option(global(fd), option);
+            Response::Done
+        },
+        Request::GetOption(fd, level, name) => Response::Option(shared.state.get_option(global(fd), level, name)),
+        Request::Listen(fd, backlog) => {
+            shared.state.listen(global(fd), backlog);
+            Response::Done
+        },
+        Request::Shutdown(fd) => Response::Sent(shared.state.shutdown(global(fd))),
+        Request::Close(fd) => {
+            shared.state.close(global(fd));
+            Response::Done
+        },
+        Request::Connect(fd, addr) => {
+            shared.state.connect_to_node(global(fd), addr);
+            Response::Done
+        },
+        Request::Accept(fd, new_fd) => {
+            let accepted = shared.state.accept(global(fd), || global(new_fd)).is_some();
+            shared.pending = accepted;
+
+            Response::Accepted(accepted)
+        },
+        Request::Send(fd, buf) => Response::Sent(shared.state.send_to(global(fd), &buf)),
+        Request::Recv(fd) => {
+            let data = shared.state.recv_from(global(fd));
+
+            // nothing follows the end of the stream or an error either
+            if data.as_ref().map(|x| x.is_empty()).unwrap_or(true) {
+                shared.pending = false;
+            }
+
+            Response::Data(data)
+        },
+        Request::SockName(fd) => Response::SockName(shared.state.get_sockname(global(fd))),
+        Request::PeerName(fd) => Response::PeerName(shared.state.get_peername(global(fd))),
+        Request::TakeError(fd) => Response::Error(shared.state.take_error(global(fd))),
+        Request::Resolve(name) => Response::Resolved(shared.state.resolve(&name)),
+        Request::Clock(kind) => Response::Clock(shared.state.clock(kind)),
+        Request::Output(lines) => {
+            shared.state.log_output(lines);
+            Response::Done
+        },
+        Request::EpollCtl(fd, events, id) => {
+            shared.state.add_epoll_fd(global(fd), events, id);
+            Response::Done
+        },
+        Request::EpollWait(timeout) => {
+            let deadline = if timeout >= 0 {
+                Some(Instant::now() + Duration::from_millis(timeout as u64))
+            } else {
+                None
+            };
+
+            loop {
+                if let Some(next) = shared.next_event(process) {
+                    break shared.epoll(Some(next));
+                }
+
+                // with simulated clocks the timeout passes in simulated time
+                if timeout >= 0 && !shared.pending && shared.state.idle(timeout as u64 * 1000) {
+                    break shared.epoll(None);
+                }
+
+                // nothing to deliver, sleep till another process changes the state or a paced
+                // event is released
+                let now = Instant::now();
+                if deadline.map(|x| now >= x).unwrap_or(false) {
+                    shared.leave(process);
+                    return shared.epoll(None);
+                }
+
+                let wake = match (deadline, shared.state.next_release()) {
+                    (Some(a), Some(b)) => Some(cmp::min(a, b)),
+                    (a, b) => a.or(b)
+                };
+
+                shared.leave(process);
+                shared = match wake {
+                    Some(wake) if wake > now => cvar.wait_timeout(shared, wake - now).unwrap().0,
+                    Some(_) => shared,
+                    None => cvar.wait(shared).unwrap()
+                };
+                shared.enter(process);
+            }
+        },
+        Request::Hello | Request::Attach(_) => panic!("Process {} registered twice", process)
+    };
+
+    shared.leave(process);
+    cvar.notify_all();
+
+    res
+}
+
+/// Serve a single connection of a process
+fn serve(coordinator: Coordinator, mut stream: UnixStream) {
+    let (process, mut res) = match read_message(&mut stream) {
+        Ok(Request::Hello) => {
+            let (process, clocks, seed) = {
+                let mut shared = coordinator.0.lock().unwrap();
+                shared.processes += 1;
+
+                (shared.processes, shared.state.clock(clock::Kind::Monotonic).is_some(), shared.state.seed())
+            };
+
+            if process >= 1 << (31 - FD_BITS) {
+                eprintln!("Coordinator: too many processes");
+                return;
+            }
+
+            println!("Coordinator: process {} joined", process);
+
+            (process, Response::Process(process, clocks, seed))
+        },
+        Ok(Request::Attach(process)) => (process, Response::Done),
+        Ok(req) => {
+            eprintln!("Coordinator: expected a new process, got {:?}", req);
+            return;
+        },
+        Err(_) => return
+    };
+
+    loop {
+        if write_message(&mut stream, &res).is_err() {
+            break;
+        }
+
+        res = match read_message(&mut stream) {
+            Ok(req) => handle(&coordinator, process, req),
+            Err(_) => break
+        };
+    }
+}
+
+fn main() {
+    let path = env::args().nth(1).unwrap_or_else(|| {
+        eprintln!("Usage: peersim-coordinator <socket>");
+        process::exit(2);
+    });
+
+    let _ = fs::remove_file(&path);
+    let listener = UnixListener::bind(&path).unwrap_or_else(|err| {
+        eprintln!("Could not listen on {}: {}", path, err);
+        process::exit(1);
+    });
+
+    let coordinator = Arc::new((Mutex::new(Shared {
+        state: State::new(),
+        pending: false,
+        current: HashMap::new(),
+        processes: 0,
+        debugger: Debugger::from_env()
+    }), Condvar::new()));
+
+    let shared = coordinator.clone();
+    metrics::serve_from_env(move || shared.0.lock().unwrap().state.metrics());
+
+    println!("Coordinator: waiting for processes on {}", path);
+
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let coordinator = coordinator.clone();
+                thread::spawn(move || serve(coordinator, stream));
+            },
+            Err(err) => eprintln!("Coordinator: {}", err)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    use libc::{EPOLLIN, EPOLLOUT};
+
+    use super::*;
+
+    fn addr(ip: [u8; 4]) -> Addr {
+        SocketAddrV4::new(Ipv4Addr::from(ip), 8000)
+    }
+
+    fn coordinator() -> Coordinator {
+        Arc::new((Mutex::new(Shared {
+            state: State::with_scenario(None),
+            pending: false,
+            current: HashMap::new(),
+            processes: 2,
+            debugger: None
+        }), Condvar::new()))
+    }
+
+    /// A node listening on `3` in the process
+    fn listen(coordinator: &Coordinator, process: u32, addr: Addr) {
+        handle(coordinator, process, Request::Bind(3, addr));
+        handle(coordinator, process, Request::Listen(3, 128));
+        handle(coordinator, process, Request::EpollCtl(3, EPOLLIN as u32, 3));
+    }
+
+    fn wait(coordinator: &Coordinator, process: u32) -> Option<EpollId> {
+        match handle(coordinator, process, Request::EpollWait(0)) {
+            Response::Epoll(next, _, _) => next.map(|(id, _)| id),
+            res => panic!("unexpected response {:?}", res)
+        }
+    }
+
+    fn recv(coordinator: &Coordinator, process: u32, fd: Fd) {
+        match handle(coordinator, process, Request::Recv(fd)) {
+            Response::Data(Err(_)) => {},
+            res => panic!("unexpected response {:?}", res)
+        }
+    }
+
+    #[test]
+    fn hang_ups_do_not_stall_other_processes() {
+        let coordinator = coordinator();
+        listen(&coordinator, 1, addr([10, 0, 0, 1]));
+        listen(&coordinator, 2, addr([10, 0, 0, 2]));
+
+        // the second process opens two connections, the first one accepts them as `5` and `7`
+        for &fd in &[4, 6] {
+            handle(&coordinator, 2, Request::Connect(fd, addr([10, 0, 0, 1])));
+            handle(&coordinator, 2, Request::EpollCtl(fd, (EPOLLIN | EPOLLOUT) as u32, fd as EpollId));
+        }
+
+        let mut accepted = vec![7, 5];
+        while let Some(id) = wait(&coordinator, 1) {
+            if id == 3 {
+                let fd = accepted.pop().unwrap();
+                assert!(match handle(&coordinator, 1, Request::Accept(3, fd)) { Response::Accepted(x) => x, _ => false });
+                handle(&coordinator, 1, Request::EpollCtl(fd, EPOLLIN as u32, fd as EpollId));
+                recv(&coordinator, 1, fd);
+            } else {
+                recv(&coordinator, 1, id as Fd);
+            }
+        }
+        assert!(accepted.is_empty());
+
+        // the first one hangs up on one of them
+        handle(&coordinator, 1, Request::Close(5));
+
+        // the second process is woken by its connects and the end of the stream, but never reads
+        while let Some(id) = wait(&coordinator, 2) {
+            assert!(id == 4 || id == 6, "{}", id);
+        }
+        assert!(coordinator.0.lock().unwrap().state.pending_events().is_empty());
+        assert!(!coordinator.0.lock().unwrap().pending);
+
+        // data on the other connection still reaches the first process
+        handle(&coordinator, 2, Request::Send(6, b"hello".to_vec()));
+        assert_eq!(wait(&coordinator, 1), Some(7));
+    }
+}
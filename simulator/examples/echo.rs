@@ -0,0 +1,42 @@
+//! A tiny tokio echo server and client in a single process, run under the preload library by
+//! the end-to-end test. The client sends a line, prints the echo and exits.
+
+extern crate tokio;
+
+use std::process;
+
+use tokio::prelude::*;
+use tokio::io;
+use tokio::net::{TcpListener, TcpStream};
+
+const MESSAGE: &[u8] = b"hello simulator\n";
+
+fn main() {
+    let addr = "127.0.0.1:7000".parse().unwrap();
+    let listener = TcpListener::bind(&addr).unwrap();
+
+    let server = listener.incoming()
+        .for_each(|socket| {
+            let (reader, writer) = socket.split();
+            tokio::spawn(io::copy(reader, writer).map(|_| ()).map_err(|_| ()));
+
+            Ok(())
+        })
+        .map_err(|err| eprintln!("Server failed: {}", err));
+
+    let client = TcpStream::connect(&addr)
+        .and_then(|stream| io::write_all(stream, MESSAGE))
+        .and_then(|(stream, _)| io::read_exact(stream, vec![0u8; MESSAGE.len()]))
+        .map(|(_, buf)| {
+            print!("echo {}", String::from_utf8_lossy(&buf));
+
+            // the server would wait for further connections forever
+            process::exit(0);
+        })
+        .map_err(|err| {
+            eprintln!("Client failed: {}", err);
+            process::exit(1);
+        });
+
+    tokio::run(server.join(client).map(|_| ()));
+}
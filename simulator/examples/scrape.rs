@@ -0,0 +1,73 @@
+//! Scrapes the metrics of its own simulation and then opens simulated connections, run under
+//! the preload library by the end-to-end test. The closed scrape leaves free file descriptors
+//! behind, which the connections reuse.
+
+extern crate tokio;
+
+use std::env;
+use std::thread;
+use std::process;
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+use std::time::{Duration, Instant};
+
+use tokio::prelude::*;
+use tokio::io;
+use tokio::timer::Delay;
+use tokio::net::{TcpListener, TcpStream};
+
+const MESSAGE: &[u8] = b"hello simulator\n";
+
+/// Request the metrics once the endpoint is up, it starts with the first `epoll_wait`
+fn scrape(path: &str) -> String {
+    let mut stream = loop {
+        match UnixStream::connect(path) {
+            Ok(stream) => break stream,
+            Err(_) => thread::sleep(Duration::from_millis(10))
+        }
+    };
+
+    stream.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+
+    response
+}
+
+fn main() {
+    let path = env::var("PEERSIM_METRICS").expect("set PEERSIM_METRICS");
+    let addr = "127.0.0.1:7000".parse().unwrap();
+    let listener = TcpListener::bind(&addr).unwrap();
+
+    let server = listener.incoming()
+        .for_each(|socket| {
+            let (reader, writer) = socket.split();
+            tokio::spawn(io::copy(reader, writer).map(|_| ()).map_err(|_| ()));
+
+            Ok(())
+        })
+        .map_err(|err| eprintln!("Server failed: {}", err));
+
+    // the runtime waits in `epoll_wait` first, then the closed scrape leaves its file
+    // descriptors to the sockets of the connection
+    let client = Delay::new(Instant::now() + Duration::from_millis(100))
+        .map_err(io::Error::other)
+        .and_then(move |_| {
+            println!("scraped {}", scrape(&path).lines().next().unwrap_or(""));
+
+            TcpStream::connect(&addr)
+        })
+        .and_then(|stream| io::write_all(stream, MESSAGE))
+        .and_then(|(stream, _)| io::read_exact(stream, vec![0u8; MESSAGE.len()]))
+        .map(|(_, buf)| {
+            print!("echo {}", String::from_utf8_lossy(&buf));
+            process::exit(0);
+        })
+        .map_err(|err| {
+            eprintln!("Client failed: {}", err);
+            process::exit(1);
+        });
+
+    tokio::run(server.join(client).map(|_| ()));
+}
@@ -0,0 +1,198 @@
+//! Explore many delivery schedules of a simulated application
+//!
+//! Runs the application again and again under `libpeersim.so`, each time with another
+//! exploration seed. After every run the user supplied invariant command is called with the
+//! path of the trace and a non-zero exit code marks the schedule as failing. The trace of a
+//! failing schedule is then shrunk to the shortest prefix still violating the invariant.
+//!
+//! ```text
+//! peersim-explore --runs 200 --strategy pct:3 --until 2000000 \
+//!     --invariant '! grep -q Malformed' \
+//!     -- ../libraries/example/target/release/peer_simulator simulate --scenario two_regions.toml
+//! ```
+
+extern crate clap;
+
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use clap::{App, Arg};
+
+struct Explorer {
+    preload: String,
+    program: Vec<String>,
+    strategy: String,
+    window: String,
+    steps: String,
+    until: String,
+    timeout: Duration,
+    invariant: String,
+    output: PathBuf
+}
+
+impl Explorer {
+    /// Run the application with a single seed and return the path of its trace
+    fn run(&self, seed: u64) -> PathBuf {
+        let trace = self.output.join(format!("seed-{}.trace", seed));
+        let log = File::create(self.output.join(format!("seed-{}.log", seed))).unwrap();
+
+        let mut child = Command::new(&self.program[0])
+            .args(&self.program[1..])
+            .env("LD_PRELOAD", &self.preload)
+            .env("PEERSIM_EXPLORE", seed.to_string())
+            .env("PEERSIM_EXPLORE_STRATEGY", &self.strategy)
+            .env("PEERSIM_EXPLORE_WINDOW", &self.window)
+            .env("PEERSIM_EXPLORE_STEPS", &self.steps)
+            .env("PEERSIM_UNTIL", &self.until)
+            .env("PEERSIM_TRACE", &trace)
+            .stdout(Stdio::from(log.try_clone().unwrap()))
+            .stderr(Stdio::from(log))
+            .spawn()
+            .unwrap_or_else(|err| panic!("Could not start {}: {}", self.program[0], err));
+
+        // a stuck simulation is killed after the timeout, its trace is checked anyway
+        let start = Instant::now();
+        while child.try_wait().unwrap().is_none() {
+            if start.elapsed() > self.timeout {
+                println!("Explore: seed {} timed out", seed);
+                let _ = child.kill();
+                let _ = child.wait();
+
+                break;
+            }
+
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        trace
+    }
+
+    fn holds(&self, trace: &Path) -> bool {
+        Command::new("sh")
+            .arg("-c")
+            .arg(format!("{} \"$0\"", self.invariant))
+            .arg(trace)
+            .stdout(Stdio::null())
+            .status()
+            .map(|x| x.success())
+            .unwrap_or_else(|err| panic!("Could not run invariant: {}", err))
+    }
+
+    /// Find the shortest prefix of the trace which still violates the invariant
+    fn minimise(&self, trace: &Path) -> (PathBuf, usize) {
+        let lines = BufReader::new(File::open(trace).unwrap()).lines()
+            .collect::<Result<Vec<String>, _>>().unwrap();
+
+        let path = trace.with_extension("min.trace");
+        let write_prefix = |n: usize| {
+            let mut file = File::create(&path).unwrap();
+            for line in &lines[..n] {
+                writeln!(file, "{}", line).unwrap();
+            }
+        };
+
+        // the empty trace holds and the full trace fails
+        let (mut lo, mut hi) = (0, lines.len());
+        while hi - lo > 1 {
+            let mid = (lo + hi) / 2;
+            write_prefix(mid);
+
+            if self.holds(&path) {
+                lo = mid;
+            } else {
+                hi = mid;
+            }
+        }
+
+        write_prefix(hi);
+
+        (path, hi)
+    }
+}
+
+fn main() {
+    let matches = App::new("Peer simulator schedule explorer")
+        .version("0.1")
+        .about("Explores delivery orders of a simulated application and checks invariants")
+        .arg(Arg::with_name("runs").short("r").long("runs").takes_value(true).default_value("100"))
+        .arg(Arg::with_name("seed").short("s").long("seed").takes_value(true).default_value("0")
+             .help("first seed, the following runs count upwards"))
+        .arg(Arg::with_name("strategy").long("strategy").takes_value(true).default_value("random")
+             .help("random, pct:<depth> or systematic"))
+        .arg(Arg::with_name("window").short("w").long("window").takes_value(true).default_value("1000")
+             .help("largest extra delay of an event in simulated time"))
+        .arg(Arg::with_name("steps").long("steps").takes_value(true).default_value("10000")
+             .help("expected number of scheduling decisions, used by PCT"))
+        .arg(Arg::with_name("until").short("u").long("until").takes_value(true).required(true)
+             .help("simulated time after which a run is stopped"))
+        .arg(Arg::with_name("timeout").short("t").long("timeout").takes_value(true).default_value("60")
+             .help("wall clock seconds before a run is killed"))
+        .arg(Arg::with_name("invariant").short("i").long("invariant").takes_value(true).required(true)
+             .help("shell command called with the trace path, fails on a violation"))
+        .arg(Arg::with_name("preload").short("p").long("preload").takes_value(true)
+             .default_value("target/debug/libpeersim.so"))
+        .arg(Arg::with_name("output").short("o").long("output").takes_value(true).default_value("explore"))
+        .arg(Arg::with_name("keep-going").short("k").long("keep-going")
+             .help("continue after the first failing schedule"))
+        .arg(Arg::with_name("program").multiple(true).required(true).last(true))
+        .get_matches();
+
+    let number = |name: &str| matches.value_of(name).unwrap().parse::<u64>().unwrap_or_else(|err| {
+        eprintln!("Invalid --{}: {}", name, err);
+        process::exit(2);
+    });
+
+    let preload = fs::canonicalize(matches.value_of("preload").unwrap()).unwrap_or_else(|err| {
+        eprintln!("Could not find the preload library: {}", err);
+        process::exit(2);
+    });
+
+    let output = PathBuf::from(matches.value_of("output").unwrap());
+    fs::create_dir_all(&output).unwrap();
+
+    let explorer = Explorer {
+        preload: preload.to_string_lossy().into_owned(),
+        program: matches.values_of("program").unwrap().map(|x| x.to_string()).collect(),
+        strategy: matches.value_of("strategy").unwrap().to_string(),
+        window: number("window").to_string(),
+        steps: number("steps").to_string(),
+        until: number("until").to_string(),
+        timeout: Duration::from_secs(number("timeout")),
+        invariant: matches.value_of("invariant").unwrap().to_string(),
+        output
+    };
+
+    let (first, runs) = (number("seed"), number("runs"));
+    let mut failures = 0;
+
+    for seed in first..first + runs {
+        let trace = explorer.run(seed);
+
+        if explorer.holds(&trace) {
+            println!("Explore: seed {} passed", seed);
+            continue;
+        }
+
+        let (minimal, length) = explorer.minimise(&trace);
+        failures += 1;
+
+        println!("Explore: seed {} violates the invariant", seed);
+        println!("         minimal trace with {} events in {}", length, minimal.display());
+        println!("         reproduce with PEERSIM_EXPLORE={} PEERSIM_EXPLORE_STRATEGY={} PEERSIM_EXPLORE_WINDOW={} PEERSIM_EXPLORE_STEPS={} PEERSIM_UNTIL={}",
+            seed, explorer.strategy, explorer.window, explorer.steps, explorer.until);
+
+        if !matches.is_present("keep-going") {
+            break;
+        }
+    }
+
+    println!("Explore: {} failing schedules", failures);
+
+    if failures > 0 {
+        process::exit(1);
+    }
+}
@@ -0,0 +1,313 @@
+//! Regression test of a simulated application against a golden trace
+//!
+//! Runs the application once under `libpeersim.so` and compares its trace line by line with
+//! a trace committed earlier. The seed comes from the scenario, so the same build produces the
+//! same trace. On the first divergence the simulated time, the node and the decoded frames of
+//! both sides are shown, together with the events leading up to it. Intended changes of the
+//! behaviour are accepted with `--bless`, which overwrites the golden trace.
+//!
+//! ```text
+//! peersim-golden --golden ../libraries/example/golden/two_regions.trace --until 300000 \
+//!     -- ../libraries/example/target/release/peer_simulator simulate --scenario two_regions.toml
+//! ```
+
+extern crate clap;
+
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use clap::{App, Arg};
+
+/// Number of matching lines shown before the divergence
+const CONTEXT: usize = 5;
+
+/// A line of a trace, split into the simulated time, the event and its decoded frames
+struct Entry {
+    time: Option<u64>,
+    log: String,
+    frames: Vec<String>
+}
+
+impl Entry {
+    fn parse(line: &str) -> Entry {
+        let mut parts = line.split(" | ");
+        let head = parts.next().unwrap_or("").trim_start();
+        let frames = parts.map(|x| x.to_string()).collect();
+
+        let (time, log) = match head.find(' ') {
+            Some(pos) => match head[..pos].parse::<u64>() {
+                Ok(time) => (Some(time), head[pos + 1..].to_string()),
+                Err(_) => (None, head.to_string())
+            },
+            None => (None, head.to_string())
+        };
+
+        Entry { time, log, frames }
+    }
+
+    /// The node an event belongs to
+    fn node(&self) -> Option<&str> {
+        let words = self.log.split(' ').collect::<Vec<_>>();
+
+        let node = match words.as_slice() {
+            ["add_node", addr, ..] | ["output", addr, ..] => *addr,
+            ["connect", _, addr, ..] | ["refused", _, addr, ..] => *addr,
+            ["overflow", _, addr, ..] | ["timed_out", _, addr, ..] => *addr,
+            ["send_packet", .., "from", addr] => *addr,
+            ["fin", _, "from", addr] | ["reset", _, "from", addr] => *addr,
+            _ => return None
+        };
+
+        if node == "-" { None } else { Some(node) }
+    }
+
+    fn show(&self, name: &str) {
+        match self.time {
+            Some(time) => println!("  {:<7} {:>10} {}", name, time, self.log),
+            None => println!("  {:<7} {:>10} {}", name, "", self.log)
+        }
+
+        for frame in &self.frames {
+            println!("  {:<7} {:>10} | {}", "", "", frame);
+        }
+    }
+}
+
+fn read_lines(path: &Path) -> Vec<String> {
+    let file = File::open(path).unwrap_or_else(|err| {
+        eprintln!("Could not read {}: {}", path.display(), err);
+        process::exit(2);
+    });
+
+    BufReader::new(file).lines().collect::<Result<Vec<_>, _>>().unwrap_or_else(|err| {
+        eprintln!("Could not read {}: {}", path.display(), err);
+        process::exit(2);
+    })
+}
+
+/// Run the application and return the path of its trace
+///
+/// A run killed after the timeout never reached `until`, its partial trace is neither
+/// compared nor blessed and the process exits with an error instead.
+fn run(preload: &str, program: &[String], until: &str, timeout: Duration, output: &Path) -> PathBuf {
+    let trace = output.with_extension("trace");
+    let log = File::create(output.with_extension("log")).unwrap();
+
+    let mut child = Command::new(&program[0])
+        .args(&program[1..])
+        .env("LD_PRELOAD", preload)
+        .env("PEERSIM_UNTIL", until)
+        .env("PEERSIM_TRACE", &trace)
+        .env("PEERSIM_OUTPUT", "trace")
+        .stdout(Stdio::from(log.try_clone().unwrap()))
+        .stderr(Stdio::from(log))
+        .spawn()
+        .unwrap_or_else(|err| panic!("Could not start {}: {}", program[0], err));
+
+    let start = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait().unwrap() {
+            break status;
+        }
+
+        if start.elapsed() > timeout {
+            let _ = child.kill();
+            let _ = child.wait();
+
+            eprintln!("Golden: run killed after {}s before reaching {}, see {}", timeout.as_secs(), until,
+                output.with_extension("log").display());
+            process::exit(3);
+        }
+
+        thread::sleep(Duration::from_millis(10));
+    };
+
+    if !status.success() {
+        eprintln!("Golden: run failed with {}, see {}", status, output.with_extension("log").display());
+        process::exit(3);
+    }
+
+    trace
+}
+
+/// Index of the first line that differs, a trace ending early differs from a longer one
+fn divergence(golden: &[String], actual: &[String]) -> Option<usize> {
+    let same = golden.iter().zip(actual).take_while(|(a, b)| a == b).count();
+
+    if same == golden.len() && same == actual.len() { None } else { Some(same) }
+}
+
+/// Compare the traces and describe the first divergence, returns if they are equal
+fn compare(golden: &[String], actual: &[String]) -> bool {
+    let same = match divergence(golden, actual) {
+        Some(same) => same,
+        None => {
+            println!("Golden: {} lines match", golden.len());
+            return true;
+        }
+    };
+
+    let expected = golden.get(same).map(|x| Entry::parse(x));
+    let found = actual.get(same).map(|x| Entry::parse(x));
+
+    println!("Golden: first divergence at line {}", same + 1);
+
+    let time = expected.as_ref().or(found.as_ref()).and_then(|x| x.time);
+    if let Some(time) = time {
+        println!("  simulated time {}", time);
+    }
+
+    let node = expected.as_ref().and_then(|x| x.node())
+        .or_else(|| found.as_ref().and_then(|x| x.node()));
+    if let Some(node) = node {
+        println!("  node           {}", node);
+    }
+
+    println!();
+    for line in &golden[same.saturating_sub(CONTEXT)..same] {
+        Entry::parse(line).show("");
+    }
+
+    match expected {
+        Some(entry) => entry.show("golden"),
+        None => println!("  golden  (trace ends)")
+    }
+
+    match found {
+        Some(entry) => entry.show("run"),
+        None => println!("  run     (trace ends)")
+    }
+
+    println!();
+    println!("Golden: {} lines in the golden trace, {} in the run", golden.len(), actual.len());
+
+    false
+}
+
+fn main() {
+    let matches = App::new("Peer simulator golden traces")
+        .version("0.1")
+        .about("Compares the trace of a simulated application with a golden trace")
+        .arg(Arg::with_name("golden").short("g").long("golden").takes_value(true).required(true)
+             .help("path of the golden trace"))
+        .arg(Arg::with_name("bless").short("b").long("bless")
+             .help("accept the trace of this run as the new golden trace"))
+        .arg(Arg::with_name("until").short("u").long("until").takes_value(true).required(true)
+             .help("simulated time after which the run is stopped"))
+        .arg(Arg::with_name("timeout").short("t").long("timeout").takes_value(true).default_value("60")
+             .help("wall clock seconds before the run is killed"))
+        .arg(Arg::with_name("preload").short("p").long("preload").takes_value(true)
+             .default_value("target/debug/libpeersim.so"))
+        .arg(Arg::with_name("output").short("o").long("output").takes_value(true).default_value("golden")
+             .help("directory for the trace and output of the run"))
+        .arg(Arg::with_name("program").multiple(true).required(true).last(true))
+        .get_matches();
+
+    let number = |name: &str| matches.value_of(name).unwrap().parse::<u64>().unwrap_or_else(|err| {
+        eprintln!("Invalid --{}: {}", name, err);
+        process::exit(2);
+    });
+
+    let preload = fs::canonicalize(matches.value_of("preload").unwrap()).unwrap_or_else(|err| {
+        eprintln!("Could not find the preload library: {}", err);
+        process::exit(2);
+    });
+
+    let golden = PathBuf::from(matches.value_of("golden").unwrap());
+    let name = golden.file_stem().map(|x| x.to_os_string()).unwrap_or_else(|| "run".into());
+
+    let output = PathBuf::from(matches.value_of("output").unwrap());
+    fs::create_dir_all(&output).unwrap();
+
+    let program = matches.values_of("program").unwrap().map(|x| x.to_string()).collect::<Vec<_>>();
+    let trace = run(&preload.to_string_lossy(), &program, &number("until").to_string(),
+        Duration::from_secs(number("timeout")), &output.join(name));
+
+    if matches.is_present("bless") {
+        fs::copy(&trace, &golden).unwrap_or_else(|err| {
+            eprintln!("Could not write {}: {}", golden.display(), err);
+            process::exit(2);
+        });
+
+        println!("Golden: blessed {} with {} lines", golden.display(), read_lines(&golden).len());
+        return;
+    }
+
+    if !golden.exists() {
+        eprintln!("No golden trace {}, create it with --bless", golden.display());
+        process::exit(2);
+    }
+
+    if !compare(&read_lines(&golden), &read_lines(&trace)) {
+        println!("Golden: trace of the run in {}, accept it with --bless", trace.display());
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(trace: &[&str]) -> Vec<String> {
+        trace.iter().map(|x| x.to_string()).collect()
+    }
+
+    #[test]
+    fn entries_are_parsed() {
+        let entry = Entry::parse("      3200 send_packet 6 29 bytes from 10.1.0.1:8000 | Join(seed@10.1.0.1:8000) | Push(1)");
+        assert_eq!(entry.time, Some(3200));
+        assert_eq!(entry.log, "send_packet 6 29 bytes from 10.1.0.1:8000");
+        assert_eq!(entry.frames, ["Join(seed@10.1.0.1:8000)", "Push(1)"]);
+        assert_eq!(entry.node(), Some("10.1.0.1:8000"));
+
+        let entry = Entry::parse("send_packet 6 29 bytes from 10.1.0.1:8000");
+        assert_eq!(entry.time, None);
+        assert_eq!(entry.log, "send_packet 6 29 bytes from 10.1.0.1:8000");
+        assert!(entry.frames.is_empty());
+    }
+
+    #[test]
+    fn events_belong_to_nodes() {
+        let node = |line: &str| Entry::parse(line).node().map(|x| x.to_string());
+
+        assert_eq!(node("0 add_node 10.1.0.1:8000 latency 200"), Some("10.1.0.1:8000".to_string()));
+        assert_eq!(node("0 connect 6 10.1.0.2:8000"), Some("10.1.0.2:8000".to_string()));
+        assert_eq!(node("94113 fin 155 from 10.2.0.6:8000"), Some("10.2.0.6:8000".to_string()));
+        assert_eq!(node("94113 reset 155 from 10.2.0.6:8000"), Some("10.2.0.6:8000".to_string()));
+        assert_eq!(node("0 output - stdout Gossip: Start server"), None);
+        assert_eq!(node("0 unknown 10.1.0.1:8000"), None);
+    }
+
+    #[test]
+    fn equal_traces_match() {
+        let trace = lines(&["0 add_node 10.1.0.1:8000 latency 200", "0 connect 6 10.1.0.1:8000"]);
+
+        assert_eq!(divergence(&trace, &trace), None);
+        assert!(compare(&trace, &trace));
+        assert!(compare(&[], &[]));
+    }
+
+    #[test]
+    fn shorter_traces_diverge_where_they_end() {
+        let golden = lines(&["0 add_node 10.1.0.1:8000 latency 200", "0 connect 6 10.1.0.1:8000"]);
+        let prefix = lines(&["0 add_node 10.1.0.1:8000 latency 200"]);
+
+        assert_eq!(divergence(&golden, &prefix), Some(1));
+        assert_eq!(divergence(&prefix, &golden), Some(1));
+        assert!(!compare(&golden, &prefix));
+        assert!(!compare(&prefix, &golden));
+    }
+
+    #[test]
+    fn differing_traces_diverge_at_the_first_difference() {
+        let golden = lines(&["0 add_node 10.1.0.1:8000 latency 200", "0 connect 6 10.1.0.1:8000", "10 fin 6 from 10.1.0.2:8000"]);
+        let actual = lines(&["0 add_node 10.1.0.1:8000 latency 200", "0 connect 6 10.1.0.3:8000", "10 fin 6 from 10.1.0.2:8000"]);
+
+        assert_eq!(divergence(&golden, &actual), Some(1));
+        assert!(!compare(&golden, &actual));
+    }
+}
@@ -0,0 +1,342 @@
+//! Replay of a recorded real run inside the simulator
+//!
+//! A process started with `PEERSIM_RECORD=<path>` and the preload library runs on the real
+//! network and records its socket calls. The replay drives the simulated network from such a
+//! recording: binds, connects and sends happen at their recorded times, while the simulator
+//! decides when connections and packets arrive. Peers outside of the recorded process are
+//! played by nodes of their own, which send what the process received from them.
+//!
+//! ```text
+//! PEERSIM_RECORD=/tmp/run.rec LD_PRELOAD=libpeersim.so peer_simulator simulate
+//! PEERSIM_SCENARIO=two_regions.toml PEERSIM_TRACE=/tmp/replay.trace peersim-replay /tmp/run.rec
+//! ```
+//!
+//! Logs of `strace` can be replayed as well, or converted into a recording with `--save`. See
+//! `strace::convert` for how to take them.
+//!
+//! ```text
+//! strace -f -tt -s 65535 -o gossip.log ./target/debug/gossip1 127.0.0.1 8000
+//! peersim-replay --strace gossip.log
+//! peersim-replay --strace gossip.log --save gossip.rec
+//! ```
+//!
+//! Scenario, trace, checkpoints and the debugger are configured with the same environment
+//! variables as for the preload library.
+
+extern crate libc;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate bincode;
+extern crate scenario;
+
+// the modules are shared with the preload library, parts of them are only used there
+#[allow(dead_code)]
+#[path = "../../src/state.rs"]
+mod state;
+#[allow(dead_code)]
+#[path = "../../src/dissect.rs"]
+mod dissect;
+#[path = "../../src/rng.rs"]
+mod rng;
+#[path = "../../src/debugger.rs"]
+mod debugger;
+#[path = "../../src/checkpoint.rs"]
+mod checkpoint;
+#[path = "../../src/explore.rs"]
+mod explore;
+#[allow(dead_code)]
+#[path = "../../src/queue.rs"]
+mod queue;
+#[allow(dead_code)]
+#[path = "../../src/pace.rs"]
+mod pace;
+#[path = "../../src/nat.rs"]
+mod nat;
+#[allow(dead_code)]
+#[path = "../../src/clock.rs"]
+mod clock;
+#[allow(dead_code)]
+#[path = "../../src/output.rs"]
+mod output;
+#[allow(dead_code)]
+#[path = "../../src/ports.rs"]
+mod ports;
+#[allow(dead_code)]
+#[path = "../../src/backlog.rs"]
+mod backlog;
+#[allow(dead_code)]
+#[path = "../../src/options.rs"]
+mod options;
+#[allow(dead_code)]
+#[path = "../../src/byzantine.rs"]
+mod byzantine;
+#[allow(dead_code)]
+#[path = "../../src/metrics.rs"]
+mod metrics;
+#[allow(dead_code)]
+#[path = "../../src/record.rs"]
+mod record;
+mod recording;
+mod strace;
+
+use std::env;
+use std::fs;
+use std::process;
+use std::collections::{BTreeMap, HashMap};
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+use state::{Addr, Event, Fd, State};
+use debugger::Debugger;
+use record::Record;
+
+/// File descriptors of the replay start here, away from the ones of the recording
+const FIRST_FD: Fd = 1 << 20;
+
+struct Replay {
+    state: State,
+    debugger: Option<Debugger>,
+    next_fd: Fd,
+    /// File descriptors of the recording and their counterpart in the simulation
+    fds: HashMap<Fd, Fd>,
+    /// Recorded connections which are accepted once their connect arrives
+    accepting: HashMap<Fd, Fd>,
+    /// Both ends of every accepted connection
+    peers: HashMap<Fd, Fd>,
+    /// Connecting sockets by the local address they had in the recording
+    connecting: HashMap<Addr, Fd>,
+    /// Nodes by their address, and if they play a peer outside of the recorded process
+    nodes: BTreeMap<Addr, bool>
+}
+
+impl Replay {
+    fn new() -> Replay {
+        Replay {
+            state: State::new(),
+            debugger: Debugger::from_env(),
+            next_fd: FIRST_FD,
+            fds: HashMap::new(),
+            accepting: HashMap::new(),
+            peers: HashMap::new(),
+            connecting: HashMap::new(),
+            nodes: BTreeMap::new()
+        }
+    }
+
+    fn new_fd(&mut self) -> Fd {
+        self.next_fd += 1;
+
+        self.next_fd
+    }
+
+    /// The node listening on an address, created if it wasn't bound in the recording
+    fn node(&mut self, addr: Addr, external: bool) -> Addr {
+        if !self.nodes.contains_key(&addr) {
+            let fd = self.new_fd();
+            self.state.add_node(fd, addr);
+            self.nodes.insert(addr, external);
+        }
+
+        addr
+    }
+
+    /// The recorded node owning a local address, the first one bound to the same IP
+    fn local_node(&mut self, local: Addr) -> Addr {
+        let node = self.nodes.iter()
+            .find(|(addr, external)| addr.ip() == local.ip() && !**external)
+            .map(|(addr, _)| *addr);
+
+        match node {
+            Some(node) => node,
+            None => self.node(local, false)
+        }
+    }
+
+    fn is_external(&self, node: Option<Addr>) -> bool {
+        node.and_then(|x| self.nodes.get(&x).cloned()).unwrap_or(false)
+    }
+
+    /// The simulated connection of a recorded one
+    ///
+    /// The kernel accepts connections before the application does, so the simulated network
+    /// runs ahead till the connection is established.
+    fn connection(&mut self, fd: Fd) -> Option<Fd> {
+        if let Some(origin) = self.accepting.get(&fd).cloned() {
+            while !self.peers.contains_key(&origin) && self.deliver_next(u64::MAX) {}
+
+            if let Some(accepted) = self.peers.get(&origin).cloned() {
+                self.accepting.remove(&fd);
+                self.fds.insert(fd, accepted);
+            }
+        }
+
+        let fd = self.fds.get(&fd).cloned()?;
+        while !self.peers.contains_key(&fd) && self.deliver_next(u64::MAX) {}
+
+        Some(fd)
+    }
+
+    /// Deliver the next event if it is due till `time`, the application always accepts and
+    /// reads right away
+    fn deliver_next(&mut self, time: u64) -> bool {
+        let event = match self.state.next_event() {
+            Some((event, at)) if at <= time => event,
+            _ => return false
+        };
+
+        if let Some(ref mut debugger) = self.debugger {
+            debugger.pause(&self.state);
+        }
+
+        match event {
+            Event::Connect(origin, listener) => {
+                let new_fd = self.new_fd();
+                match self.state.accept(listener, || new_fd) {
+                    Some(accepted) => {
+                        self.peers.insert(origin, accepted);
+                        self.peers.insert(accepted, origin);
+                    },
+                    None => return false
+                }
+            },
+            Event::SendPacket(fd, _) => {
+                if self.state.recv_from(fd).is_err() {
+                    return false;
+                }
+            },
+            Event::Fin(fd) | Event::Reset(fd) => {
+                let _ = self.state.recv_from(fd);
+            }
+        }
+
+        true
+    }
+
+    fn replay(&mut self, time: u64, record: Record) {
+        while self.deliver_next(time) {}
+        self.state.advance(time);
+
+        match record {
+            Record::Bind(fd, addr) => {
+                // connections to a wildcard listener arrive on the loopback address
+                let addr = if addr.ip().is_unspecified() {
+                    SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), addr.port())
+                } else {
+                    addr
+                };
+
+                let listener = self.new_fd();
+                self.state.add_node(listener, addr);
+                self.nodes.insert(addr, false);
+                self.fds.insert(fd, listener);
+            },
+            Record::Connect(fd, local, remote) => {
+                self.node(remote, true);
+                let node = self.local_node(local);
+
+                let new_fd = self.new_fd();
+                self.state.set_current_node(Some(node));
+                self.state.connect_to_node(new_fd, remote);
+
+                self.fds.insert(fd, new_fd);
+                self.connecting.insert(local, new_fd);
+            },
+            Record::Accept(listener, fd, peer) => {
+                let origin = match self.connecting.get(&peer).cloned() {
+                    Some(origin) => origin,
+                    None => {
+                        // the peer is outside of the recording, let it connect now
+                        let listener = match self.fds.get(&listener).cloned() {
+                            Some(listener) => self.state.addr_by_fd(listener),
+                            None => return
+                        };
+
+                        let node = self.node(peer, true);
+                        let origin = self.new_fd();
+                        self.state.set_current_node(Some(node));
+                        self.state.connect_to_node(origin, listener);
+
+                        origin
+                    }
+                };
+
+                self.fds.remove(&fd);
+                self.accepting.insert(fd, origin);
+            },
+            Record::Send(fd, buf) => {
+                if let Some(fd) = self.connection(fd) {
+                    let node = self.state.owner(fd);
+                    self.state.set_current_node(node);
+                    let _ = self.state.send_to(fd, &buf);
+                }
+            },
+            Record::Recv(fd, buf) => {
+                // data of peers outside of the recording is sent by their node
+                let peer = self.connection(fd).and_then(|fd| self.peers.get(&fd).cloned());
+
+                if let Some(peer) = peer {
+                    let node = self.state.owner(peer);
+                    if self.is_external(node) {
+                        self.state.set_current_node(node);
+                        let _ = self.state.send_to(peer, &buf);
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("Usage: peersim-replay <recording>");
+    eprintln!("       peersim-replay --strace <log> [--save <recording>]");
+    process::exit(2);
+}
+
+fn main() {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    let args = args.iter().map(|x| x.as_str()).collect::<Vec<_>>();
+
+    let mut records = match args.as_slice() {
+        [path] if !path.starts_with("--") => recording::load(path).unwrap_or_else(|err| {
+            eprintln!("Could not read recording {}: {}", path, err);
+            process::exit(1);
+        }),
+        ["--strace", path] | ["--strace", path, "--save", _] => {
+            let log = fs::read_to_string(path).unwrap_or_else(|err| {
+                eprintln!("Could not read strace log {}: {}", path, err);
+                process::exit(1);
+            });
+
+            strace::convert(&log)
+        },
+        _ => usage()
+    };
+
+    if let ["--strace", _, "--save", out] = args.as_slice() {
+        if let Err(err) = recording::save(out, &records) {
+            eprintln!("Could not write recording {}: {}", out, err);
+            process::exit(1);
+        }
+
+        println!("Converted {} socket calls into {}", records.len(), out);
+        return;
+    }
+
+    // threads record concurrently, so entries may be slightly out of order
+    records.sort_by_key(|x| x.0);
+
+    let mut replay = Replay::new();
+    let count = records.len();
+    for (time, record) in records {
+        replay.replay(time, record);
+    }
+
+    while replay.deliver_next(u64::MAX) {}
+
+    println!("Replay: {} records, simulated network finished at {}", count, replay.state.time());
+    if env::var("PEERSIM_TRACE").is_err() {
+        for line in replay.state.trace() {
+            println!("{}", line);
+        }
+    }
+}
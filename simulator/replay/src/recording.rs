@@ -0,0 +1,37 @@
+//! Recordings on disk, as written by the preload library
+
+use std::fs::File;
+use std::io::{self, Write, BufReader, BufWriter};
+
+use bincode::{serialize, deserialize_from, ErrorKind};
+
+use record::Record;
+
+/// Write entries as a recording, for example converted from another source
+pub fn save(path: &str, records: &[(u64, Record)]) -> io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    for entry in records {
+        let buf = serialize(entry).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        file.write_all(&buf)?;
+    }
+
+    file.flush()
+}
+
+/// Read all entries of a recording
+pub fn load(path: &str) -> io::Result<Vec<(u64, Record)>> {
+    let mut file = BufReader::new(File::open(path)?);
+    let mut records = Vec::new();
+
+    loop {
+        match deserialize_from(&mut file) {
+            Ok(entry) => records.push(entry),
+            Err(err) => match *err {
+                ErrorKind::Io(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidData, err))
+            }
+        }
+    }
+
+    Ok(records)
+}
@@ -0,0 +1,428 @@
+use std::collections::{HashMap, HashSet};
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+use state::{Addr, Fd};
+use record::Record;
+
+/// Time between two calls of a log without timestamps, in microseconds
+const STEP: u64 = 1000;
+
+/// First port handed to connections whose local address isn't in the log
+const FIRST_PORT: u16 = 40000;
+
+/// Timestamp of a call in seconds
+#[derive(Clone, Copy)]
+enum Stamp {
+    /// Time of the day of `-tt` or since the epoch of `-ttt`
+    Absolute(f64),
+    /// Time since the previous call of `-r`
+    Relative(f64)
+}
+
+/// A completed system call of the log
+struct Call {
+    time: Option<Stamp>,
+    name: String,
+    args: String,
+    ret: i64,
+    errno: Option<String>
+}
+
+/// Split off the timestamp of `-tt`, `-ttt` or `-r`
+fn timestamp(line: &str) -> (Option<Stamp>, &str) {
+    let mut parts = line.splitn(2, ' ');
+    let (first, rest) = match (parts.next(), parts.next()) {
+        (Some(first), Some(rest)) => (first, rest),
+        _ => return (None, line)
+    };
+
+    let seconds = if first.contains(':') {
+        let mut total = 0.0;
+        for part in first.split(':') {
+            match part.parse::<f64>() {
+                Ok(x) => total = total * 60.0 + x,
+                Err(_) => return (None, line)
+            }
+        }
+
+        Some(Stamp::Absolute(total))
+    } else if first.contains('.') {
+        // relative timestamps are small, the epoch is far away
+        first.parse::<f64>().ok()
+            .map(|x| if x > 1e8 { Stamp::Absolute(x) } else { Stamp::Relative(x) })
+    } else {
+        None
+    };
+
+    match seconds {
+        Some(seconds) => (Some(seconds), rest),
+        None => (None, line)
+    }
+}
+
+/// Parse `name(args) = ret`, with the arguments still unparsed
+fn parse_call(time: Option<Stamp>, line: &str) -> Option<Call> {
+    let open = line.find('(')?;
+    let name = &line[..open];
+    if name.is_empty() || !name.chars().all(|x| x.is_alphanumeric() || x == '_') {
+        return None;
+    }
+
+    // strace pads the result of resumed calls with spaces
+    let equals = line.rfind(" = ")?;
+    let end = line[..equals].trim_end().len();
+    if end <= open || !line[..end].ends_with(')') {
+        return None;
+    }
+
+    let args = &line[open + 1..end - 1];
+    let mut result = line[equals + 3..].split_whitespace();
+    let ret = result.next()?.parse::<i64>().ok()?;
+    let errno = result.next().filter(|x| x.starts_with('E')).map(|x| x.to_string());
+
+    Some(Call { time, name: name.to_string(), args: args.to_string(), ret, errno })
+}
+
+/// Read all completed calls, joining calls interrupted by other threads
+fn calls(log: &str) -> Vec<Call> {
+    let mut unfinished: HashMap<String, String> = HashMap::new();
+    let mut calls = Vec::new();
+
+    for line in log.lines() {
+        let (pid, line) = if line.starts_with("[pid ") {
+            match line.find("] ") {
+                Some(pos) => (line[5..pos].trim().to_string(), &line[pos + 2..]),
+                None => continue
+            }
+        } else {
+            (String::new(), line)
+        };
+
+        let (time, line) = timestamp(line);
+
+        let line = if let Some(start) = line.strip_suffix(" <unfinished ...>") {
+            unfinished.insert(pid, start.to_string());
+            continue;
+        } else if line.starts_with("<... ") {
+            let rest = match line.find(" resumed>") {
+                Some(pos) => &line[pos + 9..],
+                None => continue
+            };
+
+            match unfinished.remove(&pid) {
+                Some(start) => format!("{}{}", start, rest),
+                None => continue
+            }
+        } else {
+            line.to_string()
+        };
+
+        if let Some(call) = parse_call(time, &line) {
+            calls.push(call);
+        }
+    }
+
+    calls
+}
+
+/// Decode the escapes of a string printed by strace
+fn unescape(s: &str) -> Vec<u8> {
+    let mut out = Vec::new();
+    let bytes = s.as_bytes();
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if bytes[i] != b'\\' || i + 1 == bytes.len() {
+            out.push(bytes[i]);
+            i += 1;
+            continue;
+        }
+
+        i += 1;
+        match bytes[i] {
+            b'n' => { out.push(b'\n'); i += 1; },
+            b't' => { out.push(b'\t'); i += 1; },
+            b'r' => { out.push(b'\r'); i += 1; },
+            b'v' => { out.push(0x0b); i += 1; },
+            b'f' => { out.push(0x0c); i += 1; },
+            b'x' => {
+                let hex = s.get(i + 1..i + 3).and_then(|x| u8::from_str_radix(x, 16).ok());
+                match hex {
+                    Some(x) => { out.push(x); i += 3; },
+                    None => { out.push(b'x'); i += 1; }
+                }
+            },
+            b'0'..=b'7' => {
+                let mut value = 0u32;
+                let mut len = 0;
+                while len < 3 && i < bytes.len() && bytes[i] >= b'0' && bytes[i] <= b'7' {
+                    value = value * 8 + (bytes[i] - b'0') as u32;
+                    i += 1;
+                    len += 1;
+                }
+
+                out.push(value as u8);
+            },
+            x => { out.push(x); i += 1; }
+        }
+    }
+
+    out
+}
+
+/// All quoted strings of the arguments
+fn strings(args: &str) -> Vec<Vec<u8>> {
+    let mut strings = Vec::new();
+    let bytes = args.as_bytes();
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if bytes[i] != b'"' {
+            i += 1;
+            continue;
+        }
+
+        let start = i + 1;
+        i = start;
+        while i < bytes.len() && bytes[i] != b'"' {
+            i += if bytes[i] == b'\\' { 2 } else { 1 };
+        }
+
+        let end = i.min(bytes.len());
+        strings.push(unescape(&args[start..end]));
+        i = end + 1;
+    }
+
+    strings
+}
+
+/// Payload of a read or write, padded with zeros where strace cut it off
+fn payload(call: &Call) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for part in strings(&call.args) {
+        buf.extend(part);
+    }
+
+    buf.resize(call.ret as usize, 0);
+
+    buf
+}
+
+/// The first IPv4 address of the arguments
+fn address(args: &str) -> Option<Addr> {
+    let port = args.find("htons(").and_then(|pos| {
+        args[pos + 6..].split(')').next()?.parse::<u16>().ok()
+    })?;
+    let ip = args.find("inet_addr(\"").and_then(|pos| {
+        args[pos + 11..].split('"').next()?.parse::<Ipv4Addr>().ok()
+    })?;
+
+    Some(SocketAddrV4::new(ip, port))
+}
+
+/// Whether the arguments of `socket` create an IPv4 stream socket
+fn stream(args: &str) -> bool {
+    let mut args = args.split(',').map(|x| x.trim());
+
+    args.next() == Some("AF_INET") && args.next().map(|x| x.split('|').any(|x| x == "SOCK_STREAM")).unwrap_or(false)
+}
+
+fn fd(args: &str) -> Option<Fd> {
+    args.split(',').next()?.trim().parse().ok()
+}
+
+/// Convert the socket calls of an strace log into a recording
+///
+/// The log should be taken with `strace -f -tt -s 65535`: `-f` follows the threads of the
+/// runtime, timestamps keep the timing and a large string size keeps the payload, cut off
+/// payload is padded with zeros. Without timestamps the calls are spaced a millisecond
+/// apart. Only IPv4 stream sockets are converted, local addresses of connections come from
+/// `getsockname` if the application asked for them.
+pub fn convert(log: &str) -> Vec<(u64, Record)> {
+    let calls = calls(log);
+
+    let mut sockets = HashSet::new();
+    let mut records = Vec::new();
+    let mut next_port = FIRST_PORT;
+    let mut start = None;
+    let mut elapsed = 0.0;
+
+    let local_ip = calls.iter()
+        .filter(|x| x.name == "bind")
+        .filter_map(|x| address(&x.args))
+        .map(|x| *x.ip())
+        .next()
+        .unwrap_or(Ipv4Addr::new(127, 0, 0, 1));
+
+    for (i, call) in calls.iter().enumerate() {
+        let time = match call.time {
+            Some(Stamp::Absolute(t)) => {
+                let start = *start.get_or_insert(t);
+                ((t - start).max(0.0) * 1_000_000.0) as u64
+            },
+            Some(Stamp::Relative(t)) => {
+                elapsed += t;
+                (elapsed * 1_000_000.0) as u64
+            },
+            None => i as u64 * STEP
+        };
+
+        // the socket is created from the domain and type, there is no descriptor yet
+        if call.name == "socket" {
+            if call.ret >= 0 && stream(&call.args) {
+                sockets.insert(call.ret as Fd);
+            }
+
+            continue;
+        }
+
+        let fd = match fd(&call.args) {
+            Some(fd) => fd,
+            None => continue
+        };
+
+        let record = match call.name.as_str() {
+            "close" => {
+                sockets.remove(&fd);
+                None
+            },
+            "bind" if call.ret == 0 => address(&call.args).map(|addr| Record::Bind(fd, addr)),
+            "connect" if call.ret == 0 || call.errno.as_ref().map(|x| x == "EINPROGRESS").unwrap_or(false) => {
+                let remote = match address(&call.args) {
+                    Some(remote) => remote,
+                    None => continue
+                };
+
+                // the local address, if the application asked for it before closing the socket
+                let local = calls[i + 1..].iter()
+                    .take_while(|x| !(x.name == "close" && self::fd(&x.args) == Some(fd)))
+                    .find(|x| x.name == "getsockname" && self::fd(&x.args) == Some(fd))
+                    .and_then(|x| address(&x.args))
+                    .unwrap_or_else(|| {
+                        next_port += 1;
+                        SocketAddrV4::new(local_ip, next_port)
+                    });
+
+                Some(Record::Connect(fd, local, remote))
+            },
+            "accept" | "accept4" if call.ret >= 0 => {
+                sockets.insert(call.ret as Fd);
+                address(&call.args).map(|peer| Record::Accept(fd, call.ret as Fd, peer))
+            },
+            "sendto" | "send" | "write" | "writev" | "sendmsg" if call.ret > 0 && sockets.contains(&fd) =>
+                Some(Record::Send(fd, payload(call))),
+            "recvfrom" | "recv" | "read" | "readv" | "recvmsg" if call.ret > 0 && sockets.contains(&fd) =>
+                Some(Record::Recv(fd, payload(call))),
+            _ => None
+        };
+
+        if let Some(record) = record {
+            records.push((time, record));
+        }
+    }
+
+    records
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn calls_are_parsed() {
+        let call = parse_call(None, "connect(3, {sa_family=AF_INET, sin_port=htons(80), sin_addr=inet_addr(\"10.0.0.1\")}, 16) = -1 EINPROGRESS (Operation now in progress)").unwrap();
+        assert_eq!(call.name, "connect");
+        assert_eq!(call.args, "3, {sa_family=AF_INET, sin_port=htons(80), sin_addr=inet_addr(\"10.0.0.1\")}, 16");
+        assert_eq!(call.ret, -1);
+        assert_eq!(call.errno, Some("EINPROGRESS".to_string()));
+        assert_eq!(address(&call.args), Some(SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 80)));
+
+        let call = parse_call(None, "read(5, \"a = b\", 5)        = 5").unwrap();
+        assert_eq!(call.args, "5, \"a = b\", 5");
+        assert_eq!(call.ret, 5);
+        assert_eq!(call.errno, None);
+
+        assert!(parse_call(None, "+++ exited with 0 +++").is_none());
+        assert!(parse_call(None, "--- SIGCHLD {si_signo=SIGCHLD} ---").is_none());
+        assert!(parse_call(None, "exit_group(0) = ?").is_none());
+    }
+
+    #[test]
+    fn interrupted_calls_are_joined() {
+        let log = "[pid 11] 10:00:00.000100 recvfrom(7,  <unfinished ...>\n\
+                   [pid 12] 10:00:00.000200 write(8, \"x\", 1) = 1\n\
+                   [pid 11] 10:00:00.000300 <... recvfrom resumed>\"hi\", 2, 0, NULL, NULL) = 2\n\
+                   [pid 13] 10:00:00.000400 <... read resumed>\"lost\", 4) = 4\n";
+
+        let calls = calls(log);
+        assert_eq!(calls.len(), 2);
+        assert_eq!(calls[0].name, "write");
+        assert_eq!(calls[1].name, "recvfrom");
+        assert_eq!(calls[1].args, "7, \"hi\", 2, 0, NULL, NULL");
+        assert_eq!(calls[1].ret, 2);
+    }
+
+    #[test]
+    fn escapes_are_decoded() {
+        assert_eq!(unescape("a\\nb\\t"), b"a\nb\t".to_vec());
+        assert_eq!(unescape("\\x41\\xzz"), b"Axzz".to_vec());
+        assert_eq!(unescape("\\0\\177\\1234"), vec![0, 0o177, 0o123, b'4']);
+        assert_eq!(unescape("\\\"q\\\\"), b"\"q\\".to_vec());
+        assert_eq!(unescape("end\\"), b"end\\".to_vec());
+    }
+
+    #[test]
+    fn timestamps_are_split_off() {
+        match timestamp("10:00:01.5 read(3) = 0") {
+            (Some(Stamp::Absolute(t)), rest) => {
+                assert_eq!(t, 36001.5);
+                assert_eq!(rest, "read(3) = 0");
+            },
+            _ => panic!("no time of the day")
+        }
+
+        match timestamp("1500000000.25 read(3) = 0") {
+            (Some(Stamp::Absolute(t)), _) => assert_eq!(t, 1500000000.25),
+            _ => panic!("no time since the epoch")
+        }
+
+        match timestamp("0.000150 read(3) = 0") {
+            (Some(Stamp::Relative(t)), _) => assert_eq!(t, 0.00015),
+            _ => panic!("no relative time")
+        }
+
+        match timestamp("read(3, \"a b\", 3) = 3") {
+            (None, rest) => assert_eq!(rest, "read(3, \"a b\", 3) = 3"),
+            _ => panic!("a time without timestamps")
+        }
+    }
+
+    #[test]
+    fn outgoing_connections_are_converted() {
+        let log = "socket(AF_INET, SOCK_DGRAM|SOCK_CLOEXEC, IPPROTO_IP) = 4\n\
+                   socket(AF_INET6, SOCK_STREAM, IPPROTO_TCP) = 5\n\
+                   socket(AF_INET, SOCK_STREAM|SOCK_CLOEXEC|SOCK_NONBLOCK, IPPROTO_TCP) = 3\n\
+                   connect(3, {sa_family=AF_INET, sin_port=htons(80), sin_addr=inet_addr(\"10.0.0.1\")}, 16) = -1 EINPROGRESS (Operation now in progress)\n\
+                   sendto(3, \"ping\", 4, MSG_NOSIGNAL, NULL, 0) = 4\n\
+                   recvfrom(3, \"pong\", 8192, 0, NULL, NULL) = 4\n\
+                   sendto(4, \"dgram\", 5, 0, NULL, 0) = 5\n\
+                   write(5, \"v6\", 2) = 2\n\
+                   close(3) = 0\n\
+                   write(3, \"closed\", 6) = 6\n";
+
+        let records = convert(log);
+        let lines = records.iter()
+            .map(|(time, record)| format!("{} {}", time, record))
+            .collect::<Vec<_>>();
+
+        assert_eq!(lines, ["3000 connect 3 127.0.0.1:40001 -> 10.0.0.1:80", "4000 send 3 4 bytes", "5000 recv 3 4 bytes"]);
+
+        match (&records[1].1, &records[2].1) {
+            (Record::Send(_, sent), Record::Recv(_, received)) => {
+                assert_eq!(sent, b"ping");
+                assert_eq!(received, b"pong");
+            },
+            _ => unreachable!()
+        }
+    }
+}
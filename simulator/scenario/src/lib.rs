@@ -0,0 +1,841 @@
+//! Scenario description for simulations
+//!
+//! A scenario is a TOML file describing the nodes of a simulated network, their address
+//! ranges, the latency classes of the links between them, join times, contacts, push
+//! schedules, faults, NAT boxes, firewalls, host names, the clocks and CPUs of the nodes,
+//! adversarial nodes and how listeners with a full accept queue treat further connects. It
+//! is read by the simulator (through the `PEERSIM_SCENARIO` environment variable) and by the
+//! example application, so both agree on the topology.
+//!
+//! All times are given in microseconds of simulated time. The `regions` module generates
+//! scenarios spreading nodes over real regions, without writing the latencies by hand.
+//!
+//! ```toml
+//! seed = 42
+//! epoch = 1500000000
+//! default_latency = "lan"
+//! overflow = "retry"
+//!
+//! [latency.lan]
+//! latency = 200
+//!
+//! [latency.wan]
+//! latency = 40000
+//! jitter = 5000
+//!
+//! [[ranges]]
+//! name = "eu"
+//! network = "10.1.0.0/16"
+//!
+//! [[ranges]]
+//! name = "us"
+//! network = "10.2.0.0/16"
+//!
+//! [[links]]
+//! from = "eu"
+//! to = "us"
+//! latency = "wan"
+//!
+//! [[nodes]]
+//! name = "seed"
+//! addr = "10.1.0.1:8000"
+//!
+//! [[nodes]]
+//! count = 20
+//! range = "us"
+//! join = 1000
+//! join_every = 500
+//! contact = "seed"
+//!
+//! [[pushes]]
+//! node = "seed"
+//! at = 100000
+//! every = 100000
+//! count = 5
+//!
+//! [[faults]]
+//! kind = "partition"
+//! between = ["eu", "us"]
+//! at = 200000
+//! until = 400000
+//!
+//! [[nats]]
+//! range = "us"
+//! public = "198.51.100.1"
+//! kind = "symmetric"
+//!
+//! [[firewalls]]
+//! range = "eu"
+//! allow = ["us"]
+//!
+//! [[clocks]]
+//! range = "us"
+//! offset = -2000000
+//! drift = 50
+//!
+//! [[cpus]]
+//! node = "seed"
+//! speed = 0.5
+//! per_byte = 0.1
+//! per_wakeup = 20
+//!
+//! [[hosts]]
+//! name = "seeds.example.org"
+//! addrs = ["seed", "Node 1"]
+//! delay = 30000
+//!
+//! [[byzantine]]
+//! node = "Node 3"
+//! attacks = ["drop-push", "lie-peers"]
+//! ```
+
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate toml;
+
+pub mod regions;
+
+use std::fmt;
+use std::env;
+use std::fs::File;
+use std::io::{self, Read};
+use std::str::FromStr;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::collections::{HashMap, HashSet};
+
+use serde::de::{self, Deserialize, Deserializer};
+
+/// Environment variable pointing the simulator to a scenario file
+pub const SCENARIO_ENV: &str = "PEERSIM_SCENARIO";
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Parse(toml::de::Error),
+    Invalid(String)
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err)
+    }
+}
+
+impl From<toml::de::Error> for Error {
+    fn from(err: toml::de::Error) -> Error {
+        Error::Parse(err)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "could not read scenario: {}", err),
+            Error::Parse(err) => write!(f, "could not parse scenario: {}", err),
+            Error::Invalid(err) => write!(f, "invalid scenario: {}", err)
+        }
+    }
+}
+
+/// An IPv4 network in CIDR notation, like `10.0.0.0/8`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Network {
+    pub addr: Ipv4Addr,
+    pub prefix: u8
+}
+
+impl Network {
+    fn mask(&self) -> u32 {
+        match self.prefix {
+            0 => 0,
+            x => !0u32 << (32 - x as u32)
+        }
+    }
+
+    pub fn contains(&self, addr: &Ipv4Addr) -> bool {
+        u32::from(*addr) & self.mask() == u32::from(self.addr) & self.mask()
+    }
+
+    /// Number of usable host addresses, without network and broadcast address
+    pub fn hosts(&self) -> u64 {
+        match self.prefix {
+            31 | 32 => 1 << (32 - self.prefix as u32),
+            x => (1u64 << (32 - x as u32)) - 2
+        }
+    }
+
+    /// The n-th usable host address of the network
+    pub fn host(&self, n: u64) -> Option<Ipv4Addr> {
+        if n >= self.hosts() {
+            return None;
+        }
+
+        let offset = if self.prefix >= 31 { n } else { n + 1 };
+
+        Some(Ipv4Addr::from((u32::from(self.addr) & self.mask()) + offset as u32))
+    }
+}
+
+impl FromStr for Network {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Network, String> {
+        let mut parts = s.splitn(2, '/');
+        let addr = parts.next().unwrap().parse::<Ipv4Addr>()
+            .map_err(|err| format!("{}: {}", s, err))?;
+        let prefix = match parts.next() {
+            Some(x) => x.parse::<u8>().map_err(|err| format!("{}: {}", s, err))?,
+            None => 32
+        };
+
+        if prefix > 32 {
+            return Err(format!("{}: prefix larger than 32", s));
+        }
+
+        Ok(Network { addr, prefix })
+    }
+}
+
+impl<'de> Deserialize<'de> for Network {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Network, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(de::Error::custom)
+    }
+}
+
+/// Latency class of a link
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct Latency {
+    pub latency: u64,
+    #[serde(default)]
+    pub jitter: u64
+}
+
+/// A named address range
+#[derive(Deserialize, Debug, Clone)]
+pub struct Range {
+    pub name: String,
+    pub network: Network
+}
+
+/// Assigns a latency class to all links between two ranges (in both directions)
+#[derive(Deserialize, Debug, Clone)]
+pub struct Link {
+    pub from: String,
+    pub to: String,
+    pub latency: String
+}
+
+/// A group of nodes
+///
+/// Either a single node with a fixed `addr` or `count` nodes with addresses allocated from
+/// `range`. The join time of the i-th node in the group is `join + i * join_every`. The
+/// contact is the name of another node, an address or `random` for a random node defined
+/// before this one.
+#[derive(Deserialize, Debug, Clone)]
+pub struct Nodes {
+    pub name: Option<String>,
+    pub addr: Option<SocketAddrV4>,
+    pub range: Option<String>,
+    #[serde(default = "one")]
+    pub count: usize,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    #[serde(default)]
+    pub join: u64,
+    #[serde(default)]
+    pub join_every: u64,
+    pub contact: Option<String>
+}
+
+/// Push `count` blocks of `size` bytes from a node, starting at `at` and repeating `every`
+#[derive(Deserialize, Debug, Clone)]
+pub struct Push {
+    pub node: String,
+    #[serde(default)]
+    pub at: u64,
+    #[serde(default)]
+    pub every: u64,
+    #[serde(default = "one")]
+    pub count: usize,
+    #[serde(default = "one")]
+    pub size: usize
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum Fault {
+    /// The node stops sending and receiving at `at`
+    Crash { node: String, at: u64 },
+    /// No traffic between two ranges from `at` till `until`, delayed packets arrive after
+    /// the partition healed
+    Partition { between: (String, String), at: u64, until: u64 }
+}
+
+/// What happens to a connect arriving at a listener with a full accept queue
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Overflow {
+    /// The SYN is dropped and sent again after a timeout, like Linux does by default
+    #[default]
+    Retry,
+    /// The connect is refused at once, like with `tcp_abort_on_overflow`
+    Refuse
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum NatKind {
+    /// Once a node connected out, anyone may connect to its public address
+    FullCone,
+    /// Only addresses the node connected to may connect to its public address
+    Restricted,
+    /// Like restricted, but with another public port for every destination
+    Symmetric
+}
+
+/// All nodes of a range sit behind a NAT box with a single public address
+///
+/// Nodes of the range reach each other directly. Connections leaving the range get a
+/// public port of the NAT box, and connections to the private addresses from outside are
+/// refused.
+#[derive(Deserialize, Debug, Clone)]
+pub struct Nat {
+    pub range: String,
+    pub public: Ipv4Addr,
+    pub kind: NatKind
+}
+
+/// Refuses connections into a range, except from the range itself and the allowed ranges
+#[derive(Deserialize, Debug, Clone)]
+pub struct Firewall {
+    pub range: String,
+    #[serde(default)]
+    pub allow: Vec<String>
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DnsError {
+    /// The name doesn't exist
+    Nxdomain,
+    /// The name server failed, a later attempt may succeed
+    Servfail
+}
+
+/// A host name of the simulated DNS
+///
+/// Every entry of `addrs` is either the name of a node or an IPv4 address and becomes an A
+/// record. Resolving the name takes `delay` of simulated time and fails with `error` if set.
+#[derive(Deserialize, Debug, Clone)]
+pub struct Host {
+    pub name: String,
+    #[serde(default)]
+    pub addrs: Vec<String>,
+    #[serde(default)]
+    pub delay: u64,
+    pub error: Option<DnsError>
+}
+
+/// Clock of a single node or all nodes of a range
+///
+/// The wall clock of the node is `offset` microseconds off and runs `drift` parts per
+/// million faster (or slower, if negative) than the simulated time.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct Clock {
+    pub node: Option<String>,
+    pub range: Option<String>,
+    #[serde(default)]
+    pub offset: i64,
+    #[serde(default)]
+    pub drift: f64
+}
+
+/// Processing costs of a single node, all nodes of a range or, without both, of all nodes
+///
+/// Handling a received packet takes `per_packet` plus `per_byte` for every byte, each wakeup
+/// by `epoll_wait` takes `per_wakeup`. All costs are divided by the `speed` of the CPU. A
+/// busy node handles its next event only after it finished the previous one.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct Cpu {
+    pub node: Option<String>,
+    pub range: Option<String>,
+    #[serde(default = "default_speed")]
+    pub speed: f64,
+    #[serde(default = "default_per_packet")]
+    pub per_packet: u64,
+    #[serde(default)]
+    pub per_byte: f64,
+    #[serde(default)]
+    pub per_wakeup: u64
+}
+
+/// Costs of nodes without a CPU in the scenario
+pub const DEFAULT_CPU: Cpu = Cpu {
+    node: None,
+    range: None,
+    speed: 1.0,
+    per_packet: 200,
+    per_byte: 0.0,
+    per_wakeup: 0
+};
+
+impl Cpu {
+    /// Simulated time to handle some packets, bytes and wakeups
+    pub fn cost(&self, packets: u64, bytes: u64, wakeups: u64) -> u64 {
+        let work = (packets * self.per_packet + wakeups * self.per_wakeup) as f64
+            + bytes as f64 * self.per_byte;
+
+        (work / self.speed).round() as u64
+    }
+}
+
+/// Mutation of the gossip frames an adversarial node sends
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Attack {
+    /// `Push` frames are never sent
+    DropPush,
+    /// `GetPeers` answers list made up peers at unreachable addresses
+    LiePeers,
+    /// Every frame is followed by the frame sent before it
+    Replay,
+    /// Length headers claim the largest frame the header allows
+    Malformed
+}
+
+/// Adversarial behaviour of a single node or all nodes of a range
+///
+/// The attacks are applied by the simulator to the outgoing gossip frames of the nodes, the
+/// application itself stays honest. A byzantine entry of the node itself wins over one of
+/// its range.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct Byzantine {
+    pub node: Option<String>,
+    pub range: Option<String>,
+    pub attacks: Vec<Attack>
+}
+
+/// Answer of the simulated DNS for a host name
+#[derive(Debug, Clone, PartialEq)]
+pub struct Resolution {
+    pub addrs: Vec<Ipv4Addr>,
+    pub delay: u64,
+    pub error: Option<DnsError>
+}
+
+/// A single node after expanding all node groups
+#[derive(Debug, Clone, PartialEq)]
+pub struct Node {
+    pub name: String,
+    pub addr: SocketAddrV4,
+    pub join: u64,
+    pub contact: Option<SocketAddrV4>
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Scenario {
+    #[serde(default)]
+    pub seed: u64,
+    /// Wall clock time in seconds at the start of the simulation, defaults to the host time
+    pub epoch: Option<u64>,
+    pub default_latency: Option<String>,
+    /// Handling of connects beyond the backlog of a listener
+    #[serde(default)]
+    pub overflow: Overflow,
+    #[serde(default)]
+    pub latency: HashMap<String, Latency>,
+    #[serde(default)]
+    pub ranges: Vec<Range>,
+    #[serde(default)]
+    pub links: Vec<Link>,
+    #[serde(default, rename = "nodes")]
+    pub groups: Vec<Nodes>,
+    #[serde(default)]
+    pub pushes: Vec<Push>,
+    #[serde(default)]
+    pub faults: Vec<Fault>,
+    #[serde(default)]
+    pub nats: Vec<Nat>,
+    #[serde(default)]
+    pub firewalls: Vec<Firewall>,
+    #[serde(default)]
+    pub hosts: Vec<Host>,
+    #[serde(default)]
+    pub clocks: Vec<Clock>,
+    #[serde(default)]
+    pub cpus: Vec<Cpu>,
+    #[serde(default)]
+    pub byzantine: Vec<Byzantine>,
+    #[serde(skip)]
+    nodes: Vec<Node>,
+    #[serde(skip)]
+    by_name: HashMap<String, usize>,
+    #[serde(skip)]
+    by_ip: HashMap<Ipv4Addr, usize>,
+    #[serde(skip)]
+    resolutions: HashMap<String, Resolution>
+}
+
+fn one() -> usize {
+    1
+}
+
+fn default_port() -> u16 {
+    8000
+}
+
+fn default_speed() -> f64 {
+    DEFAULT_CPU.speed
+}
+
+fn default_per_packet() -> u64 {
+    DEFAULT_CPU.per_packet
+}
+
+impl Scenario {
+    pub fn from_file(path: &str) -> Result<Scenario, Error> {
+        let mut content = String::new();
+        File::open(path)?.read_to_string(&mut content)?;
+
+        content.parse()
+    }
+
+    /// Load the scenario named by the `PEERSIM_SCENARIO` environment variable
+    pub fn from_env() -> Option<Result<Scenario, Error>> {
+        env::var(SCENARIO_ENV).ok().map(|path| Scenario::from_file(&path))
+    }
+
+    /// All nodes of the scenario in the order of their definition
+    pub fn nodes(&self) -> &[Node] {
+        &self.nodes
+    }
+
+    pub fn node(&self, name: &str) -> Option<&Node> {
+        self.by_name.get(name).map(|x| &self.nodes[*x])
+    }
+
+    pub fn node_by_ip(&self, ip: &Ipv4Addr) -> Option<&Node> {
+        self.by_ip.get(ip).map(|x| &self.nodes[*x])
+    }
+
+    /// The NAT box a private address sits behind
+    pub fn nat_of(&self, ip: &Ipv4Addr) -> Option<&Nat> {
+        let range = self.range_of(ip)?;
+
+        self.nats.iter().find(|x| x.range == range.name)
+    }
+
+    /// The NAT box owning a public address
+    pub fn nat_by_public(&self, ip: &Ipv4Addr) -> Option<&Nat> {
+        self.nats.iter().find(|x| x.public == *ip)
+    }
+
+    /// If both addresses are in the same range
+    pub fn same_range(&self, a: &Ipv4Addr, b: &Ipv4Addr) -> bool {
+        match (self.range_of(a), self.range_of(b)) {
+            (Some(a), Some(b)) => a.name == b.name,
+            _ => false
+        }
+    }
+
+    /// If a firewall refuses connections from one address to another
+    pub fn firewalled(&self, from: Option<&Ipv4Addr>, to: &Ipv4Addr) -> bool {
+        let to = match self.range_of(to) {
+            Some(range) => &range.name,
+            None => return false
+        };
+        let from = from.and_then(|x| self.range_of(x)).map(|x| &x.name);
+
+        self.firewalls.iter().any(|x| {
+            &x.range == to && from.map(|from| from != to && !x.allow.contains(from)).unwrap_or(true)
+        })
+    }
+
+    /// If the nodes read simulated clocks instead of the host clock
+    pub fn simulated_clocks(&self) -> bool {
+        self.epoch.is_some() || !self.clocks.is_empty()
+    }
+
+    /// The clock of a node, a clock of the node itself wins over one of its range
+    pub fn clock_of(&self, ip: &Ipv4Addr) -> Option<&Clock> {
+        let name = self.node_by_ip(ip).map(|x| &x.name);
+        let range = self.range_of(ip).map(|x| &x.name);
+
+        self.clocks.iter().find(|x| x.node.is_some() && x.node.as_ref() == name)
+            .or_else(|| self.clocks.iter().find(|x| x.range.is_some() && x.range.as_ref() == range))
+    }
+
+    /// The CPU of a node, a CPU of the node itself wins over one of its range and the default
+    pub fn cpu_of(&self, ip: &Ipv4Addr) -> &Cpu {
+        let name = self.node_by_ip(ip).map(|x| &x.name);
+        let range = self.range_of(ip).map(|x| &x.name);
+
+        self.cpus.iter().find(|x| x.node.is_some() && x.node.as_ref() == name)
+            .or_else(|| self.cpus.iter().find(|x| x.range.is_some() && x.range.as_ref() == range))
+            .or_else(|| self.cpus.iter().find(|x| x.node.is_none() && x.range.is_none()))
+            .unwrap_or(&DEFAULT_CPU)
+    }
+
+    /// The adversarial behaviour of a node, if any
+    pub fn byzantine_of(&self, ip: &Ipv4Addr) -> Option<&Byzantine> {
+        let name = self.node_by_ip(ip).map(|x| &x.name);
+        let range = self.range_of(ip).map(|x| &x.name);
+
+        self.byzantine.iter().find(|x| x.node.is_some() && x.node.as_ref() == name)
+            .or_else(|| self.byzantine.iter().find(|x| x.range.is_some() && x.range.as_ref() == range))
+    }
+
+    /// Look up a host name in the simulated DNS, names are case insensitive
+    pub fn host(&self, name: &str) -> Option<&Resolution> {
+        self.resolutions.get(&name.trim_end_matches('.').to_lowercase())
+    }
+
+    pub fn range(&self, name: &str) -> Option<&Range> {
+        self.ranges.iter().find(|x| x.name == name)
+    }
+
+    /// The first range containing an address
+    pub fn range_of(&self, ip: &Ipv4Addr) -> Option<&Range> {
+        self.ranges.iter().find(|x| x.network.contains(ip))
+    }
+
+    /// Latency class of the link between two addresses
+    pub fn latency(&self, from: &Ipv4Addr, to: &Ipv4Addr) -> Option<&Latency> {
+        let (from, to) = match (self.range_of(from), self.range_of(to)) {
+            (Some(a), Some(b)) => (a.name.as_str(), b.name.as_str()),
+            _ => return self.default_latency.as_ref().and_then(|x| self.latency.get(x))
+        };
+
+        self.links.iter()
+            .find(|x| (x.from == from && x.to == to) || (x.from == to && x.to == from))
+            .map(|x| &x.latency)
+            .or(self.default_latency.as_ref())
+            .and_then(|x| self.latency.get(x))
+    }
+
+    /// Time at which a crashed node stopped working
+    pub fn crashed(&self, ip: &Ipv4Addr) -> Option<u64> {
+        self.faults.iter().filter_map(|x| match x {
+            Fault::Crash { node, at } => self.node(node)
+                .filter(|x| x.addr.ip() == ip)
+                .map(|_| *at),
+            _ => None
+        }).min()
+    }
+
+    /// If two addresses are partitioned at `time`, returns the time the partition heals
+    pub fn partitioned(&self, from: &Ipv4Addr, to: &Ipv4Addr, time: u64) -> Option<u64> {
+        let (from, to) = match (self.range_of(from), self.range_of(to)) {
+            (Some(a), Some(b)) => (a.name.as_str(), b.name.as_str()),
+            _ => return None
+        };
+
+        self.faults.iter().filter_map(|x| match x {
+            Fault::Partition { between: (a, b), at, until } => {
+                let hit = (a == from && b == to) || (a == to && b == from);
+
+                if hit && *at <= time && time < *until {
+                    Some(*until)
+                } else {
+                    None
+                }
+            },
+            _ => None
+        }).max()
+    }
+
+    /// Expand all node groups and check the references of the scenario
+    fn resolve(&mut self) -> Result<(), Error> {
+        for name in self.default_latency.iter().chain(self.links.iter().map(|x| &x.latency)) {
+            if !self.latency.contains_key(name) {
+                return Err(Error::Invalid(format!("unknown latency class {}", name)));
+            }
+        }
+
+        for link in &self.links {
+            for name in &[&link.from, &link.to] {
+                if self.range(name).is_none() {
+                    return Err(Error::Invalid(format!("unknown range {}", name)));
+                }
+            }
+        }
+
+        let mut nodes: Vec<Node> = Vec::new();
+        let mut by_name = HashMap::new();
+        let mut by_ip = HashMap::new();
+        let mut addrs = HashSet::new();
+        let mut contacts = Vec::new();
+        let mut allocated: HashMap<String, u64> = HashMap::new();
+        let mut rng = self.seed;
+
+        // fixed addresses are never handed out for ranges
+        let fixed = self.groups.iter().filter_map(|x| x.addr).collect::<HashSet<SocketAddrV4>>();
+
+        for group in &self.groups {
+            for i in 0..group.count {
+                let name = match (&group.name, group.count) {
+                    (Some(name), 1) => name.clone(),
+                    (Some(name), _) => format!("{} {}", name, i),
+                    (None, _) => format!("Node {}", nodes.len())
+                };
+
+                let addr = match (&group.addr, &group.range) {
+                    (Some(addr), _) if group.count == 1 => *addr,
+                    (None, Some(range)) => {
+                        let network = self.range(range)
+                            .ok_or_else(|| Error::Invalid(format!("unknown range {}", range)))?
+                            .network;
+
+                        let next = allocated.entry(range.clone()).or_insert(0);
+                        let addr = loop {
+                            let ip = network.host(*next)
+                                .ok_or_else(|| Error::Invalid(format!("range {} exhausted", range)))?;
+                            *next += 1;
+
+                            let addr = SocketAddrV4::new(ip, group.port);
+                            if !addrs.contains(&addr) && !fixed.contains(&addr) {
+                                break addr;
+                            }
+                        };
+
+                        addr
+                    },
+                    _ => return Err(Error::Invalid(format!("node {} needs either a single addr or a range", name)))
+                };
+
+                if by_name.contains_key(&name) || !addrs.insert(addr) {
+                    return Err(Error::Invalid(format!("node {} ({}) is defined twice", name, addr)));
+                }
+
+                // nodes may share an ip on different ports, the first one answers lookups by ip
+                by_name.insert(name.clone(), nodes.len());
+                by_ip.entry(*addr.ip()).or_insert(nodes.len());
+
+                contacts.push((nodes.len(), group.contact.clone()));
+                nodes.push(Node {
+                    name,
+                    addr,
+                    join: group.join + i as u64 * group.join_every,
+                    contact: None
+                });
+            }
+        }
+
+        for (idx, contact) in contacts {
+            nodes[idx].contact = match contact.as_deref() {
+                None => None,
+                Some("random") if idx == 0 => None,
+                Some("random") => {
+                    rng = rng.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+
+                    Some(nodes[(rng >> 33) as usize % idx].addr)
+                },
+                Some(contact) => match by_name.get(contact) {
+                    Some(idx) => Some(nodes[*idx].addr),
+                    None => Some(contact.parse().map_err(|_| Error::Invalid(format!("unknown contact {}", contact)))?)
+                }
+            };
+        }
+
+        for push in &self.pushes {
+            if !by_name.contains_key(&push.node) {
+                return Err(Error::Invalid(format!("push from unknown node {}", push.node)));
+            }
+        }
+
+        for fault in &self.faults {
+            match fault {
+                Fault::Crash { node, .. } => if !by_name.contains_key(node) {
+                    return Err(Error::Invalid(format!("crash of unknown node {}", node)));
+                },
+                Fault::Partition { between: (a, b), .. } => for name in &[a, b] {
+                    if self.range(name).is_none() {
+                        return Err(Error::Invalid(format!("partition of unknown range {}", name)));
+                    }
+                }
+            }
+        }
+
+        for name in self.nats.iter().map(|x| &x.range)
+            .chain(self.firewalls.iter().flat_map(|x| Some(&x.range).into_iter().chain(&x.allow))) {
+            if self.range(name).is_none() {
+                return Err(Error::Invalid(format!("unknown range {} in NAT or firewall", name)));
+            }
+        }
+
+        for nat in &self.nats {
+            if self.range_of(&nat.public).is_some() {
+                return Err(Error::Invalid(format!("public address {} of a NAT lies in a range", nat.public)));
+            }
+        }
+
+        for clock in &self.clocks {
+            match (&clock.node, &clock.range) {
+                (Some(node), None) if by_name.contains_key(node) => {},
+                (None, Some(range)) if self.range(range).is_some() => {},
+                _ => return Err(Error::Invalid(format!("clock needs either a known node or a known range, got {:?}", clock)))
+            }
+
+            if clock.drift <= -1_000_000.0 {
+                return Err(Error::Invalid(format!("clock drift of {} ppm stops the clock", clock.drift)));
+            }
+        }
+
+        for cpu in &self.cpus {
+            match (&cpu.node, &cpu.range) {
+                (Some(node), None) if by_name.contains_key(node) => {},
+                (None, Some(range)) if self.range(range).is_some() => {},
+                (None, None) => {},
+                _ => return Err(Error::Invalid(format!("CPU needs either a known node, a known range or neither, got {:?}", cpu)))
+            }
+
+            if cpu.speed <= 0.0 || cpu.per_byte < 0.0 {
+                return Err(Error::Invalid(format!("CPU needs a positive speed and costs, got {:?}", cpu)));
+            }
+        }
+
+        for byzantine in &self.byzantine {
+            match (&byzantine.node, &byzantine.range) {
+                (Some(node), None) if by_name.contains_key(node) => {},
+                (None, Some(range)) if self.range(range).is_some() => {},
+                _ => return Err(Error::Invalid(format!("byzantine needs either a known node or a known range, got {:?}", byzantine)))
+            }
+        }
+
+        let mut resolutions = HashMap::new();
+        for host in &self.hosts {
+            let addrs = host.addrs.iter().map(|addr| match by_name.get(addr) {
+                Some(idx) => Ok(*nodes[*idx].addr.ip()),
+                None => addr.parse().map_err(|_| Error::Invalid(format!("host {} points to unknown node {}", host.name, addr)))
+            }).collect::<Result<Vec<Ipv4Addr>, Error>>()?;
+
+            if addrs.is_empty() && host.error.is_none() {
+                return Err(Error::Invalid(format!("host {} needs addrs or an error", host.name)));
+            }
+
+            let name = host.name.trim_end_matches('.').to_lowercase();
+            if resolutions.insert(name, Resolution { addrs, delay: host.delay, error: host.error }).is_some() {
+                return Err(Error::Invalid(format!("host {} is defined twice", host.name)));
+            }
+        }
+
+        self.by_name = by_name;
+        self.by_ip = by_ip;
+        self.nodes = nodes;
+        self.resolutions = resolutions;
+
+        Ok(())
+    }
+}
+
+impl FromStr for Scenario {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Scenario, Error> {
+        let mut scenario: Scenario = toml::from_str(s)?;
+        scenario.resolve()?;
+
+        Ok(scenario)
+    }
+}
@@ -0,0 +1,127 @@
+//! Latency presets modelled on real regions
+//!
+//! Every region gets an address range and a latency class for links within it. Links
+//! between two regions use one-way latencies derived from typical round trip times between
+//! the data centers of public clouds, with a jitter of a tenth of the latency.
+//!
+//! ```text
+//! peer_simulator simulate --nodes 60 --regions eu,na,apac
+//! ```
+//!
+//! spreads 60 nodes round robin over Europe, North America and Asia-Pacific, every node
+//! contacting a random node started before it.
+
+use std::fmt::Write;
+
+use Error;
+
+/// A region of the presets
+pub struct Region {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub network: &'static str,
+    /// Round trip time between two nodes of the region, in milliseconds
+    pub rtt: u64
+}
+
+pub const REGIONS: &[Region] = &[
+    Region { name: "eu", description: "Europe", network: "10.1.0.0/16", rtt: 20 },
+    Region { name: "na", description: "North America", network: "10.2.0.0/16", rtt: 30 },
+    Region { name: "sa", description: "South America", network: "10.3.0.0/16", rtt: 30 },
+    Region { name: "apac", description: "Asia-Pacific", network: "10.4.0.0/16", rtt: 40 }
+];
+
+/// Round trip times between two regions, in milliseconds
+const RTTS: &[(&str, &str, u64)] = &[
+    ("eu", "na", 90),
+    ("eu", "sa", 190),
+    ("eu", "apac", 250),
+    ("na", "sa", 130),
+    ("na", "apac", 170),
+    ("sa", "apac", 320)
+];
+
+pub fn region(name: &str) -> Option<&'static Region> {
+    REGIONS.iter().find(|x| x.name == name)
+}
+
+/// Round trip time between two regions in milliseconds
+pub fn rtt(a: &str, b: &str) -> Option<u64> {
+    if a == b {
+        return region(a).map(|x| x.rtt);
+    }
+
+    RTTS.iter()
+        .find(|x| (x.0 == a && x.1 == b) || (x.0 == b && x.1 == a))
+        .map(|x| x.2)
+}
+
+/// Write the latency class of a link with the given round trip time
+fn latency_class(source: &mut String, name: &str, rtt: u64) {
+    let latency = rtt * 1000 / 2;
+
+    writeln!(source, "[latency.{}]", name).unwrap();
+    writeln!(source, "latency = {}", latency).unwrap();
+    writeln!(source, "jitter = {}\n", latency / 10).unwrap();
+}
+
+fn link(source: &mut String, from: &str, to: &str, latency: &str) {
+    writeln!(source, "[[links]]").unwrap();
+    writeln!(source, "from = \"{}\"", from).unwrap();
+    writeln!(source, "to = \"{}\"", to).unwrap();
+    writeln!(source, "latency = \"{}\"\n", latency).unwrap();
+}
+
+/// Scenario source with `nodes` nodes spread round robin over the named regions
+///
+/// The nodes of a region are named after it, like `eu 0`. The first node has no contact,
+/// every later one contacts a random node defined before it. The source is returned
+/// instead of a parsed scenario, so it can be handed to the simulator as a file.
+pub fn preset(names: &[&str], nodes: usize) -> Result<String, Error> {
+    if names.is_empty() {
+        return Err(Error::Invalid("no regions given".into()));
+    }
+
+    let regions = names.iter()
+        .map(|name| region(name).ok_or_else(|| Error::Invalid(format!("unknown region {}", name))))
+        .collect::<Result<Vec<&Region>, Error>>()?;
+
+    let mut source = String::new();
+
+    for (i, a) in regions.iter().enumerate() {
+        latency_class(&mut source, a.name, a.rtt);
+
+        for b in &regions[i + 1..] {
+            latency_class(&mut source, &format!("{}-{}", a.name, b.name), rtt(a.name, b.name).unwrap());
+        }
+    }
+
+    for region in &regions {
+        writeln!(source, "[[ranges]]").unwrap();
+        writeln!(source, "name = \"{}\"", region.name).unwrap();
+        writeln!(source, "network = \"{}\"\n", region.network).unwrap();
+    }
+
+    for (i, a) in regions.iter().enumerate() {
+        link(&mut source, a.name, a.name, a.name);
+
+        for b in &regions[i + 1..] {
+            link(&mut source, a.name, b.name, &format!("{}-{}", a.name, b.name));
+        }
+    }
+
+    // a group per node keeps the round robin order, the random contact picks an earlier node
+    let mut counts = vec![0; regions.len()];
+    for i in 0..nodes {
+        let idx = i % regions.len();
+
+        writeln!(source, "[[nodes]]").unwrap();
+        writeln!(source, "name = \"{} {}\"", regions[idx].name, counts[idx]).unwrap();
+        writeln!(source, "range = \"{}\"", regions[idx].name).unwrap();
+        writeln!(source, "contact = \"random\"\n").unwrap();
+
+        counts[idx] += 1;
+    }
+
+    Ok(source)
+}
@@ -0,0 +1,268 @@
+extern crate scenario;
+
+use std::net::SocketAddrV4;
+
+use scenario::{regions, Error, Scenario};
+
+fn addrs(scenario: &Scenario) -> Vec<SocketAddrV4> {
+    scenario.nodes().iter().map(|x| x.addr).collect()
+}
+
+/// Message of the error a scenario fails with
+fn invalid(source: &str) -> String {
+    match source.parse::<Scenario>() {
+        Err(Error::Invalid(msg)) => msg,
+        Err(err) => panic!("expected an invalid scenario, got {}", err),
+        Ok(_) => panic!("expected an invalid scenario")
+    }
+}
+
+#[test]
+fn nodes_may_share_an_ip_on_different_ports() {
+    let scenario: Scenario = "
+        [[ranges]]
+        name = \"dc\"
+        network = \"10.0.0.0/24\"
+
+        [[nodes]]
+        count = 2
+        range = \"dc\"
+
+        [[nodes]]
+        name = \"fixed\"
+        addr = \"10.0.0.1:9000\"
+    ".parse().unwrap();
+
+    assert_eq!(addrs(&scenario), vec![
+        "10.0.0.1:8000".parse().unwrap(),
+        "10.0.0.2:8000".parse().unwrap(),
+        "10.0.0.1:9000".parse().unwrap()
+    ]);
+    assert_eq!(scenario.node_by_ip(&"10.0.0.1".parse().unwrap()).unwrap().name, "Node 0");
+}
+
+#[test]
+fn ranges_skip_fixed_addresses() {
+    let scenario: Scenario = "
+        [[ranges]]
+        name = \"dc\"
+        network = \"10.0.0.0/24\"
+
+        [[nodes]]
+        count = 2
+        range = \"dc\"
+
+        [[nodes]]
+        name = \"fixed\"
+        addr = \"10.0.0.1:8000\"
+    ".parse().unwrap();
+
+    assert_eq!(addrs(&scenario), vec![
+        "10.0.0.2:8000".parse().unwrap(),
+        "10.0.0.3:8000".parse().unwrap(),
+        "10.0.0.1:8000".parse().unwrap()
+    ]);
+}
+
+#[test]
+fn latencies_follow_the_links_between_ranges() {
+    let scenario: Scenario = "
+        default_latency = \"lan\"
+
+        [latency.lan]
+        latency = 200
+
+        [latency.wan]
+        latency = 40000
+        jitter = 5000
+
+        [[ranges]]
+        name = \"eu\"
+        network = \"10.1.0.0/16\"
+
+        [[ranges]]
+        name = \"us\"
+        network = \"10.2.0.0/16\"
+
+        [[links]]
+        from = \"eu\"
+        to = \"us\"
+        latency = \"wan\"
+
+        [[nodes]]
+        name = \"seed\"
+        addr = \"10.1.0.1:8000\"
+
+        [[pushes]]
+        node = \"seed\"
+        at = 100000
+        every = 100000
+        count = 5
+
+        [[faults]]
+        kind = \"partition\"
+        between = [\"eu\", \"us\"]
+        at = 200000
+        until = 400000
+    ".parse().unwrap();
+
+    let eu = "10.1.0.1".parse().unwrap();
+    let us = "10.2.0.1".parse().unwrap();
+
+    assert_eq!(scenario.latency(&eu, &us).map(|x| (x.latency, x.jitter)), Some((40000, 5000)));
+    assert_eq!(scenario.latency(&us, &eu).map(|x| x.latency), Some(40000));
+    assert_eq!(scenario.latency(&eu, &"10.1.0.2".parse().unwrap()).map(|x| x.latency), Some(200));
+    assert_eq!(scenario.pushes.len(), 1);
+    assert_eq!(scenario.partitioned(&eu, &us, 100000), None);
+    assert_eq!(scenario.partitioned(&us, &eu, 200000), Some(400000));
+}
+
+#[test]
+fn groups_take_addresses_and_join_times_in_order() {
+    let scenario: Scenario = "
+        [[ranges]]
+        name = \"dc\"
+        network = \"10.0.0.0/24\"
+
+        [[nodes]]
+        name = \"peer\"
+        count = 3
+        range = \"dc\"
+        port = 9000
+        join = 1000
+        join_every = 500
+    ".parse().unwrap();
+
+    let nodes = scenario.nodes().iter()
+        .map(|x| (x.name.as_str(), x.addr.to_string(), x.join))
+        .collect::<Vec<_>>();
+
+    assert_eq!(nodes, vec![
+        ("peer 0", "10.0.0.1:9000".to_string(), 1000),
+        ("peer 1", "10.0.0.2:9000".to_string(), 1500),
+        ("peer 2", "10.0.0.3:9000".to_string(), 2000)
+    ]);
+}
+
+#[test]
+fn contacts_by_name_address_or_random() {
+    let scenario: Scenario = "
+        seed = 7
+
+        [[ranges]]
+        name = \"dc\"
+        network = \"10.0.0.0/24\"
+
+        [[nodes]]
+        name = \"seed\"
+        range = \"dc\"
+        contact = \"random\"
+
+        [[nodes]]
+        name = \"named\"
+        range = \"dc\"
+        contact = \"seed\"
+
+        [[nodes]]
+        name = \"outside\"
+        range = \"dc\"
+        contact = \"192.168.1.1:8000\"
+
+        [[nodes]]
+        count = 5
+        range = \"dc\"
+        contact = \"random\"
+    ".parse().unwrap();
+
+    let nodes = scenario.nodes();
+    assert_eq!(nodes[0].contact, None);
+    assert_eq!(nodes[1].contact, Some(nodes[0].addr));
+    assert_eq!(nodes[2].contact, Some("192.168.1.1:8000".parse().unwrap()));
+
+    // a random contact is always a node defined before
+    for (i, node) in nodes.iter().enumerate().skip(3) {
+        let contact = node.contact.unwrap();
+        assert!(nodes[..i].iter().any(|x| x.addr == contact));
+    }
+}
+
+#[test]
+fn unknown_contacts_are_rejected() {
+    assert_eq!(invalid("
+        [[nodes]]
+        name = \"a\"
+        addr = \"10.0.0.1:8000\"
+        contact = \"nobody\"
+    "), "unknown contact nobody");
+}
+
+#[test]
+fn duplicate_nodes_are_rejected() {
+    assert_eq!(invalid("
+        [[nodes]]
+        name = \"a\"
+        addr = \"10.0.0.1:8000\"
+
+        [[nodes]]
+        name = \"a\"
+        addr = \"10.0.0.2:8000\"
+    "), "node a (10.0.0.2:8000) is defined twice");
+
+    assert_eq!(invalid("
+        [[nodes]]
+        name = \"a\"
+        addr = \"10.0.0.1:8000\"
+
+        [[nodes]]
+        name = \"b\"
+        addr = \"10.0.0.1:8000\"
+    "), "node b (10.0.0.1:8000) is defined twice");
+}
+
+#[test]
+fn bad_ranges_are_rejected() {
+    assert_eq!(invalid("
+        [[nodes]]
+        count = 2
+        range = \"nowhere\"
+    "), "unknown range nowhere");
+
+    assert_eq!(invalid("
+        [[ranges]]
+        name = \"tiny\"
+        network = \"10.0.0.0/30\"
+
+        [[nodes]]
+        count = 3
+        range = \"tiny\"
+    "), "range tiny exhausted");
+
+    match "
+        [[ranges]]
+        name = \"wide\"
+        network = \"10.0.0.0/33\"
+    ".parse::<Scenario>() {
+        Err(Error::Parse(err)) => assert!(err.to_string().contains("prefix larger than 32")),
+        other => panic!("expected a parse error, got {:?}", other.map(|_| ()))
+    }
+}
+
+#[test]
+fn region_presets_spread_the_nodes() {
+    let scenario: Scenario = regions::preset(&["eu", "apac"], 5).unwrap().parse().unwrap();
+
+    let names = scenario.nodes().iter().map(|x| x.name.as_str()).collect::<Vec<_>>();
+    assert_eq!(names, vec!["eu 0", "apac 0", "eu 1", "apac 1", "eu 2"]);
+
+    let eu = scenario.node("eu 0").unwrap().addr;
+    let apac = scenario.node("apac 0").unwrap().addr;
+    assert_eq!(scenario.latency(eu.ip(), apac.ip()).map(|x| x.latency), Some(125000));
+}
+
+#[test]
+fn unknown_regions_are_rejected() {
+    match regions::preset(&["eu", "mars"], 4) {
+        Err(Error::Invalid(msg)) => assert_eq!(msg, "unknown region mars"),
+        other => panic!("expected an unknown region, got {:?}", other)
+    }
+}
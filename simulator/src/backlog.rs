@@ -0,0 +1,86 @@
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet};
+
+use libc::c_int;
+
+use state::Fd;
+
+/// Largest backlog of a listener, like `net.core.somaxconn`
+const SOMAXCONN: usize = 4096;
+
+/// Time before a dropped SYN is sent again, doubled with every retransmission, in microseconds
+pub const SYN_TIMEOUT: u64 = 1_000_000;
+
+/// Retransmissions of a SYN before the connect fails, like `net.ipv4.tcp_syn_retries`
+pub const SYN_RETRIES: u32 = 6;
+
+/// Accept queues of the listening sockets
+///
+/// A connect enters the queue of its listener when it arrives and leaves it when the
+/// application accepts it. Like Linux, a queue holds up to backlog + 1 connections, further
+/// connects find it full. Sockets without `listen` have unbounded queues.
+#[derive(Serialize, Deserialize, Clone, Default, Debug, PartialEq)]
+pub struct Backlogs {
+    limits: BTreeMap<Fd, usize>,
+    /// Connecting sockets waiting in the queue of a listener
+    queued: BTreeMap<Fd, BTreeSet<Fd>>,
+    /// Retransmissions of the SYN of a connecting socket so far
+    retries: BTreeMap<Fd, u32>
+}
+
+impl Backlogs {
+    pub fn listen(&mut self, fd: Fd, backlog: c_int) {
+        self.limits.insert(fd, cmp::min(cmp::max(backlog, 0) as usize, SOMAXCONN));
+    }
+
+    /// Put an arriving connect into the accept queue, returns `false` if the queue is full
+    pub fn arrive(&mut self, listener: Fd, origin: Fd) -> bool {
+        let queue = self.queued.entry(listener).or_default();
+        if queue.contains(&origin) {
+            return true;
+        }
+
+        if let Some(limit) = self.limits.get(&listener) {
+            if queue.len() > *limit {
+                return false;
+            }
+        }
+
+        queue.insert(origin);
+        self.retries.remove(&origin);
+
+        true
+    }
+
+    /// Count a retransmission of a dropped SYN, returns its delay or `None` if the connect
+    /// gives up
+    pub fn retry(&mut self, origin: Fd) -> Option<u64> {
+        let retries = self.retries.entry(origin).or_insert(0);
+        if *retries >= SYN_RETRIES {
+            self.retries.remove(&origin);
+            return None;
+        }
+
+        let delay = SYN_TIMEOUT << *retries;
+        *retries += 1;
+
+        Some(delay)
+    }
+
+    pub fn accepted(&mut self, listener: Fd, origin: Fd) {
+        if let Some(queue) = self.queued.get_mut(&listener) {
+            queue.remove(&origin);
+        }
+    }
+
+    /// Forget a closed socket, a connecting one leaves the queue it waits in
+    pub fn close(&mut self, fd: Fd) {
+        self.limits.remove(&fd);
+        self.queued.remove(&fd);
+        self.retries.remove(&fd);
+
+        for queue in self.queued.values_mut() {
+            queue.remove(&fd);
+        }
+    }
+}
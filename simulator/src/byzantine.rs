@@ -0,0 +1,107 @@
+use std::collections::BTreeMap;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+
+use bincode::deserialize;
+use scenario::Attack;
+
+use dissect::{self, Packet, PeerPresence, VERSION};
+use state::Fd;
+
+/// Mutates the outgoing gossip frames of adversarial nodes
+///
+/// Written bytes are cut into frames per sending file descriptor, a frame is only passed on
+/// once it is complete. Streams not speaking our protocol are passed on untouched.
+#[derive(Clone, Default)]
+pub struct Adversary {
+    /// Written bytes waiting for the rest of their frame
+    streams: BTreeMap<Fd, Vec<u8>>,
+    /// The frame sent last on each stream, for replays
+    last: BTreeMap<Fd, Vec<u8>>,
+    opaque: BTreeMap<Fd, ()>
+}
+
+impl Adversary {
+    /// The bytes to send instead of a write, empty if nothing is sent yet
+    pub fn mutate(&mut self, fd: Fd, attacks: &[Attack], buf: &[u8]) -> Vec<u8> {
+        if self.opaque.contains_key(&fd) {
+            return buf.to_vec();
+        }
+
+        let mut out = Vec::new();
+        let stream = self.streams.entry(fd).or_default();
+        stream.extend_from_slice(buf);
+
+        while let Some((version, header_length, length)) = dissect::parse_header(stream) {
+            if version != VERSION {
+                self.opaque.insert(fd, ());
+                out.append(stream);
+
+                break;
+            }
+
+            if stream.len() < header_length + length {
+                break;
+            }
+
+            let frame: Vec<u8> = stream.drain(..header_length + length).collect();
+            let packet = match deserialize::<Packet>(&frame[header_length..]) {
+                Ok(packet) => packet,
+                Err(_) => {
+                    out.extend(frame);
+                    continue;
+                }
+            };
+
+            let frame = match packet {
+                Packet::Push(_) if attacks.contains(&Attack::DropPush) => continue,
+                Packet::GetPeers(Some(ref peers)) if attacks.contains(&Attack::LiePeers) => {
+                    dissect::encode(&Packet::GetPeers(Some(lies(peers.len()))))
+                },
+                _ => frame
+            };
+
+            let frame = if attacks.contains(&Attack::Malformed) {
+                malformed(&frame)
+            } else {
+                frame
+            };
+
+            out.extend_from_slice(&frame);
+
+            if attacks.contains(&Attack::Replay) {
+                if let Some(last) = self.last.get(&fd) {
+                    out.extend_from_slice(last);
+                }
+
+                self.last.insert(fd, frame);
+            }
+        }
+
+        out
+    }
+
+    pub fn close(&mut self, fd: Fd) {
+        self.streams.remove(&fd);
+        self.last.remove(&fd);
+        self.opaque.remove(&fd);
+    }
+}
+
+/// Made up peers in the documentation network, nobody answers there
+fn lies(count: usize) -> Vec<PeerPresence> {
+    (0..count.max(1)).map(|i| PeerPresence {
+        id: format!("Liar {}", i),
+        addr: SocketAddr::from(SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, (i % 254) as u8 + 1), 8000)),
+        writer: None
+    }).collect()
+}
+
+/// The frame with a length header claiming 4 GiB of payload
+fn malformed(frame: &[u8]) -> Vec<u8> {
+    let header_length = 2 + (frame[0] & 0b00000011) as usize;
+
+    let mut out = vec![VERSION << 2 | 0b11, 0xff, 0xff, 0xff, 0xff];
+    out.extend_from_slice(&frame[header_length..]);
+
+    out
+}
@@ -0,0 +1,160 @@
+use std::env;
+use std::fs::File;
+use std::io::{self, Read, BufReader, BufWriter};
+use std::hash::{Hash, Hasher};
+use std::collections::hash_map::DefaultHasher;
+use std::sync::Arc;
+use std::net::Ipv4Addr;
+
+use libc::c_int;
+use bincode::{serialize, serialize_into, deserialize_from};
+use scenario::{Scenario, SCENARIO_ENV};
+
+use rng::Rng;
+use state::{Addr, Fd, EpollId, Event, Log};
+use queue::Key;
+use ports::Reuse;
+use backlog::Backlogs;
+use options::Options;
+use metrics::Counters;
+
+/// Network state of the simulator in a serialisable and comparable form
+///
+/// All maps are stored as sorted lists, so two snapshots of the same state are equal
+/// byte by byte.
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+pub struct Snapshot {
+    pub timer: u64,
+    pub nodes: Vec<(Addr, Fd, u64)>,
+    pub connections: Vec<(Fd, Fd)>,
+    pub events: Vec<(Event, u64)>,
+    pub epoll_notify: Vec<(Fd, c_int)>,
+    pub epoll: Vec<(Fd, c_int, EpollId)>,
+    pub owner: Vec<(Fd, Addr)>,
+    pub last_delivery: Vec<(Fd, u64)>,
+    pub busy: Vec<(Addr, u64)>,
+    pub rng: Rng,
+    /// NAT mappings as node, destination, public address and the hosts contacted through it
+    pub nat: Vec<(Addr, Option<Addr>, Addr, Vec<Ipv4Addr>)>,
+    /// Addresses of the peers and sources of connections, rewritten by NAT boxes
+    pub peers: Vec<(Fd, Addr)>,
+    pub sources: Vec<(Fd, Addr)>,
+    /// Ports in use with their sockets, if they accepted a connection and the end of `TIME_WAIT`
+    pub ports: Vec<(Addr, Vec<Fd>, bool, u64)>,
+    pub backlogs: Backlogs,
+    pub reuse: Vec<(Fd, Reuse)>,
+    pub linger: Vec<(Fd, c_int)>,
+    pub options: Vec<(Fd, Options)>,
+    pub shut: Vec<Fd>,
+    pub eof: Vec<Fd>,
+    pub hung_up: Vec<(Fd, bool)>,
+    pub reset: Vec<Fd>,
+    pub errors: Vec<(Fd, c_int)>,
+    /// Packets held back by Nagle's algorithm with the time they leave
+    pub held: Vec<(Fd, Key, u64)>,
+    pub acked: Vec<(Fd, u64)>,
+    pub unread: Vec<(Fd, usize)>,
+    pub blocked: Vec<Fd>,
+    pub activity: Vec<(Fd, u64)>,
+    pub counters: Vec<(Addr, Counters)>,
+    pub delivered: u64
+}
+
+impl Snapshot {
+    pub fn digest(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        serialize(self).unwrap().hash(&mut hasher);
+
+        hasher.finish()
+    }
+}
+
+/// A snapshot written to disk together with everything needed to replay up to it
+///
+/// The application memory is not part of a checkpoint. Instead the application is started
+/// again with the same scenario and replayed deterministically until the simulated time of
+/// the checkpoint. The network state is then compared against the snapshot.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Checkpoint {
+    pub scenario: Option<String>,
+    pub snapshot: Snapshot,
+    pub logs: Vec<(u64, Log)>
+}
+
+impl Checkpoint {
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        let file = BufWriter::new(File::create(path)?);
+
+        serialize_into(file, self).map_err(io::Error::other)
+    }
+
+    pub fn load(path: &str) -> io::Result<Checkpoint> {
+        let file = BufReader::new(File::open(path)?);
+
+        deserialize_from(file).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+}
+
+/// Checkpoint actions requested through the environment
+///
+/// * `PEERSIM_CHECKPOINT=<time>:<path>` saves a checkpoint once the simulated time is reached
+/// * `PEERSIM_RESTORE=<path>` replays the application up to the checkpoint and verifies it
+/// * `PEERSIM_FORK=<scenario>` continues after the restored checkpoint with another scenario,
+///   which has to contain the same nodes but may inject different faults
+#[derive(Clone, Default)]
+pub struct Checkpoints {
+    pub save: Option<(u64, String)>,
+    pub restore: Option<Arc<Checkpoint>>,
+    pub fork: Option<Scenario>
+}
+
+impl Checkpoints {
+    pub fn from_env() -> Checkpoints {
+        let save = env::var("PEERSIM_CHECKPOINT").ok().map(|x| {
+            let mut parts = x.splitn(2, ':');
+            let time = parts.next().and_then(|x| x.parse::<u64>().ok());
+
+            match (time, parts.next()) {
+                (Some(time), Some(path)) => (time, path.to_string()),
+                _ => panic!("PEERSIM_CHECKPOINT has to look like <time>:<path>, got {}", x)
+            }
+        });
+
+        let restore = env::var("PEERSIM_RESTORE").ok().map(|path| {
+            Checkpoint::load(&path)
+                .map(Arc::new)
+                .unwrap_or_else(|err| panic!("Could not load checkpoint {}: {}", path, err))
+        });
+
+        let fork = env::var("PEERSIM_FORK").ok().map(|path| {
+            Scenario::from_file(&path).unwrap_or_else(|err| panic!("{}", err))
+        });
+
+        if fork.is_some() && restore.is_none() {
+            panic!("PEERSIM_FORK needs a checkpoint in PEERSIM_RESTORE");
+        }
+
+        Checkpoints { save, restore, fork }
+    }
+
+    /// The scenario to replay, either from the checkpoint or from the environment
+    pub fn scenario(&self) -> Option<Scenario> {
+        match self.restore {
+            Some(ref checkpoint) => checkpoint.scenario.as_ref()
+                .map(|x| x.parse().unwrap_or_else(|err| panic!("{}", err))),
+            None => Scenario::from_env()
+                .map(|x| x.unwrap_or_else(|err| panic!("{}", err)))
+        }
+    }
+}
+
+/// Source of the scenario file in use, stored alongside a checkpoint
+pub fn scenario_source() -> Option<String> {
+    env::var(SCENARIO_ENV).ok().map(|path| {
+        let mut content = String::new();
+        File::open(&path).and_then(|mut x| x.read_to_string(&mut content))
+            .unwrap_or_else(|err| panic!("Could not read scenario {}: {}", path, err));
+
+        content
+    })
+}
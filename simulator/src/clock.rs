@@ -0,0 +1,48 @@
+use std::cell::Cell;
+
+use scenario;
+
+thread_local! {
+    static REAL: Cell<u32> = const { Cell::new(0) };
+}
+
+/// Run a closure which reads the host clock
+///
+/// The time hooks answer with the clock of the running node. The simulator itself needs the
+/// host clock for wall clock waits, and has to bypass the hooks while holding the state.
+pub fn real<T, F: FnOnce() -> T>(f: F) -> T {
+    let _ = REAL.try_with(|x| x.set(x.get() + 1));
+    let res = f();
+    let _ = REAL.try_with(|x| x.set(x.get() - 1));
+
+    res
+}
+
+/// If the host clock was requested by the simulator
+///
+/// Threads shutting down read the host clock as well, their counter is gone already.
+pub fn is_real() -> bool {
+    REAL.try_with(|x| x.get() > 0).unwrap_or(true)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Kind {
+    /// Wall clock time since the UNIX epoch, affected by offset and drift
+    Realtime,
+    /// Time since the start of the simulation, only affected by drift
+    Monotonic
+}
+
+/// Monotonic clocks start at one second, some applications treat zero as unset
+const MONOTONIC_START: u64 = 1_000_000;
+
+/// Read the clock of a node at a simulated time, all times in microseconds
+pub fn read(kind: Kind, time: u64, epoch: u64, clock: Option<&scenario::Clock>) -> u64 {
+    let (offset, drift) = clock.map(|x| (x.offset, x.drift)).unwrap_or((0, 0.0));
+    let local = time as f64 * (1.0 + drift / 1_000_000.0);
+
+    match kind {
+        Kind::Realtime => (epoch as f64 + local + offset as f64).max(0.0) as u64,
+        Kind::Monotonic => MONOTONIC_START + local as u64
+    }
+}
@@ -0,0 +1,268 @@
+use std::env;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::SocketAddrV4;
+use std::os::unix::net::{UnixListener, UnixStream};
+
+use dissect::{Dissector, Frame};
+use state::{Event, Fd, State};
+
+const HELP: &str = "\
+step [n]               run the next n events (default 1)
+continue [time]        run till the next breakpoint or the simulated time is reached
+break node <addr>      stop at events for the node with this address
+break fd <fd>          stop at events for this file descriptor
+break packet <text>    stop at packets whose dissected frames contain the text
+breakpoints            list all breakpoints
+delete [n]             delete the n-th breakpoint or all of them
+print <what>           show time, event, queue, connections, epoll, nodes or trace [n]
+detach                 run to the end without stopping
+help                   this message";
+
+#[derive(Clone, Copy, PartialEq)]
+enum Mode {
+    /// Stop after the given number of events
+    Step(usize),
+    /// Stop at the first event at or after the given time
+    Until(u64),
+    Continue,
+    Detached
+}
+
+pub enum Breakpoint {
+    Node(SocketAddrV4),
+    Fd(Fd),
+    Packet(String)
+}
+
+/// Step debugger for the event loop of the simulator
+///
+/// The simulation is paused before every event is delivered. A client connects to the Unix
+/// socket given in `PEERSIM_DEBUG` (for example with `socat - UNIX-CONNECT:<path>`) and
+/// controls the execution with a small line based protocol. The debugger keeps its own
+/// dissector, fed in delivery order, to match packet breakpoints against the decoded frames.
+pub struct Debugger {
+    listener: UnixListener,
+    client: Option<(BufReader<UnixStream>, UnixStream)>,
+    mode: Mode,
+    breakpoints: Vec<Breakpoint>,
+    dissector: Dissector,
+    last: Option<(u64, Event)>
+}
+
+impl Debugger {
+    pub fn from_env() -> Option<Debugger> {
+        env::var("PEERSIM_DEBUG").ok().map(|path| {
+            let _ = fs::remove_file(&path);
+            let listener = UnixListener::bind(&path)
+                .unwrap_or_else(|err| panic!("Could not open debug socket {}: {}", path, err));
+
+            println!("Debugger: waiting on {}", path);
+
+            Debugger {
+                listener,
+                client: None,
+                mode: Mode::Step(0),
+                breakpoints: Vec::new(),
+                dissector: Dissector::new(),
+                last: None
+            }
+        })
+    }
+
+    /// Called before the next event of the queue is delivered
+    pub fn pause(&mut self, state: &State) {
+        if self.mode == Mode::Detached {
+            return;
+        }
+
+        let (event, time) = match state.next_event() {
+            Some(x) => x,
+            None => return
+        };
+
+        // the same event may be announced more than once before it is consumed
+        if self.last.as_ref() == Some(&(time, event.clone())) {
+            return;
+        }
+        self.last = Some((time, event.clone()));
+
+        let frames = match event {
+            Event::SendPacket(fd, ref buf) => self.dissector.feed(fd, buf),
+            _ => Vec::new()
+        };
+
+        let mut stop = match self.mode {
+            Mode::Step(0) => true,
+            Mode::Step(n) => { self.mode = Mode::Step(n - 1); false },
+            Mode::Until(until) => time >= until,
+            _ => false
+        };
+
+        if let Some(idx) = self.hit(state, &event, &frames) {
+            self.send(&format!("Breakpoint {} hit", idx));
+            stop = true;
+        }
+
+        if stop {
+            self.prompt(state, &describe(state, &event, time, &frames));
+        }
+    }
+
+    fn hit(&self, state: &State, event: &Event, frames: &[Frame]) -> Option<usize> {
+        let fds = match event {
+            Event::SendPacket(fd, _) | Event::Fin(fd) | Event::Reset(fd) => vec![*fd],
+            Event::Connect(from, to) => vec![*from, *to]
+        };
+
+        self.breakpoints.iter().position(|x| match x {
+            Breakpoint::Node(addr) => fds.iter().any(|fd| state.owner(*fd) == Some(*addr)),
+            Breakpoint::Fd(fd) => fds.contains(fd),
+            Breakpoint::Packet(text) => frames.iter().any(|x| x.to_string().contains(text.as_str()))
+        })
+    }
+
+    /// Block until the client resumes the simulation
+    fn prompt(&mut self, state: &State, description: &str) {
+        self.send(description);
+
+        loop {
+            if self.client.is_none() {
+                let stream = match self.listener.accept() {
+                    Ok((stream, _)) => stream,
+                    Err(_) => { self.mode = Mode::Detached; return; }
+                };
+
+                self.client = Some((BufReader::new(stream.try_clone().unwrap()), stream));
+                self.send(description);
+            }
+
+            self.send_raw("(peersim) ");
+
+            let mut line = String::new();
+            let read = match self.client {
+                Some((ref mut reader, _)) => reader.read_line(&mut line),
+                None => continue
+            };
+
+            // a lost client is replaced by the next one connecting
+            if read.map(|x| x == 0).unwrap_or(true) {
+                self.client = None;
+                continue;
+            }
+
+            let args = line.split_whitespace().collect::<Vec<&str>>();
+            let answer = match args.as_slice() {
+                [] => continue,
+                ["step"] | ["s"] => { self.mode = Mode::Step(0); return; },
+                ["step", n] | ["s", n] => match n.parse::<usize>() {
+                    Ok(n) if n > 0 => { self.mode = Mode::Step(n - 1); return; },
+                    _ => format!("invalid number {}", n)
+                },
+                ["continue"] | ["c"] => { self.mode = Mode::Continue; return; },
+                ["continue", time] | ["c", time] => match time.parse::<u64>() {
+                    Ok(time) => { self.mode = Mode::Until(time); return; },
+                    _ => format!("invalid time {}", time)
+                },
+                ["detach"] | ["quit"] => { self.mode = Mode::Detached; return; },
+                ["break", "node", addr] => match addr.parse() {
+                    Ok(addr) => self.add_breakpoint(Breakpoint::Node(addr)),
+                    Err(err) => format!("invalid address {}: {}", addr, err)
+                },
+                ["break", "fd", fd] => match fd.parse() {
+                    Ok(fd) => self.add_breakpoint(Breakpoint::Fd(fd)),
+                    Err(err) => format!("invalid fd {}: {}", fd, err)
+                },
+                ["break", "packet", ..] => {
+                    let text = args[2..].join(" ");
+                    self.add_breakpoint(Breakpoint::Packet(text))
+                },
+                ["breakpoints"] => self.breakpoints.iter().enumerate().map(|(i, x)| match x {
+                    Breakpoint::Node(addr) => format!("{}: node {}", i, addr),
+                    Breakpoint::Fd(fd) => format!("{}: fd {}", i, fd),
+                    Breakpoint::Packet(text) => format!("{}: packet {}", i, text)
+                }).collect::<Vec<String>>().join("\n"),
+                ["delete"] => { self.breakpoints.clear(); "Deleted all breakpoints".into() },
+                ["delete", n] => match n.parse::<usize>() {
+                    Ok(n) if n < self.breakpoints.len() => { self.breakpoints.remove(n); format!("Deleted breakpoint {}", n) },
+                    _ => format!("no breakpoint {}", n)
+                },
+                ["print", what] => print(state, what, None),
+                ["print", what, n] => print(state, what, n.parse().ok()),
+                ["help"] => HELP.into(),
+                _ => format!("unknown command: {}", line.trim())
+            };
+
+            self.send(&answer);
+        }
+    }
+
+    fn add_breakpoint(&mut self, breakpoint: Breakpoint) -> String {
+        self.breakpoints.push(breakpoint);
+
+        format!("Breakpoint {} set", self.breakpoints.len() - 1)
+    }
+
+    fn send(&mut self, msg: &str) {
+        self.send_raw(&format!("{}\n", msg));
+    }
+
+    fn send_raw(&mut self, msg: &str) {
+        let failed = match self.client {
+            Some((_, ref mut stream)) => stream.write_all(msg.as_bytes()).is_err(),
+            None => false
+        };
+
+        if failed {
+            self.client = None;
+        }
+    }
+}
+
+fn describe(state: &State, event: &Event, time: u64, frames: &[Frame]) -> String {
+    let node = |fd: Fd| state.owner(fd).map(|x| x.to_string()).unwrap_or("?".into());
+
+    let mut line = match event {
+        Event::SendPacket(fd, buf) => format!("[{}] deliver {} bytes to fd {} ({})", time, buf.len(), fd, node(*fd)),
+        Event::Connect(from, to) => format!("[{}] connect fd {} ({}) to listener {} ({})", time, from, node(*from), to, node(*to)),
+        Event::Fin(fd) => format!("[{}] end of stream to fd {} ({})", time, fd, node(*fd)),
+        Event::Reset(fd) => format!("[{}] abort connection of fd {} ({})", time, fd, node(*fd))
+    };
+
+    for frame in frames {
+        line.push_str(&format!("\n    {}", frame));
+    }
+
+    line
+}
+
+fn print(state: &State, what: &str, n: Option<usize>) -> String {
+    let lines = match what {
+        "time" => vec![state.time().to_string()],
+        "event" => state.next_event().into_iter()
+            .map(|(event, time)| format!("{:>10} {}", time, event)).collect(),
+        "queue" | "events" => state.pending_events().into_iter()
+            .map(|(event, time)| format!("{:>10} {}", time, event)).collect(),
+        "connections" => state.connections().into_iter()
+            .map(|(a, b)| format!("{} -> {}", a, b)).collect(),
+        "epoll" => state.epoll_registrations().iter()
+            .map(|(fd, events, id)| format!("fd {} events {:#x} id {}", fd, events, id))
+            .chain(state.get_epoll().iter().map(|(fd, events)| format!("notify fd {} events {:#x}", fd, events)))
+            .collect(),
+        "nodes" => state.listeners().into_iter()
+            .map(|(addr, fd)| format!("{} listening on fd {}", addr, fd)).collect(),
+        "trace" => {
+            let trace = state.trace();
+            let skip = trace.len().saturating_sub(n.unwrap_or(20));
+
+            trace.into_iter().skip(skip).collect()
+        },
+        _ => return format!("unknown state {}, try time, event, queue, connections, epoll, nodes or trace", what)
+    };
+
+    if lines.is_empty() {
+        "(empty)".into()
+    } else {
+        lines.join("\n")
+    }
+}
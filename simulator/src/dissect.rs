@@ -0,0 +1,165 @@
+use std::fmt;
+use std::net::SocketAddr;
+use std::collections::HashMap;
+
+use bincode::{deserialize, serialize};
+
+use state::Fd;
+
+/// The protocol version spoken by `libraries/gossip1`
+pub const VERSION: u8 = 1;
+
+/// Mirror of the `PeerPresence` in the gossip library
+///
+/// The fields have to be in the same order as the original struct, because bincode
+/// serialises them without any names.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PeerPresence {
+    pub id: String,
+    pub addr: SocketAddr,
+    pub writer: Option<usize>
+}
+
+/// Mirror of the `Packet` in the gossip library
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Packet {
+    Join(PeerPresence),
+    GetPeers(Option<Vec<PeerPresence>>),
+    Push(Vec<u8>)
+}
+
+/// A single frame cut out of a byte stream
+#[derive(Debug, Clone)]
+pub enum Frame {
+    Packet(Packet),
+    Malformed(String)
+}
+
+/// Parse the header of a gossip frame
+///
+/// The header consists of a 6bit version, a 2bit `additional` field and 1 to 4 bytes of
+/// length in little endian. Returns the version, the length of the header and the length
+/// of the payload.
+pub fn parse_header(buf: &[u8]) -> Option<(u8, usize, usize)> {
+    if buf.is_empty() {
+        return None;
+    }
+
+    let (version, meta_length) = (buf[0] >> 2, (buf[0] & 0b00000011) as usize);
+    let header_length = 2 + meta_length;
+
+    if buf.len() < header_length {
+        return None;
+    }
+
+    let length = buf[1..header_length].iter().rev()
+        .fold(0usize, |acc, x| acc << 8 | *x as usize);
+
+    Some((version, header_length, length))
+}
+
+/// Encode a packet as a gossip frame, like the `PeerCodecWrite` of the gossip library
+pub fn encode(packet: &Packet) -> Vec<u8> {
+    let payload = serialize(packet).unwrap();
+    let meta_length = (32 - (payload.len() as u32).leading_zeros()) as u8 / 8;
+
+    let mut frame = vec![VERSION << 2 | meta_length];
+    frame.extend((0..meta_length as usize + 1).map(|i| (payload.len() >> (8 * i)) as u8));
+    frame.extend(payload);
+
+    frame
+}
+
+/// Reassembles gossip frames from the byte streams of many connections
+///
+/// Every stream is identified by the receiving file descriptor. A stream with a wrong
+/// version is not speaking our protocol and won't be dissected any further.
+#[derive(Clone)]
+pub struct Dissector {
+    streams: HashMap<Fd, Vec<u8>>,
+    opaque: HashMap<Fd, ()>
+}
+
+impl Dissector {
+    pub fn new() -> Dissector {
+        Dissector {
+            streams: HashMap::new(),
+            opaque: HashMap::new()
+        }
+    }
+
+    /// Append bytes to a stream and return every completed frame
+    pub fn feed(&mut self, fd: Fd, buf: &[u8]) -> Vec<Frame> {
+        if self.opaque.contains_key(&fd) {
+            return Vec::new();
+        }
+
+        let mut frames = Vec::new();
+        let stream = self.streams.entry(fd).or_default();
+        stream.extend_from_slice(buf);
+
+        while let Some((version, header_length, length)) = parse_header(stream) {
+            if version != VERSION {
+                frames.push(Frame::Malformed(format!("unknown version {}", version)));
+                self.opaque.insert(fd, ());
+                stream.clear();
+
+                break;
+            }
+
+            // wait for the remaining fragments
+            if stream.len() < header_length + length {
+                break;
+            }
+
+            let frame: Vec<u8> = stream.drain(..header_length + length).collect();
+            match deserialize::<Packet>(&frame[header_length..]) {
+                Ok(packet) => frames.push(Frame::Packet(packet)),
+                Err(err) => frames.push(Frame::Malformed(format!("{}", err)))
+            }
+        }
+
+        frames
+    }
+
+    /// Number of bytes waiting for the rest of their frame
+    pub fn pending(&self, fd: Fd) -> usize {
+        self.streams.get(&fd).map(|x| x.len()).unwrap_or(0)
+    }
+
+    /// Forget the stream of a closed socket, its file descriptor may be reused
+    pub fn reset(&mut self, fd: Fd) {
+        self.streams.remove(&fd);
+        self.opaque.remove(&fd);
+    }
+}
+
+impl fmt::Display for PeerPresence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}@{}", self.id, self.addr)
+    }
+}
+
+impl fmt::Display for Packet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Packet::Join(presence) => write!(f, "Join({})", presence),
+            Packet::GetPeers(None) => write!(f, "GetPeers(?)"),
+            Packet::GetPeers(Some(peers)) => {
+                let peers = peers.iter().map(|x| x.to_string()).collect::<Vec<String>>();
+
+                write!(f, "GetPeers([{}])", peers.join(", "))
+            },
+            Packet::Push(data) => write!(f, "Push({} bytes)", data.len())
+        }
+    }
+}
+
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Frame::Packet(packet) => write!(f, "{}", packet),
+            Frame::Malformed(err) => write!(f, "Malformed({})", err)
+        }
+    }
+}
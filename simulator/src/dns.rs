@@ -0,0 +1,123 @@
+use std::ptr;
+use std::mem;
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::net::{IpAddr, Ipv4Addr, SocketAddrV4};
+
+use libc::{c_char, c_int, addrinfo, hostent, sockaddr, sockaddr_in, malloc, strdup, AF_INET, SOCK_STREAM, IPPROTO_TCP, AI_CANONNAME};
+
+use state::to_sockaddr;
+
+pub const HOST_NOT_FOUND: c_int = 1;
+pub const TRY_AGAIN: c_int = 2;
+
+extern "C" {
+    fn __h_errno_location() -> *mut c_int;
+}
+
+pub unsafe fn set_h_errno(err: c_int) {
+    *__h_errno_location() = err;
+}
+
+/// The host name to look up in the simulated DNS
+///
+/// Addresses and `localhost` are left to the C library, they don't need a name server.
+pub unsafe fn simulated_name(node: *const c_char) -> Option<String> {
+    if node.is_null() {
+        return None;
+    }
+
+    let name = CStr::from_ptr(node).to_str().ok()?;
+    if name.parse::<IpAddr>().is_ok() || name.eq_ignore_ascii_case("localhost") {
+        return None;
+    }
+
+    Some(name.to_string())
+}
+
+/// Port of a numeric service, services by name are not supported
+pub unsafe fn port(service: *const c_char) -> Option<u16> {
+    if service.is_null() {
+        return Some(0);
+    }
+
+    CStr::from_ptr(service).to_str().ok()?.parse().ok()
+}
+
+/// Build an address list which can be released with the `freeaddrinfo` of the C library
+///
+/// glibc frees every entry and its canonical name with `free`, so each entry is allocated in
+/// a single block together with its address. Only TCP entries are returned, since the
+/// simulator only knows streams.
+pub unsafe fn addrinfo_list(name: &str, addrs: &[Ipv4Addr], port: u16, hints: *const addrinfo) -> *mut addrinfo {
+    let (socktype, protocol, flags) = if hints.is_null() {
+        (0, 0, 0)
+    } else {
+        ((*hints).ai_socktype, (*hints).ai_protocol, (*hints).ai_flags)
+    };
+
+    let mut head: *mut addrinfo = ptr::null_mut();
+    for (i, addr) in addrs.iter().enumerate().rev() {
+        let size = mem::size_of::<addrinfo>() + mem::size_of::<sockaddr_in>();
+        let entry = malloc(size) as *mut addrinfo;
+        let sockaddr = entry.offset(1) as *mut sockaddr_in;
+
+        ptr::write(sockaddr, to_sockaddr(SocketAddrV4::new(*addr, port)));
+        ptr::write(entry, addrinfo {
+            ai_flags: flags,
+            ai_family: AF_INET,
+            ai_socktype: if socktype == 0 { SOCK_STREAM } else { socktype },
+            ai_protocol: if protocol == 0 { IPPROTO_TCP } else { protocol },
+            ai_addrlen: mem::size_of::<sockaddr_in>() as u32,
+            ai_addr: sockaddr as *mut sockaddr,
+            ai_canonname: ptr::null_mut(),
+            ai_next: head
+        });
+
+        if i == 0 && flags & AI_CANONNAME != 0 {
+            let name = CString::new(name).unwrap();
+            (*entry).ai_canonname = strdup(name.as_ptr());
+        }
+
+        head = entry;
+    }
+
+    head
+}
+
+/// Storage of the last `gethostbyname` answer, which stays valid till the next call
+struct HostEntry {
+    entry: hostent,
+    _name: CString,
+    _addrs: Vec<[u8; 4]>,
+    _list: Vec<*mut c_char>,
+    _aliases: Vec<*mut c_char>
+}
+
+thread_local! {
+    static HOST_ENTRY: RefCell<Option<Box<HostEntry>>> = const { RefCell::new(None) };
+}
+
+pub fn host_entry(name: &str, addrs: &[Ipv4Addr]) -> *mut hostent {
+    let name = CString::new(name).unwrap();
+    let mut octets = addrs.iter().map(|x| x.octets()).collect::<Vec<[u8; 4]>>();
+    let mut list = octets.iter_mut().map(|x| x.as_mut_ptr() as *mut c_char).collect::<Vec<_>>();
+    list.push(ptr::null_mut());
+    let mut aliases = vec![ptr::null_mut()];
+
+    let entry = hostent {
+        h_name: name.as_ptr() as *mut c_char,
+        h_aliases: aliases.as_mut_ptr(),
+        h_addrtype: AF_INET,
+        h_length: 4,
+        h_addr_list: list.as_mut_ptr()
+    };
+
+    // moving the vectors and the string into the box keeps their heap buffers in place
+    let mut entry = Box::new(HostEntry { entry, _name: name, _addrs: octets, _list: list, _aliases: aliases });
+    let ptr = &mut entry.entry as *mut hostent;
+
+    HOST_ENTRY.with(|x| *x.borrow_mut() = Some(entry));
+
+    ptr
+}
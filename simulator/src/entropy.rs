@@ -0,0 +1,93 @@
+use std::env;
+use std::cell::Cell;
+use std::ffi::CStr;
+use std::collections::{BTreeMap, BTreeSet};
+
+use libc::{c_char, c_int};
+
+use rng::Rng;
+use state::Addr;
+
+thread_local! {
+    static INSIDE: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Run a closure drawing simulated entropy, `None` if this thread is already doing so
+///
+/// Setting up the entropy creates hash maps, which draw their keys from `getrandom` again.
+/// These nested calls, and calls while the thread is shutting down, go to the host.
+pub fn enter<T, F: FnOnce() -> T>(f: F) -> Option<T> {
+    let entered = INSIDE.try_with(|x| !x.replace(true)).unwrap_or(false);
+    if !entered {
+        return None;
+    }
+
+    let res = f();
+    INSIDE.with(|x| x.set(false));
+
+    Some(res)
+}
+
+/// If a path names one of the random devices of the host
+pub unsafe fn is_device(path: *const c_char) -> bool {
+    if path.is_null() {
+        return false;
+    }
+
+    matches!(CStr::from_ptr(path).to_bytes(), b"/dev/urandom" | b"/dev/random")
+}
+
+/// Deterministic replacement for `getrandom` and the random devices
+///
+/// Every node draws from a stream of its own, seeded with the seed of the scenario and its
+/// address. Entropy drawn outside of any node, for example while the application starts, comes
+/// from a stream of the process. Set `PEERSIM_ENTROPY=host` to use the entropy of the host.
+pub struct Entropy {
+    seed: u64,
+    streams: BTreeMap<Option<Addr>, Rng>,
+    /// Open file descriptors of random devices
+    files: BTreeSet<c_int>
+}
+
+impl Entropy {
+    pub fn from_env<F: FnOnce() -> u64>(seed: F) -> Option<Entropy> {
+        // recorded runs are real ones
+        if env::var("PEERSIM_ENTROPY").map(|x| x == "host").unwrap_or(false) || env::var("PEERSIM_RECORD").is_ok() {
+            return None;
+        }
+
+        Some(Entropy {
+            seed: seed(),
+            streams: BTreeMap::new(),
+            files: BTreeSet::new()
+        })
+    }
+
+    /// Fill a buffer with the next bytes of the stream of a node
+    pub fn fill(&mut self, node: Option<Addr>, buf: &mut [u8]) {
+        let seed = self.seed;
+        let rng = self.streams.entry(node).or_insert_with(|| {
+            let key = node.map(|x| (u64::from(u32::from(*x.ip())) << 16 | x.port() as u64) + 1)
+                .unwrap_or(0);
+
+            Rng::new(seed ^ key.wrapping_mul(0x9E3779B97F4A7C15))
+        });
+
+        for chunk in buf.chunks_mut(8) {
+            let bytes = rng.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+
+    pub fn open(&mut self, fd: c_int) {
+        self.files.insert(fd);
+    }
+
+    pub fn is_open(&self, fd: c_int) -> bool {
+        self.files.contains(&fd)
+    }
+
+    pub fn close(&mut self, fd: c_int) {
+        self.files.remove(&fd);
+    }
+}
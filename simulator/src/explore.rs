@@ -0,0 +1,126 @@
+use std::env;
+use std::collections::HashMap;
+
+use rng::Rng;
+use state::Addr;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Strategy {
+    /// Every event gets a uniform random delay inside the window
+    Random,
+    /// Probabilistic concurrency testing: nodes get random priorities, events of lower
+    /// priority nodes are delayed, and at `depth - 1` random steps the scheduling node is
+    /// demoted to the lowest priority
+    Pct { depth: usize },
+    /// The n-th scheduling decision delays its event iff the n-th bit of the seed is set,
+    /// so the seeds `0..2^n` enumerate all orders of the first n decisions. The exploration
+    /// is bounded: only the first 64 decisions are covered, later events are never delayed
+    Systematic
+}
+
+/// Perturbs delivery orders and latencies of the simulation
+///
+/// Configured with `PEERSIM_EXPLORE=<seed>`, `PEERSIM_EXPLORE_STRATEGY=random|pct:<depth>|systematic`,
+/// `PEERSIM_EXPLORE_WINDOW=<time>` (the largest extra delay) and `PEERSIM_EXPLORE_STEPS=<n>`
+/// (the expected number of decisions, used to place the PCT change points). Packets of a
+/// single stream still arrive in order.
+#[derive(Clone)]
+pub struct Explore {
+    seed: u64,
+    strategy: Strategy,
+    window: u64,
+    rng: Rng,
+    priorities: HashMap<Addr, u64>,
+    change_points: Vec<u64>,
+    lowest: u64,
+    step: u64
+}
+
+impl Explore {
+    pub fn new(seed: u64, strategy: Strategy, window: u64, steps: u64) -> Explore {
+        let mut rng = Rng::new(seed);
+
+        let change_points = match strategy {
+            Strategy::Pct { depth } => (1..depth).map(|_| rng.below(steps)).collect(),
+            _ => Vec::new()
+        };
+
+        Explore {
+            seed,
+            strategy,
+            window,
+            rng,
+            priorities: HashMap::new(),
+            change_points,
+            lowest: 0,
+            step: 0
+        }
+    }
+
+    pub fn from_env() -> Option<Explore> {
+        let seed = env::var("PEERSIM_EXPLORE").ok()?.parse::<u64>()
+            .unwrap_or_else(|err| panic!("PEERSIM_EXPLORE has to be a seed: {}", err));
+
+        let strategy = match env::var("PEERSIM_EXPLORE_STRATEGY") {
+            Ok(ref x) if x == "random" => Strategy::Random,
+            Ok(ref x) if x == "systematic" => Strategy::Systematic,
+            Ok(ref x) if x.starts_with("pct:") => Strategy::Pct {
+                depth: x[4..].parse().unwrap_or_else(|err| panic!("Invalid PCT depth {}: {}", x, err))
+            },
+            Ok(x) => panic!("Unknown exploration strategy {}", x),
+            Err(_) => Strategy::Random
+        };
+
+        let window = env::var("PEERSIM_EXPLORE_WINDOW").ok()
+            .and_then(|x| x.parse().ok())
+            .unwrap_or(1000);
+
+        let steps = env::var("PEERSIM_EXPLORE_STEPS").ok()
+            .and_then(|x| x.parse().ok())
+            .unwrap_or(10000);
+
+        println!("Explore: seed {} with {:?} in a window of {}", seed, strategy, window);
+
+        Some(Explore::new(seed, strategy, window, steps))
+    }
+
+    /// Extra delay of an event scheduled by `node`
+    pub fn delay(&mut self, node: Option<Addr>) -> u64 {
+        let step = self.step;
+        self.step += 1;
+
+        match self.strategy {
+            Strategy::Random => self.rng.below(self.window + 1),
+            Strategy::Systematic => {
+                if step < 64 && self.seed & (1 << step) != 0 {
+                    self.window
+                } else {
+                    0
+                }
+            },
+            Strategy::Pct { .. } => {
+                let node = match node {
+                    Some(node) => node,
+                    None => return 0
+                };
+
+                if !self.priorities.contains_key(&node) {
+                    let priority = self.rng.next_u64() | 1 << 63;
+                    self.priorities.insert(node, priority);
+                }
+
+                // demote the node to a priority lower than all others
+                if self.change_points.contains(&step) {
+                    self.lowest += 1;
+                    self.priorities.insert(node, self.lowest);
+                }
+
+                // higher priorities are scheduled earlier, scaled in 128 bits so that large
+                // windows do not overflow
+                let priority = u128::from(self.priorities[&node] >> 32);
+                let window = u128::from(self.window);
+                (window - priority * window / u128::from(u32::MAX)) as u64
+            }
+        }
+    }
+}
@@ -1,6 +1,10 @@
 extern crate libc;
-extern crate priority_queue;
 extern crate errno;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate bincode;
+extern crate scenario;
 
 #[macro_use]
 extern crate lazy_static;
@@ -9,21 +13,113 @@ extern crate lazy_static;
 extern crate redhook;
 
 mod state;
+mod dissect;
+mod rng;
+mod debugger;
+mod checkpoint;
+mod explore;
+mod queue;
+mod remote;
+mod pace;
+mod dns;
+mod nat;
+mod clock;
+mod output;
+mod entropy;
+mod record;
+mod ports;
+mod backlog;
+mod options;
+mod byzantine;
+mod metrics;
 
 use std::ptr;
+use std::mem;
+use std::cmp;
+use std::collections::{BTreeSet, HashSet};
 use std::net::{Ipv4Addr, SocketAddrV4};
-use libc::{c_int, c_void, size_t, ssize_t, sockaddr, socklen_t,AF_INET, sockaddr_in, epoll_event, EPOLL_CTL_ADD, EPOLLIN, EPOLLOUT, EPOLLRDHUP, EPOLLPRI, EPOLLERR, EPOLLHUP, EPOLLET, EPOLLONESHOT, EPOLLWAKEUP, EPOLLEXCLUSIVE, c_uint, mode_t, EAGAIN, EWOULDBLOCK, iovec};
-use errno::{set_errno, Errno};
+use libc::{c_int, c_void, size_t, ssize_t, sockaddr, socklen_t,AF_INET, AF_UNIX, sockaddr_in, epoll_event, EPOLL_CTL_ADD, EPOLLIN, EPOLLOUT, EPOLLRDHUP, EPOLLPRI, EPOLLERR, EPOLLHUP, EPOLLET, EPOLLONESHOT, EPOLLWAKEUP, EPOLLEXCLUSIVE, c_uint, mode_t, EAGAIN, iovec, SOL_SOCKET, SO_ERROR, SO_REUSEADDR, SO_REUSEPORT, SO_LINGER, SO_KEEPALIVE, SO_SNDBUF, SO_RCVBUF, IPPROTO_TCP, TCP_NODELAY, TCP_KEEPIDLE, TCP_KEEPINTVL, TCP_KEEPCNT, linger, SHUT_RD, clockid_t, timespec, timeval, time_t, c_long, suseconds_t, CLOCK_REALTIME, CLOCK_REALTIME_COARSE, CLOCK_MONOTONIC, CLOCK_MONOTONIC_RAW, CLOCK_MONOTONIC_COARSE, CLOCK_BOOTTIME, socket, c_char, SYS_getrandom, addrinfo, hostent, AF_UNSPEC, EAI_NONAME, EAI_AGAIN, EAI_FAMILY, EAI_SERVICE, EINPROGRESS, SOCK_STREAM, SOCK_CLOEXEC};
+use errno::{errno, set_errno, Errno};
 
 use std::sync::{Condvar, Mutex, atomic::AtomicBool, atomic::Ordering};
 use std::slice;
-use state::State;
-use std::time::Duration;
+use state::{SocketOption, State};
+use debugger::Debugger;
+use remote::Remote;
+use output::{Line, Output};
+use entropy::Entropy;
+use checkpoint::Checkpoints;
+use record::{Record, Recorder};
+use scenario::DnsError;
+use std::time::{Duration, Instant};
 
 lazy_static! {
     static ref STATE: Mutex<State> = Mutex::new(State::new());
     static ref SYNC: (Mutex<bool>, Condvar) = (Mutex::new(false), Condvar::new());
     static ref PENDING: AtomicBool= AtomicBool::new(false);
+    static ref DEBUGGER: Mutex<Option<Debugger>> = Mutex::new(Debugger::from_env());
+    /// Unix sockets are not simulated, but handed to the kernel (for example the debugger)
+    static ref KERNEL_FDS: Mutex<HashSet<c_int>> = Mutex::new(HashSet::new());
+    /// Forward all calls to a coordinator instead of simulating locally
+    static ref REMOTE: Option<Remote> = Remote::from_env();
+    /// Prefix the output of the application with the node, if enabled
+    static ref OUTPUT: Option<Mutex<Output>> = Output::from_env().map(|output| {
+        unsafe { libc::atexit(flush_output); }
+        Mutex::new(output)
+    });
+    /// Simulated time and node of the last delivered event
+    static ref RUNNING: Mutex<(u64, Option<SocketAddrV4>)> = Mutex::new((0, None));
+    /// Entropy of the nodes drawn from the seed, unless the host entropy is used
+    static ref ENTROPY: Option<Mutex<Entropy>> = Entropy::from_env(entropy_seed).map(Mutex::new);
+    /// Pass everything to the kernel and record the socket calls, if enabled
+    static ref RECORDER: Option<Recorder> = Recorder::from_env();
+    /// Simulated sockets which have to be released when they are closed
+    static ref SOCKETS: Mutex<BTreeSet<c_int>> = Mutex::new(BTreeSet::new());
+    /// If the metrics are served, by the coordinator instead when running remote
+    static ref METRICS: bool = REMOTE.is_none() && metrics::serve_from_env(|| STATE.lock().unwrap().metrics());
+}
+
+/// If a file descriptor is handled by the kernel, all of them are while recording
+fn is_kernel_fd(fd: c_int) -> bool {
+    RECORDER.is_some() || KERNEL_FDS.lock().unwrap().contains(&fd)
+}
+
+/// Record a socket call of a real run, if recording
+fn record<F: FnOnce() -> Record>(f: F) {
+    if let Some(ref recorder) = *RECORDER {
+        recorder.record(f());
+    }
+}
+
+unsafe fn to_addr(addr: *const sockaddr) -> SocketAddrV4 {
+    let addr = addr as *const sockaddr_in;
+
+    // convert host and port to SocketAddrV4
+    let port = (*addr).sin_port.to_be();
+    let ip = Ipv4Addr::from((*addr).sin_addr.s_addr.to_be());
+
+    SocketAddrV4::new(ip, port)
+}
+
+/// Address of a kernel socket, `None` for anything but IPv4
+unsafe fn kernel_addr(fd: c_int, getter: unsafe extern "C" fn(c_int, *mut sockaddr, *mut socklen_t) -> c_int) -> Option<SocketAddrV4> {
+    let mut addr = state::empty_addr();
+    let mut len = mem::size_of::<sockaddr_in>() as socklen_t;
+
+    if getter(fd, &mut addr as *mut sockaddr_in as *mut sockaddr, &mut len) < 0 || addr.sin_family != AF_INET as u16 {
+        return None;
+    }
+
+    Some(to_addr(&addr as *const sockaddr_in as *const sockaddr))
+}
+
+/// Wake up the epoll_wait thread after the state has changed
+///
+/// Never call this while holding `STATE`. Taking the `SYNC` lock ensures that the wakeup
+/// isn't lost between the state check of epoll_wait and its sleep.
+fn wake_epoll() {
+    let _guard = SYNC.0.lock().unwrap();
+    SYNC.1.notify_one();
 }
 
 hook! {
@@ -34,79 +130,407 @@ hook! {
 
 hook! {
     unsafe fn readv(fd: c_int, iov: *mut iovec, iovcnt: c_int) -> ssize_t => fake_readv {
-        if fd < 2 {
-            return real!(readv)(fd, iov, iovcnt);
+        if fd < 2 || is_kernel_fd(fd) {
+            let len = real!(readv)(fd, iov, iovcnt);
+
+            if len > 0 && fd >= 2 {
+                record(|| {
+                    let mut buf = Vec::with_capacity(len as usize);
+                    for iov in slice::from_raw_parts(iov, iovcnt as usize) {
+                        let part = cmp::min(iov.iov_len, len as usize - buf.len());
+                        buf.extend_from_slice(slice::from_raw_parts(iov.iov_base as *const u8, part));
+                    }
+
+                    Record::Recv(fd, buf)
+                });
+            }
+
+            return len;
         }
 
-        print!("R");
-        if let Some(arr) = STATE.lock().unwrap().recv_from(fd) {
-            (*iov).iov_len = arr.len();
-            let buf_ptr = arr.as_ptr();
+        match recv_packet(fd) {
+            Ok(arr) => {
+                // scatter the packet over the buffers, the rest of a too large one is lost
+                let mut len = 0;
+                for iov in slice::from_raw_parts(iov, iovcnt as usize) {
+                    let part = cmp::min(iov.iov_len, arr.len() - len);
+                    ptr::copy(arr[len..].as_ptr(), iov.iov_base as *mut u8, part);
+                    len += part;
+                }
 
-            ptr::copy(buf_ptr, (*iov).iov_base as *mut u8, arr.len());
+                len as ssize_t
+            },
+            Err(err) => {
+                set_errno(Errno(err));
 
-            arr.len() as ssize_t
-        } else {
-            //println!("invalid read in {}!", fd);
-            PENDING.store(false, Ordering::Relaxed);
-            SYNC.1.notify_one();
+                -1
+            }
+        }
+    }
+}
+
+/// Receive the next packet of a simulated connection
+///
+/// Once no packet is left or the stream ended, the delivered event was consumed and the next
+/// one may follow.
+fn recv_packet(fd: c_int) -> Result<Vec<u8>, c_int> {
+    let arr = match *REMOTE {
+        Some(ref remote) => remote.recv(fd),
+        None => STATE.lock().unwrap().recv_from(fd)
+    };
+
+    // nothing follows the end of the stream or an error either
+    if REMOTE.is_none() && arr.as_ref().map(|x| x.is_empty()).unwrap_or(true) {
+        PENDING.store(false, Ordering::Relaxed);
+        wake_epoll();
+    }
+
+    arr
+}
+
+// the standard library reads from sockets with `recv` instead of `read`
+hook! {
+    unsafe fn recv(fd: c_int, buf: *mut c_void, len: size_t, flags: c_int) -> ssize_t => fake_recv {
+        if is_kernel_fd(fd) {
+            let received = real!(recv)(fd, buf, len, flags);
+            if received > 0 {
+                record(|| Record::Recv(fd, slice::from_raw_parts(buf as *const u8, received as usize).into()));
+            }
 
-            set_errno(Errno(EWOULDBLOCK));
+            return received;
+        }
+
+        match recv_packet(fd) {
+            Ok(arr) => {
+                // packets are never split, the rest of a too large one is lost
+                let len = cmp::min(len, arr.len());
+                ptr::copy(arr.as_ptr(), buf as *mut u8, len);
+
+                len as ssize_t
+            },
+            Err(err) => {
+                set_errno(Errno(err));
 
-            -1
+                -1
+            }
         }
     }
 }
 
 hook! {
     unsafe fn send(fd: c_int, buf: *const c_void, len: size_t, _flags: c_int) -> ssize_t => fake_send {
-        print!("W");
+        if is_kernel_fd(fd) {
+            let sent = real!(send)(fd, buf, len, _flags);
+            if sent > 0 {
+                record(|| Record::Send(fd, slice::from_raw_parts(buf as *const u8, sent as usize).into()));
+            }
+
+            return sent;
+        }
+
         let buf = slice::from_raw_parts(buf as *const u8, len);
 
-        STATE.lock().unwrap().send_to(fd, buf);
+        let res = match *REMOTE {
+            Some(ref remote) => remote.send(fd, buf),
+            None => {
+                let res = STATE.lock().unwrap().send_to(fd, buf);
 
-        if !PENDING.load(Ordering::Relaxed) {
-            // wake up the epoll_wait thread
-            SYNC.1.notify_one();
+                if !PENDING.load(Ordering::Relaxed) {
+                    // wake up the epoll_wait thread
+                    wake_epoll();
+                }
+
+                res
+            }
+        };
+
+        match res {
+            Ok(()) => len as ssize_t,
+            Err(err) => {
+                set_errno(Errno(err));
+
+                -1
+            }
+        }
+    }
+}
+
+hook! {
+    unsafe fn write(fd: c_int, buf: *const c_void, count: size_t) -> ssize_t => fake_write {
+        let output = match *OUTPUT {
+            Some(ref output) if fd == 1 || fd == 2 => output,
+            _ => return real!(write)(fd, buf, count)
+        };
+
+        let buf = slice::from_raw_parts(buf as *const u8, count);
+        let (now, node) = *RUNNING.lock().unwrap();
+        let out = output.lock().unwrap().write(fd, buf, now, node);
+
+        match write_all(fd, &out) {
+            res if res < 0 => res,
+            _ => count as ssize_t
+        }
+    }
+}
+
+/// Pass prefixed output on to the kernel
+unsafe fn write_all(fd: c_int, out: &[u8]) -> ssize_t {
+    let mut written = 0;
+    while written < out.len() {
+        let res = real!(write)(fd, out[written..].as_ptr() as *const c_void, out.len() - written);
+        if res < 0 {
+            return res;
+        }
+
+        written += res as usize;
+    }
+
+    written as ssize_t
+}
+
+/// Write the partial lines of the application when the process exits
+extern "C" fn flush_output() {
+    if let Some(ref output) = *OUTPUT {
+        let (now, node) = *RUNNING.lock().unwrap();
+
+        for (fd, out) in output.lock().unwrap().flush(now, node) {
+            unsafe { write_all(fd, &out); }
+        }
+    }
+}
+
+/// Remember the node of a delivered event, returns the output lines which have to be stored
+/// in the trace
+fn set_running(now: u64, node: Option<SocketAddrV4>) -> Vec<Line> {
+    *RUNNING.lock().unwrap() = (now, node);
+
+    match *OUTPUT {
+        Some(ref output) => output.lock().unwrap().take_pending(),
+        None => Vec::new()
+    }
+}
+
+/// Seed of the scenario, taken from the coordinator or the checkpoint if there is one
+fn entropy_seed() -> u64 {
+    match *REMOTE {
+        Some(ref remote) => remote.seed(),
+        None => Checkpoints::from_env().scenario().map(|x| x.seed).unwrap_or(0)
+    }
+}
+
+/// Run a closure on the simulated entropy, `None` if the host entropy is used
+fn with_entropy<T, F: FnOnce(&mut Entropy) -> T>(f: F) -> Option<T> {
+    entropy::enter(|| ENTROPY.as_ref().map(|x| f(&mut x.lock().unwrap()))).and_then(|x| x)
+}
+
+/// Fill a buffer from the entropy stream of the running node, false if the host fills it
+unsafe fn fill_entropy(buf: *mut c_void, len: size_t) -> bool {
+    let node = RUNNING.lock().unwrap().1;
+    let buf = slice::from_raw_parts_mut(buf as *mut u8, len);
+
+    with_entropy(|x| x.fill(node, buf)).is_some()
+}
+
+hook! {
+    unsafe fn getrandom(buf: *mut c_void, buflen: size_t, flags: c_uint) -> ssize_t => fake_getrandom {
+        if fill_entropy(buf, buflen) {
+            return buflen as ssize_t;
+        }
+
+        real!(getrandom)(buf, buflen, flags)
+    }
+}
+
+// `syscall` is variadic, older versions of `rand` call `getrandom` through it. On x86_64 the
+// arguments are passed like fixed ones, so all six possible arguments are forwarded.
+hook! {
+    unsafe fn syscall(num: c_long, a1: c_long, a2: c_long, a3: c_long, a4: c_long, a5: c_long, a6: c_long) -> c_long => fake_syscall {
+        if num == SYS_getrandom && fill_entropy(a1 as *mut c_void, a2 as size_t) {
+            return a2;
+        }
+
+        real!(syscall)(num, a1, a2, a3, a4, a5, a6)
+    }
+}
+
+hook! {
+    unsafe fn open(path: *const c_char, flags: c_int, mode: mode_t) -> c_int => fake_open {
+        let fd = real!(open)(path, flags, mode);
+        if fd >= 0 && entropy::is_device(path) {
+            with_entropy(|x| x.open(fd));
+        }
+
+        fd
+    }
+}
+
+hook! {
+    unsafe fn open64(path: *const c_char, flags: c_int, mode: mode_t) -> c_int => fake_open64 {
+        let fd = real!(open64)(path, flags, mode);
+        if fd >= 0 && entropy::is_device(path) {
+            with_entropy(|x| x.open(fd));
+        }
+
+        fd
+    }
+}
+
+hook! {
+    unsafe fn read(fd: c_int, buf: *mut c_void, count: size_t) -> ssize_t => fake_read {
+        if with_entropy(|x| x.is_open(fd)) == Some(true) && fill_entropy(buf, count) {
+            return count as ssize_t;
+        }
+
+        real!(read)(fd, buf, count)
+    }
+}
+
+hook! {
+    unsafe fn close(fd: c_int) -> c_int => fake_close {
+        with_entropy(|x| x.close(fd));
+
+        // the number may come back for a simulated socket
+        KERNEL_FDS.lock().unwrap().remove(&fd);
+
+        if SOCKETS.lock().unwrap().remove(&fd) {
+            match *REMOTE {
+                Some(ref remote) => remote.close(fd),
+                None => {
+                    STATE.lock().unwrap().close(fd);
+                    wake_epoll();
+                }
+            }
         }
 
-        len as ssize_t
+        real!(close)(fd)
     }
 }
 
 hook! {
     unsafe fn bind(ssocket: c_int, address: *const sockaddr, _address_len: socklen_t) -> c_int => fake_bind {
-        if (*address).sa_family == AF_INET as u16 {
-            let addr = address as *const sockaddr_in;
+        if (*address).sa_family == AF_UNIX as u16 {
+            KERNEL_FDS.lock().unwrap().insert(ssocket);
 
-            // convert host and port to SocketAddrV4
-            let port = (*addr).sin_port.to_be();
-            let addr = Ipv4Addr::from(((*addr).sin_addr.s_addr as u32).to_be());
+            return real!(bind)(ssocket, address, _address_len);
+        }
 
-            STATE.lock().unwrap().add_node(ssocket, SocketAddrV4::new(addr, port));
-        } else {
+        if RECORDER.is_some() {
+            let res = real!(bind)(ssocket, address, _address_len);
+            if res == 0 {
+                if let Some(addr) = kernel_addr(ssocket, real!(getsockname)) {
+                    record(|| Record::Bind(ssocket, addr));
+                }
+            }
+
+            return res;
+        }
+
+        if (*address).sa_family != AF_INET as u16 {
             panic!("We're only supporting the IPv4 address space");
         }
 
+        let addr = to_addr(address);
+        let res = match *REMOTE {
+            Some(ref remote) => remote.bind(ssocket, addr),
+            None => STATE.lock().unwrap().bind(ssocket, addr)
+        };
+
+        match res {
+            Ok(_) => {
+                SOCKETS.lock().unwrap().insert(ssocket);
+
+                0
+            },
+            Err(err) => {
+                set_errno(Errno(err));
+
+                -1
+            }
+        }
+    }
+}
+
+hook! {
+    unsafe fn listen(fd: c_int, backlog: c_int) -> c_int => fake_listen {
+        if is_kernel_fd(fd) {
+            return real!(listen)(fd, backlog);
+        }
+
+        match *REMOTE {
+            Some(ref remote) => remote.listen(fd, backlog),
+            None => STATE.lock().unwrap().listen(fd, backlog)
+        }
+
         0
     }
 }
 
+hook! {
+    unsafe fn shutdown(fd: c_int, how: c_int) -> c_int => fake_shutdown {
+        if is_kernel_fd(fd) {
+            return real!(shutdown)(fd, how);
+        }
+
+        // only the sending side is simulated, the receiving side keeps getting packets
+        if how == SHUT_RD {
+            return 0;
+        }
+
+        let res = match *REMOTE {
+            Some(ref remote) => remote.shutdown(fd),
+            None => {
+                let res = STATE.lock().unwrap().shutdown(fd);
+                wake_epoll();
+
+                res
+            }
+        };
+
+        match res {
+            Ok(()) => 0,
+            Err(err) => {
+                set_errno(Errno(err));
+
+                -1
+            }
+        }
+    }
+}
+
 hook! {
     unsafe fn connect(ssocket: c_int, address: *const sockaddr, _address_len: socklen_t) -> c_int => fake_connect {
-        print!("C");
+        if (*address).sa_family == AF_UNIX as u16 {
+            KERNEL_FDS.lock().unwrap().insert(ssocket);
+
+            return real!(connect)(ssocket, address, _address_len);
+        }
+
+        if RECORDER.is_some() {
+            let res = real!(connect)(ssocket, address, _address_len);
+            if (res == 0 || errno().0 == EINPROGRESS) && (*address).sa_family == AF_INET as u16 {
+                if let Some(local) = kernel_addr(ssocket, real!(getsockname)) {
+                    record(|| Record::Connect(ssocket, local, to_addr(address)));
+                }
+            }
+
+            return res;
+        }
+
         if (*address).sa_family == AF_INET as u16 {
-            let addr = address as *const sockaddr_in;
+            let addr = to_addr(address);
+            SOCKETS.lock().unwrap().insert(ssocket);
 
-            // convert host and port to SocketAddrV4
-            let port = (*addr).sin_port.to_be();
-            let addr = Ipv4Addr::from(((*addr).sin_addr.s_addr as u32).to_be());
+            if let Some(ref remote) = *REMOTE {
+                remote.connect(ssocket, addr);
 
-            STATE.lock().unwrap().connect_to_node(ssocket, SocketAddrV4::new(addr, port));
+                return 0;
+            }
+
+            STATE.lock().unwrap().connect_to_node(ssocket, addr);
 
             if !PENDING.load(Ordering::Relaxed) {
                 // wake up the epoll_wait thread
-                SYNC.1.notify_one();
+                wake_epoll();
             }
         } else {
             panic!("We're only supporting the IPv4 address space");
@@ -118,14 +542,50 @@ hook! {
 
 hook! {
     unsafe fn accept4(ssocket: c_int, address: *mut sockaddr, address_len: *mut socklen_t, flg: c_int) -> c_int => fake_accept {
-        //real!(accept4)(ssocket, address, address_len, flg);
-        //
+        if is_kernel_fd(ssocket) {
+            let fd = real!(accept4)(ssocket, address, address_len, flg);
+            if fd >= 0 {
+                KERNEL_FDS.lock().unwrap().insert(fd);
+
+                if let Some(peer) = kernel_addr(fd, real!(getpeername)) {
+                    record(|| Record::Accept(ssocket, fd, peer));
+                }
+            }
+
+            return fd;
+        }
+
+        if let Some(ref remote) = *REMOTE {
+            // the coordinator only knows about connections of this process by their fd
+            let fd = socket(AF_INET, SOCK_STREAM | SOCK_CLOEXEC, 0);
+            if remote.accept(ssocket, fd) {
+                SOCKETS.lock().unwrap().insert(fd);
+                let peer = remote.peername(fd).map(state::to_sockaddr).unwrap_or_else(state::empty_addr);
+
+                (*address_len) = 16;
+                ptr::write(address as *mut sockaddr_in, peer);
+
+                return fd;
+            }
+
+            real!(close)(fd);
+            set_errno(Errno(EAGAIN));
+
+            return -1;
+        }
 
         let mut ret_fd = 0;
-        if let Some(fd) = STATE.lock().unwrap().accept(ssocket) {
-            println!("accept");
+        let (fd, peer) = {
+            let mut state = STATE.lock().unwrap();
+            let fd = state.accept(ssocket, || socket(AF_INET, SOCK_STREAM | SOCK_CLOEXEC, 0));
+
+            (fd, fd.and_then(|x| state.get_peername(x)))
+        };
+
+        if let Some(fd) = fd {
+            SOCKETS.lock().unwrap().insert(fd);
             (*address_len) = 16;
-            let addr = state::empty_addr();
+            let addr = peer.map(state::to_sockaddr).unwrap_or_else(state::empty_addr);
 
             ptr::write(address as *mut sockaddr_in, addr);
             //
@@ -133,8 +593,6 @@ hook! {
 
             ret_fd = fd;
         } else {
-            println!("invalid accept");
-
             set_errno(Errno(EAGAIN));
 
             ret_fd = -1
@@ -146,7 +604,7 @@ hook! {
             PENDING.store(false, Ordering::Relaxed);
         }
 
-        SYNC.1.notify_one();
+        wake_epoll();
 
         ret_fd
     }
@@ -155,7 +613,14 @@ hook! {
 
 hook! {
     unsafe fn getsockname(fd: c_int, address: *mut sockaddr, address_len: *mut socklen_t) -> c_int => fake_getsockname {
-        let addr = STATE.lock().unwrap().get_sockname(fd);
+        if is_kernel_fd(fd) {
+            return real!(getsockname)(fd, address, address_len);
+        }
+
+        let addr = match *REMOTE {
+            Some(ref remote) => remote.sockname(fd),
+            None => STATE.lock().unwrap().get_sockname(fd)
+        };
 
         *address_len = 16;
         let addr = state::to_sockaddr(addr);
@@ -167,14 +632,153 @@ hook! {
 
 hook! {
     unsafe fn getpeername(_fd: c_int, address: *mut sockaddr, address_len: *mut socklen_t) -> c_int => fake_getpeername {
+        if is_kernel_fd(_fd) {
+            return real!(getpeername)(_fd, address, address_len);
+        }
+
+        let peer = match *REMOTE {
+            Some(ref remote) => remote.peername(_fd),
+            None => STATE.lock().unwrap().get_peername(_fd)
+        };
+
         *address_len = 16;
-        let addr = state::empty_addr();
+        let addr = peer.map(state::to_sockaddr).unwrap_or_else(state::empty_addr);
 
         ptr::write(address as *mut sockaddr_in, addr);
         
         0
     }
 }
+
+hook! {
+    unsafe fn setsockopt(fd: c_int, level: c_int, name: c_int, value: *const c_void, len: socklen_t) -> c_int => fake_setsockopt {
+        if is_kernel_fd(fd) || len < 4 {
+            return real!(setsockopt)(fd, level, name, value, len);
+        }
+
+        let int = *(value as *const c_int);
+        let option = match (level, name) {
+            (SOL_SOCKET, SO_REUSEADDR) => SocketOption::ReuseAddr(int != 0),
+            (SOL_SOCKET, SO_REUSEPORT) => SocketOption::ReusePort(int != 0),
+            (SOL_SOCKET, SO_LINGER) if len as usize >= mem::size_of::<linger>() => {
+                let linger = *(value as *const linger);
+                SocketOption::Linger(if linger.l_onoff != 0 { Some(linger.l_linger) } else { None })
+            },
+            (SOL_SOCKET, SO_KEEPALIVE) => SocketOption::KeepAlive(int != 0),
+            (SOL_SOCKET, SO_SNDBUF) => SocketOption::SendBuffer(int),
+            (SOL_SOCKET, SO_RCVBUF) => SocketOption::RecvBuffer(int),
+            (IPPROTO_TCP, TCP_NODELAY) => SocketOption::NoDelay(int != 0),
+            (IPPROTO_TCP, TCP_KEEPIDLE) => SocketOption::KeepIdle(int),
+            (IPPROTO_TCP, TCP_KEEPINTVL) => SocketOption::KeepInterval(int),
+            (IPPROTO_TCP, TCP_KEEPCNT) => SocketOption::KeepCount(int),
+            _ => return real!(setsockopt)(fd, level, name, value, len)
+        };
+
+        match *REMOTE {
+            Some(ref remote) => remote.set_option(fd, option),
+            None => STATE.lock().unwrap().set_option(fd, option)
+        }
+
+        0
+    }
+}
+
+hook! {
+    unsafe fn getsockopt(fd: c_int, level: c_int, name: c_int, value: *mut c_void, len: *mut socklen_t) -> c_int => fake_getsockopt {
+        if is_kernel_fd(fd) || *len < 4 {
+            return real!(getsockopt)(fd, level, name, value, len);
+        }
+
+        let option = if level == SOL_SOCKET && name == SO_ERROR {
+            // refused connects are reported here
+            let err = match *REMOTE {
+                Some(ref remote) => remote.take_error(fd),
+                None => STATE.lock().unwrap().take_error(fd)
+            };
+
+            err.unwrap_or(0)
+        } else {
+            let option = match *REMOTE {
+                Some(ref remote) => remote.get_option(fd, level, name),
+                None => STATE.lock().unwrap().get_option(fd, level, name)
+            };
+
+            match option {
+                Some(option) => option,
+                None => return real!(getsockopt)(fd, level, name, value, len)
+            }
+        };
+
+        *len = 4;
+        ptr::write(value as *mut c_int, option);
+
+        0
+    }
+}
+/// Resolve a name through the simulated DNS, `None` if it isn't simulated
+fn resolve(name: &str) -> Option<Result<Vec<Ipv4Addr>, DnsError>> {
+    if RECORDER.is_some() {
+        return None;
+    }
+
+    match *REMOTE {
+        Some(ref remote) => remote.resolve(name),
+        None => STATE.lock().unwrap().resolve(name)
+    }
+}
+
+hook! {
+    unsafe fn getaddrinfo(node: *const c_char, service: *const c_char, hints: *const addrinfo, res: *mut *mut addrinfo) -> c_int => fake_getaddrinfo {
+        let name = match dns::simulated_name(node) {
+            Some(name) => name,
+            None => return real!(getaddrinfo)(node, service, hints, res)
+        };
+
+        match resolve(&name) {
+            Some(Ok(addrs)) => {
+                let port = match dns::port(service) {
+                    Some(port) => port,
+                    None => return EAI_SERVICE
+                };
+
+                // all simulated nodes live in the IPv4 address space
+                if !hints.is_null() && (*hints).ai_family != AF_UNSPEC && (*hints).ai_family != AF_INET {
+                    return EAI_FAMILY;
+                }
+
+                *res = dns::addrinfo_list(&name, &addrs, port, hints);
+
+                0
+            },
+            Some(Err(DnsError::Nxdomain)) => EAI_NONAME,
+            Some(Err(DnsError::Servfail)) => EAI_AGAIN,
+            None => real!(getaddrinfo)(node, service, hints, res)
+        }
+    }
+}
+
+hook! {
+    unsafe fn gethostbyname(node: *const c_char) -> *mut hostent => fake_gethostbyname {
+        let name = match dns::simulated_name(node) {
+            Some(name) => name,
+            None => return real!(gethostbyname)(node)
+        };
+
+        match resolve(&name) {
+            Some(Ok(addrs)) => dns::host_entry(&name, &addrs),
+            Some(Err(err)) => {
+                dns::set_h_errno(match err {
+                    DnsError::Nxdomain => dns::HOST_NOT_FOUND,
+                    DnsError::Servfail => dns::TRY_AGAIN
+                });
+
+                ptr::null_mut()
+            },
+            None => real!(gethostbyname)(node)
+        }
+    }
+}
+
 fn events_to_string(events: c_uint) -> String {
     [
         ("EPOLLIN", EPOLLIN), 
@@ -198,16 +802,25 @@ fn events_to_string(events: c_uint) -> String {
 
 hook! {
     unsafe fn epoll_ctl(epfd: c_int, op: c_int, fd: c_int, event: *mut epoll_event) -> c_int => fake_epoll_ctl {
-        print!("E");
+        if RECORDER.is_some() {
+            return real!(epoll_ctl)(epfd, op, fd, event);
+        }
+
         if op == EPOLL_CTL_ADD {
             let events = (*event).events;
 
             //println!("Hook: register {} with id {}", fd, (*event).u64);
 
-            // wake up the epoll_wait thread
-            SYNC.1.notify_one();
+            if let Some(ref remote) = *REMOTE {
+                remote.epoll_ctl(fd, events, (*event).u64);
+
+                return 0;
+            }
 
             STATE.lock().unwrap().add_epoll_fd(fd, events, (*event).u64);
+
+            // wake up the epoll_wait thread
+            wake_epoll();
         }
 
         0
@@ -215,60 +828,79 @@ hook! {
 }
 
 hook! {
-    unsafe fn epoll_wait(_epfd: c_int, events: *mut epoll_event, _maxevents: c_int, _timeout: c_int) -> c_int => fake_epoll_wait {
-        let mut started = SYNC.0.lock().unwrap();
-        loop {
-            /*if let Some((fd_id, fd_events)) = STATE.lock().unwrap().next_epoll_notify() {
-                //println!(" ===> Notify id {}", fd_id);
-                let answ = epoll_event { 
-                    events: fd_events as u32, 
-                    u64: fd_id
-                };
+    unsafe fn epoll_wait(_epfd: c_int, events: *mut epoll_event, _maxevents: c_int, timeout: c_int) -> c_int => fake_epoll_wait {
+        if RECORDER.is_some() {
+            return real!(epoll_wait)(_epfd, events, _maxevents, timeout);
+        }
 
-                ptr::write(events, answ);
+        if let Some(ref remote) = *REMOTE {
+            let (next, now, node) = remote.epoll_wait(timeout);
 
-                return 1;
-            }*/
-
-            loop {
-                loop {
-                    let lock = STATE.try_lock();
-                    
-                    let mut val = match lock {
-                        Ok(val) => val,
-                        Err(_) => {
-                            println!("Would block");
-                            continue
-                        }
-                    };
+            return match next {
+                Some((fd_id, fd_events)) => {
+                    let lines = set_running(now, node);
+                    if !lines.is_empty() {
+                        remote.output(lines);
+                    }
 
-                    if let Some((fd_id, fd_events)) = val.next_epoll_notify() {
-                        println!(" ===> Notify2 id {}", fd_id);
+                    ptr::write(events, epoll_event { events: fd_events as u32, u64: fd_id });
 
-                        let answ = epoll_event { 
-                            events: fd_events as u32, 
-                            u64: fd_id
-                        };
+                    1
+                },
+                None => 0
+            };
+        }
 
-                        ptr::write(events, answ);
+        lazy_static::initialize(&METRICS);
 
-                        return 1;
-                    }
+        let deadline = if timeout >= 0 {
+            Some(clock::real(Instant::now) + Duration::from_millis(timeout as u64))
+        } else {
+            None
+        };
 
-                    break;
-                }
+        let mut started = SYNC.0.lock().unwrap();
+        loop {
+            let (next, idle, release) = {
+                let mut state = STATE.lock().unwrap();
+
+                // first notify finished operations, then deliver the next event as soon as
+                // the previous one was consumed
+                let next = match state.next_epoll_notify() {
+                    Some(notify) => Some(notify),
+                    None if !PENDING.load(Ordering::Relaxed) => {
+                        let next_id = state.next_epoll_id();
+
+                        if next_id.is_some() {
+                            // give the debugger a chance to stop before the event is delivered
+                            if let Some(ref mut debugger) = *DEBUGGER.lock().unwrap() {
+                                debugger.pause(&state);
+                            }
+
+                            // only data and connects wait for the application to consume them
+                            if !state.take_hang_up() {
+                                PENDING.store(true, Ordering::Relaxed);
+                            }
+                        }
 
-                if PENDING.load(Ordering::Relaxed) {
-                    started = SYNC.1.wait(started).unwrap();
-                } else {
-                    break;
+                        next_id
+                    },
+                    None => None
+                };
+
+                if next.is_some() {
+                    let lines = set_running(state.time(), state.current());
+                    state.log_output(lines);
                 }
-            }
 
-            let next_id = STATE.lock().unwrap().next_epoll_id();
-            if let Some((fd_id, fd_events)) = next_id {
-                PENDING.store(true, Ordering::Relaxed);
+                // with simulated clocks the timeout passes in simulated time
+                let idle = next.is_none() && timeout >= 0 && !PENDING.load(Ordering::Relaxed)
+                    && state.idle(timeout as u64 * 1000);
 
+                (next, idle, state.next_release())
+            };
+
+            if let Some((fd_id, fd_events)) = next {
                 let answ = epoll_event { 
                     events: fd_events as u32, 
                     u64: fd_id
@@ -279,6 +911,89 @@ hook! {
                 return 1;
             }
 
+            if idle {
+                return 0;
+            }
+
+            // nothing to deliver, sleep till another hook changes the state or a paced event
+            // is released
+            let now = clock::real(Instant::now);
+            if deadline.map(|x| now >= x).unwrap_or(false) {
+                return 0;
+            }
+
+            let wake = match (deadline, release) {
+                (Some(a), Some(b)) => Some(cmp::min(a, b)),
+                (a, b) => a.or(b)
+            };
+
+            started = clock::real(|| match wake {
+                Some(wake) if wake > now => SYNC.1.wait_timeout(started, wake - now).unwrap().0,
+                Some(_) => started,
+                None => SYNC.1.wait(started).unwrap()
+            });
+        }
+    }
+}
+
+/// Clock of the running node in microseconds, `None` if the host clock is used
+fn node_clock(kind: clock::Kind) -> Option<u64> {
+    if clock::is_real() || RECORDER.is_some() {
+        return None;
+    }
+
+    match *REMOTE {
+        Some(ref remote) => remote.clock(kind),
+        None => STATE.lock().unwrap().clock(kind)
+    }
+}
+
+hook! {
+    unsafe fn clock_gettime(clk: clockid_t, tp: *mut timespec) -> c_int => fake_clock_gettime {
+        let kind = match clk {
+            CLOCK_REALTIME | CLOCK_REALTIME_COARSE => clock::Kind::Realtime,
+            CLOCK_MONOTONIC | CLOCK_MONOTONIC_RAW | CLOCK_MONOTONIC_COARSE | CLOCK_BOOTTIME => clock::Kind::Monotonic,
+            _ => return real!(clock_gettime)(clk, tp)
+        };
+
+        match node_clock(kind) {
+            Some(micros) => {
+                (*tp).tv_sec = (micros / 1_000_000) as time_t;
+                (*tp).tv_nsec = (micros % 1_000_000 * 1000) as c_long;
+
+                0
+            },
+            None => real!(clock_gettime)(clk, tp)
+        }
+    }
+}
+
+hook! {
+    unsafe fn gettimeofday(tv: *mut timeval, tz: *mut c_void) -> c_int => fake_gettimeofday {
+        match node_clock(clock::Kind::Realtime) {
+            Some(micros) if !tv.is_null() => {
+                (*tv).tv_sec = (micros / 1_000_000) as time_t;
+                (*tv).tv_usec = (micros % 1_000_000) as suseconds_t;
+
+                0
+            },
+            _ => real!(gettimeofday)(tv, tz)
+        }
+    }
+}
+
+hook! {
+    unsafe fn time(t: *mut time_t) -> time_t => fake_time {
+        match node_clock(clock::Kind::Realtime) {
+            Some(micros) => {
+                let secs = (micros / 1_000_000) as time_t;
+                if !t.is_null() {
+                    *t = secs;
+                }
+
+                secs
+            },
+            None => real!(time)(t)
         }
     }
 }
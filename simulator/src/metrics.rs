@@ -0,0 +1,131 @@
+use std::env;
+use std::fs;
+use std::thread;
+use std::fmt::Write as FmtWrite;
+use std::io::{BufRead, BufReader, Write};
+use std::collections::BTreeMap;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::time::Instant;
+
+use clock;
+use state::Addr;
+
+/// Environment variable with the socket path of the metrics endpoint
+pub const METRICS_ENV: &str = "PEERSIM_METRICS";
+
+/// Traffic of a single node
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct Counters {
+    pub sent_messages: u64,
+    pub sent_bytes: u64,
+    pub received_messages: u64,
+    pub received_bytes: u64
+}
+
+/// Progress of a simulation at a single moment
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Metrics {
+    pub time: u64,
+    /// Events waiting in the queue
+    pub queued: usize,
+    /// Events delivered so far
+    pub delivered: u64,
+    pub nodes: BTreeMap<Addr, Counters>,
+    /// Open connections of every node
+    pub connections: BTreeMap<Addr, usize>
+}
+
+fn metric(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP peersim_{} {}", name, help).unwrap();
+    writeln!(out, "# TYPE peersim_{} {}", name, kind).unwrap();
+}
+
+impl Metrics {
+    /// The metrics in the Prometheus text format, with the delivered events per second of
+    /// wall clock time
+    pub fn render(&self, rate: f64) -> String {
+        let mut out = String::new();
+
+        metric(&mut out, "time_microseconds", "gauge", "Simulated time");
+        writeln!(out, "peersim_time_microseconds {}", self.time).unwrap();
+        metric(&mut out, "queue_depth", "gauge", "Events waiting for their delivery");
+        writeln!(out, "peersim_queue_depth {}", self.queued).unwrap();
+        metric(&mut out, "events_total", "counter", "Events delivered so far");
+        writeln!(out, "peersim_events_total {}", self.delivered).unwrap();
+        metric(&mut out, "events_per_second", "gauge", "Events delivered per second of wall clock time since the last scrape");
+        writeln!(out, "peersim_events_per_second {:.1}", rate).unwrap();
+
+        self.counter(&mut out, "sent_messages_total", "Writes of a node", |x| x.sent_messages);
+        self.counter(&mut out, "sent_bytes_total", "Bytes written by a node", |x| x.sent_bytes);
+        self.counter(&mut out, "received_messages_total", "Packets read by a node", |x| x.received_messages);
+        self.counter(&mut out, "received_bytes_total", "Bytes read by a node", |x| x.received_bytes);
+
+        metric(&mut out, "node_connections", "gauge", "Open connections of a node");
+        for (node, connections) in &self.connections {
+            writeln!(out, "peersim_node_connections{{node=\"{}\"}} {}", node, connections).unwrap();
+        }
+
+        out
+    }
+
+    /// A counter of every node
+    fn counter(&self, out: &mut String, name: &str, help: &str, value: fn(&Counters) -> u64) {
+        metric(out, &format!("node_{}", name), "counter", help);
+
+        for (node, counters) in &self.nodes {
+            writeln!(out, "peersim_node_{}{{node=\"{}\"}} {}", name, node, value(counters)).unwrap();
+        }
+    }
+}
+
+/// Answer a single request with the current metrics
+fn answer(stream: UnixStream, body: &str) {
+    let mut reader = BufReader::new(&stream);
+
+    // the request itself doesn't matter, every path gets the metrics
+    let mut line = String::new();
+    while reader.read_line(&mut line).map(|n| n > 0).unwrap_or(false) && line.trim() != "" {
+        line.clear();
+    }
+
+    let _ = write!(&stream, "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body);
+}
+
+/// Serve the metrics of a running simulation over HTTP on a Unix socket
+///
+/// Set `PEERSIM_METRICS=<socket>` and scrape it, for example with
+/// `curl --unix-socket <socket> http://localhost/metrics`. `collect` is called for every
+/// request from a thread of its own. Returns if the endpoint was started.
+pub fn serve_from_env<F: Fn() -> Metrics + Send + 'static>(collect: F) -> bool {
+    let path = match env::var(METRICS_ENV) {
+        Ok(path) => path,
+        Err(_) => return false
+    };
+
+    let _ = fs::remove_file(&path);
+    let listener = UnixListener::bind(&path)
+        .unwrap_or_else(|err| panic!("Could not open metrics socket {}: {}", path, err));
+
+    println!("Metrics: serving on {}", path);
+
+    thread::spawn(move || {
+        let mut last = (clock::real(Instant::now), 0);
+
+        for stream in listener.incoming().filter_map(|x| x.ok()) {
+            let metrics = collect();
+
+            let now = clock::real(Instant::now);
+            let elapsed = now.duration_since(last.0).as_secs_f64();
+            let rate = if elapsed > 0.0 {
+                metrics.delivered.saturating_sub(last.1) as f64 / elapsed
+            } else {
+                0.0
+            };
+            last = (now, metrics.delivered);
+
+            answer(stream, &metrics.render(rate));
+        }
+    });
+
+    true
+}
@@ -0,0 +1,79 @@
+use std::collections::{HashMap, HashSet};
+use std::net::Ipv4Addr;
+
+use scenario::{self, NatKind};
+
+use state::Addr;
+
+/// First public port handed out by a NAT box
+const FIRST_PORT: u32 = 49152;
+
+/// Address mappings of all NAT boxes in the scenario
+#[derive(Clone, Default)]
+pub struct Nat {
+    /// Public address of a node, for symmetric NATs one per destination
+    mappings: HashMap<(Addr, Option<Addr>), Addr>,
+    /// Node behind a public address and the addresses it connected to
+    by_public: HashMap<Addr, (Addr, HashSet<Ipv4Addr>)>,
+    next_port: HashMap<Ipv4Addr, u32>
+}
+
+impl Nat {
+    /// Public address of a connection from `node` behind `nat` to `dest`
+    pub fn outbound(&mut self, nat: &scenario::Nat, node: Addr, dest: Addr) -> Addr {
+        let key = match nat.kind {
+            NatKind::Symmetric => (node, Some(dest)),
+            _ => (node, None)
+        };
+
+        let public = match self.mappings.get(&key) {
+            Some(public) => *public,
+            None => {
+                let port = self.next_port.entry(nat.public).or_insert(FIRST_PORT);
+                if *port > u32::from(u16::MAX) {
+                    panic!("NAT {} ran out of ports", nat.public);
+                }
+
+                let public = Addr::new(nat.public, *port as u16);
+                *port += 1;
+
+                self.mappings.insert(key, public);
+                self.by_public.insert(public, (node, HashSet::new()));
+
+                public
+            }
+        };
+
+        self.by_public.get_mut(&public).unwrap().1.insert(*dest.ip());
+
+        public
+    }
+
+    /// All mappings as node, destination, public address and the hosts contacted through it,
+    /// sorted for snapshots
+    pub fn mappings(&self) -> Vec<(Addr, Option<Addr>, Addr, Vec<Ipv4Addr>)> {
+        let mut mappings = self.mappings.iter()
+            .map(|(&(node, dest), &public)| {
+                let mut contacted = self.by_public[&public].1.iter().cloned().collect::<Vec<_>>();
+                contacted.sort();
+
+                (node, dest, public, contacted)
+            })
+            .collect::<Vec<_>>();
+
+        mappings.sort();
+        mappings
+    }
+
+    /// Node receiving a connection from `from` to a public address, `None` if it is dropped
+    pub fn inbound(&self, nat: &scenario::Nat, from: Option<Addr>, public: Addr) -> Option<Addr> {
+        let (node, contacted) = self.by_public.get(&public)?;
+
+        match nat.kind {
+            NatKind::FullCone => Some(*node),
+            NatKind::Restricted | NatKind::Symmetric => from
+                .filter(|from| contacted.contains(from.ip()))
+                .map(|_| *node)
+        }
+    }
+}
@@ -0,0 +1,86 @@
+use std::cmp;
+
+use libc::{c_int, SOL_SOCKET, IPPROTO_TCP, SO_KEEPALIVE, SO_SNDBUF, SO_RCVBUF, TCP_NODELAY, TCP_KEEPIDLE,
+    TCP_KEEPINTVL, TCP_KEEPCNT};
+
+use state::SocketOption;
+
+/// Largest segment, smaller writes are held back by Nagle's algorithm
+pub const MSS: usize = 1460;
+
+/// Buffer sizes of a new socket, the defaults of `tcp_wmem` and `tcp_rmem`
+const SNDBUF: c_int = 16384;
+const RCVBUF: c_int = 131072;
+
+/// Smallest buffer sizes Linux accepts, after doubling
+const MIN_SNDBUF: c_int = 4608;
+const MIN_RCVBUF: c_int = 2304;
+
+/// Options of a socket which change the timing of the simulated network
+///
+/// Like Linux, buffer sizes are doubled when they are set and the doubled value is reported
+/// back. Keepalive times are in seconds.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct Options {
+    pub nodelay: bool,
+    pub keepalive: bool,
+    pub keep_idle: c_int,
+    pub keep_interval: c_int,
+    pub keep_count: c_int,
+    pub sndbuf: c_int,
+    pub rcvbuf: c_int
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            nodelay: false,
+            keepalive: false,
+            keep_idle: 7200,
+            keep_interval: 75,
+            keep_count: 9,
+            sndbuf: SNDBUF,
+            rcvbuf: RCVBUF
+        }
+    }
+}
+
+impl Options {
+    pub fn set(&mut self, option: SocketOption) {
+        match option {
+            SocketOption::NoDelay(x) => self.nodelay = x,
+            SocketOption::KeepAlive(x) => self.keepalive = x,
+            SocketOption::KeepIdle(x) => self.keep_idle = cmp::max(x, 1),
+            SocketOption::KeepInterval(x) => self.keep_interval = cmp::max(x, 1),
+            SocketOption::KeepCount(x) => self.keep_count = cmp::max(x, 1),
+            SocketOption::SendBuffer(x) => self.sndbuf = cmp::max(x.saturating_mul(2), MIN_SNDBUF),
+            SocketOption::RecvBuffer(x) => self.rcvbuf = cmp::max(x.saturating_mul(2), MIN_RCVBUF),
+            _ => {}
+        }
+    }
+
+    /// Value of an option for `getsockopt`, `None` if it isn't simulated
+    pub fn get(&self, level: c_int, name: c_int) -> Option<c_int> {
+        let value = match (level, name) {
+            (SOL_SOCKET, SO_KEEPALIVE) => self.keepalive as c_int,
+            (SOL_SOCKET, SO_SNDBUF) => self.sndbuf,
+            (SOL_SOCKET, SO_RCVBUF) => self.rcvbuf,
+            (IPPROTO_TCP, TCP_NODELAY) => self.nodelay as c_int,
+            (IPPROTO_TCP, TCP_KEEPIDLE) => self.keep_idle,
+            (IPPROTO_TCP, TCP_KEEPINTVL) => self.keep_interval,
+            (IPPROTO_TCP, TCP_KEEPCNT) => self.keep_count,
+            _ => return None
+        };
+
+        Some(value)
+    }
+
+    /// Time between the last traffic of a connection and its first keepalive probe
+    pub fn idle_time(&self) -> u64 {
+        self.keep_idle as u64 * 1_000_000
+    }
+
+    pub fn probe_interval(&self) -> u64 {
+        self.keep_interval as u64 * 1_000_000
+    }
+}
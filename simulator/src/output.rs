@@ -0,0 +1,93 @@
+use std::env;
+use std::collections::HashMap;
+
+use libc::c_int;
+
+use state::Addr;
+
+/// A line written by the application, with the simulated time and the node it belongs to
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Line {
+    pub time: u64,
+    pub node: Option<Addr>,
+    pub fd: c_int,
+    pub text: String
+}
+
+/// Attributes the output of the application on stdout and stderr to the nodes
+///
+/// Configured with `PEERSIM_OUTPUT=prefix`, every line is prefixed with the simulated time
+/// and the node whose event was delivered last. `PEERSIM_OUTPUT=trace` additionally stores
+/// the lines in the trace. Partial lines are held back until they are complete or the
+/// process exits. Only writes through the `write` symbol are seen, `printf` of the C library
+/// bypasses it.
+pub struct Output {
+    trace: bool,
+    partial: HashMap<c_int, Vec<u8>>,
+    pending: Vec<Line>
+}
+
+impl Output {
+    /// Never prints, since it is created within the `write` hook
+    pub fn from_env() -> Option<Output> {
+        let trace = match env::var("PEERSIM_OUTPUT").ok()?.as_str() {
+            "prefix" => false,
+            "trace" => true,
+            _ => return None
+        };
+
+        Some(Output::new(trace))
+    }
+
+    pub fn new(trace: bool) -> Output {
+        Output {
+            trace,
+            partial: HashMap::new(),
+            pending: Vec::new()
+        }
+    }
+
+    /// Prefix the complete lines of a write of a node, returns the bytes to pass on
+    pub fn write(&mut self, fd: c_int, buf: &[u8], time: u64, node: Option<Addr>) -> Vec<u8> {
+        let mut out = Vec::new();
+        let partial = self.partial.entry(fd).or_default();
+        partial.extend_from_slice(buf);
+
+        while let Some(pos) = partial.iter().position(|x| *x == b'\n') {
+            let line = partial.drain(..pos + 1).collect::<Vec<u8>>();
+            let text = String::from_utf8_lossy(&line[..pos]).into_owned();
+
+            out.extend_from_slice(prefix(time, node).as_bytes());
+            out.extend_from_slice(&line);
+
+            if self.trace {
+                self.pending.push(Line { time, node, fd, text });
+            }
+        }
+
+        out
+    }
+
+    /// Complete the partial lines when the process exits, returns the bytes to pass on for
+    /// every file descriptor
+    pub fn flush(&mut self, time: u64, node: Option<Addr>) -> Vec<(c_int, Vec<u8>)> {
+        let mut fds = self.partial.keys().cloned().collect::<Vec<_>>();
+        fds.sort();
+
+        fds.into_iter().filter_map(|fd| {
+            let partial = self.partial.remove(&fd).filter(|x| !x.is_empty())?;
+            Some((fd, self.write(fd, &[&partial[..], b"\n"].concat(), time, node)))
+        }).collect()
+    }
+
+    /// Lines which still have to be stored in the trace
+    pub fn take_pending(&mut self) -> Vec<Line> {
+        self.pending.split_off(0)
+    }
+}
+
+fn prefix(time: u64, node: Option<Addr>) -> String {
+    let name = node.map(|x| x.to_string()).unwrap_or_else(|| "-".into());
+
+    format!("[{:>10} {}] ", time, name)
+}
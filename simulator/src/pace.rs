@@ -0,0 +1,55 @@
+use std::env;
+use std::time::{Duration, Instant};
+
+use clock;
+
+/// Releases events no faster than the wall clock
+///
+/// Configured with `PEERSIM_PACE=<factor>`, the simulated time passing per wall clock time.
+/// `1` runs in real time, `0.1` ten times slower and `100` a hundred times faster. The
+/// simulated time itself is unaffected, events are only held back until their time has come.
+/// If the simulation falls behind, for example while stopped in the debugger, it continues
+/// from the current wall clock time instead of catching up in a burst.
+#[derive(Clone)]
+pub struct Pace {
+    factor: f64,
+    /// Wall clock time at which a simulated time was reached
+    anchor: (Instant, u64)
+}
+
+impl Pace {
+    pub fn new(factor: f64) -> Pace {
+        assert!(factor > 0.0, "The pace has to be positive, got {}", factor);
+
+        Pace {
+            factor,
+            anchor: (clock::real(Instant::now), 0)
+        }
+    }
+
+    pub fn from_env() -> Option<Pace> {
+        let factor = env::var("PEERSIM_PACE").ok()?.parse::<f64>()
+            .unwrap_or_else(|err| panic!("PEERSIM_PACE has to be a factor: {}", err));
+
+        println!("Pace: {} times real time", factor);
+
+        Some(Pace::new(factor))
+    }
+
+    /// Wall clock time at which an event of the simulated time may be released
+    pub fn release(&self, time: u64) -> Instant {
+        let (instant, start) = self.anchor;
+        let micros = time.saturating_sub(start) as f64 / self.factor;
+
+        instant + Duration::from_micros(micros as u64)
+    }
+
+    /// Remember that an event of the simulated time was released
+    pub fn released(&mut self, time: u64) {
+        let now = clock::real(Instant::now);
+
+        if now > self.release(time) {
+            self.anchor = (now, time);
+        }
+    }
+}
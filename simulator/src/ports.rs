@@ -0,0 +1,172 @@
+use std::collections::{BTreeMap, HashMap};
+use std::net::Ipv4Addr;
+
+use libc::{c_int, EADDRINUSE};
+
+use state::{Addr, Fd};
+
+/// Ports handed out for binds to port zero, the default range of Linux
+const EPHEMERAL: (u16, u16) = (32768, 60999);
+
+/// Time a port stays in `TIME_WAIT` after a listener with connections closed, in microseconds
+pub const TIME_WAIT: u64 = 60_000_000;
+
+/// `SO_REUSEADDR` and `SO_REUSEPORT` of a socket
+#[derive(Serialize, Deserialize, Clone, Copy, Default, Debug, PartialEq)]
+pub struct Reuse {
+    pub addr: bool,
+    pub port: bool
+}
+
+#[derive(Clone, Default)]
+struct Port {
+    /// Sockets bound to the port
+    fds: Vec<(Fd, Reuse)>,
+    /// Socket receiving the next connection, if several share the port
+    next: usize,
+    /// If a connection was accepted on the port
+    used: bool,
+    /// End of `TIME_WAIT` after the last socket closed
+    linger: u64
+}
+
+impl Port {
+    /// If a socket may bind this port at `time`
+    fn admits(&self, reuse: Reuse, time: u64) -> bool {
+        if self.fds.is_empty() {
+            self.linger <= time || reuse.addr
+        } else {
+            reuse.port && self.fds.iter().all(|(_, x)| x.port)
+        }
+    }
+}
+
+/// Port table of every host
+///
+/// Binding port zero picks a free ephemeral port of the host. A port is shared if all
+/// sockets on it set `SO_REUSEPORT`, connections are then spread round robin over them.
+/// After a listener which accepted connections closed, the port stays in `TIME_WAIT` and can
+/// only be bound with `SO_REUSEADDR`. The wildcard address conflicts with every host.
+#[derive(Clone, Default)]
+pub struct Ports {
+    hosts: HashMap<Ipv4Addr, BTreeMap<u16, Port>>,
+    bound: HashMap<Fd, Addr>,
+    next_ephemeral: HashMap<Ipv4Addr, u16>
+}
+
+impl Ports {
+    /// Entries of a port on all hosts overlapping with `ip`
+    fn overlapping<'a>(&'a self, ip: Ipv4Addr, port: u16) -> impl Iterator<Item=&'a Port> + 'a {
+        self.hosts.iter()
+            .filter(move |(host, _)| **host == ip || host.is_unspecified() || ip.is_unspecified())
+            .filter_map(move |(_, ports)| ports.get(&port))
+    }
+
+    fn is_free(&self, ip: Ipv4Addr, port: u16, reuse: Reuse, time: u64) -> bool {
+        self.overlapping(ip, port).all(|x| x.admits(reuse, time))
+    }
+
+    /// Bind a socket, returns the address with the port picked for port zero
+    pub fn bind(&mut self, fd: Fd, addr: Addr, reuse: Reuse, time: u64) -> Result<Addr, c_int> {
+        let ip = *addr.ip();
+
+        let port = if addr.port() == 0 {
+            let start = self.next_ephemeral.get(&ip).cloned().unwrap_or(EPHEMERAL.0);
+            let offset = (start - EPHEMERAL.0) as u32;
+            let count = (EPHEMERAL.1 - EPHEMERAL.0) as u32 + 1;
+
+            let port = (0..count)
+                .map(|i| EPHEMERAL.0 + ((offset + i) % count) as u16)
+                .find(|port| self.is_free(ip, *port, Reuse::default(), time))
+                .ok_or(EADDRINUSE)?;
+
+            self.next_ephemeral.insert(ip, if port == EPHEMERAL.1 { EPHEMERAL.0 } else { port + 1 });
+
+            port
+        } else if self.is_free(ip, addr.port(), reuse, time) {
+            addr.port()
+        } else {
+            return Err(EADDRINUSE);
+        };
+
+        let addr = Addr::new(ip, port);
+        let entry = self.hosts.entry(ip).or_default()
+            .entry(port).or_default();
+
+        // a new socket after TIME_WAIT starts without connections
+        if entry.fds.is_empty() {
+            *entry = Port::default();
+        }
+
+        entry.fds.push((fd, reuse));
+        self.bound.insert(fd, addr);
+
+        Ok(addr)
+    }
+
+    /// Every port in use with its sockets, if it accepted a connection and the end of
+    /// `TIME_WAIT`, sorted for snapshots
+    pub fn ports(&self) -> Vec<(Addr, Vec<Fd>, bool, u64)> {
+        let mut ports = self.hosts.iter()
+            .flat_map(|(ip, ports)| ports.iter().map(move |(port, x)| {
+                (Addr::new(*ip, *port), x.fds.iter().map(|x| x.0).collect(), x.used, x.linger)
+            }))
+            .collect::<Vec<_>>();
+
+        ports.sort();
+        ports
+    }
+
+    /// Remove a closed socket, returns its address if it was bound
+    pub fn release(&mut self, fd: Fd, time: u64) -> Option<Addr> {
+        let addr = self.bound.remove(&fd)?;
+
+        let ports = self.hosts.get_mut(addr.ip())?;
+        let empty = {
+            let port = ports.get_mut(&addr.port())?;
+            port.fds.retain(|x| x.0 != fd);
+
+            if port.fds.is_empty() && port.used {
+                port.linger = time + TIME_WAIT;
+            }
+
+            port.fds.is_empty() && !port.used
+        };
+
+        if empty {
+            ports.remove(&addr.port());
+        }
+
+        Some(addr)
+    }
+
+    /// All sockets bound to an address
+    pub fn sockets(&self, addr: Addr) -> Vec<Fd> {
+        self.hosts.get(addr.ip())
+            .and_then(|x| x.get(&addr.port()))
+            .map(|x| x.fds.iter().map(|x| x.0).collect())
+            .unwrap_or_default()
+    }
+
+    /// The socket receiving the next connection to an address
+    pub fn listener(&mut self, addr: Addr) -> Option<Fd> {
+        let port = self.hosts.get_mut(addr.ip())?.get_mut(&addr.port())?;
+        if port.fds.is_empty() {
+            return None;
+        }
+
+        let fd = port.fds[port.next % port.fds.len()].0;
+        port.next = port.next.wrapping_add(1);
+
+        Some(fd)
+    }
+
+    /// Remember that a connection was accepted on the port of a listener
+    pub fn accepted(&mut self, fd: Fd) {
+        if let Some(addr) = self.bound.get(&fd) {
+            if let Some(port) = self.hosts.get_mut(addr.ip()).and_then(|x| x.get_mut(&addr.port())) {
+                port.used = true;
+            }
+        }
+    }
+}
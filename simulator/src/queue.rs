@@ -0,0 +1,150 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use state::{Event, Fd};
+
+/// Event queue ordered by delivery time, with an index per receiving file descriptor
+///
+/// Events with the same delivery time keep the order in which they were pushed. Finding and
+/// removing the next event of a file descriptor costs O(log n) instead of a scan through
+/// the whole queue.
+#[derive(Clone)]
+pub struct Queue {
+    order: BTreeMap<Key, Event>,
+    by_fd: HashMap<Fd, BTreeSet<Key>>,
+    /// The pending connect of a connecting socket
+    connecting: HashMap<Fd, Key>,
+    seq: u64
+}
+
+/// Position of an event in the queue, its delivery time and a sequence number
+pub type Key = (u64, u64);
+
+/// The file descriptor which will receive an event
+pub fn target(event: &Event) -> Fd {
+    match event {
+        Event::SendPacket(fd, _) | Event::Fin(fd) | Event::Reset(fd) => *fd,
+        Event::Connect(_, listener) => *listener
+    }
+}
+
+impl Queue {
+    pub fn new() -> Queue {
+        Queue {
+            order: BTreeMap::new(),
+            by_fd: HashMap::new(),
+            connecting: HashMap::new(),
+            seq: 0
+        }
+    }
+
+    pub fn push(&mut self, event: Event, time: u64) -> Key {
+        let key = (time, self.seq);
+        self.seq += 1;
+
+        self.by_fd.entry(target(&event)).or_default().insert(key);
+        if let Event::Connect(origin, _) = event {
+            self.connecting.insert(origin, key);
+        }
+        self.order.insert(key, event);
+
+        key
+    }
+
+    /// Add data to a queued packet, returns `false` if it was delivered already
+    pub fn append(&mut self, key: Key, buf: &[u8]) -> bool {
+        match self.order.get_mut(&key) {
+            Some(Event::SendPacket(_, data)) => {
+                data.extend_from_slice(buf);
+                true
+            },
+            _ => false
+        }
+    }
+
+    /// The event with the earliest delivery time
+    pub fn peek(&self) -> Option<(&Event, u64)> {
+        self.order.iter().next().map(|(key, event)| (event, key.0))
+    }
+
+    fn due_key(&self, fd: Fd) -> Option<Key> {
+        let head = self.order.keys().next()?.0;
+        let key = *self.by_fd.get(&fd)?.iter().next()?;
+
+        if key.0 == head {
+            Some(key)
+        } else {
+            None
+        }
+    }
+
+    /// The next event of `fd`, if it is due at the time of the earliest event
+    pub fn peek_due(&self, fd: Fd) -> Option<(&Event, u64)> {
+        self.due_key(fd).map(|key| (&self.order[&key], key.0))
+    }
+
+    /// Remove the next event of `fd`, if it is due at the time of the earliest event
+    pub fn pop_due(&mut self, fd: Fd) -> Option<(Event, u64)> {
+        let key = self.due_key(fd)?;
+
+        self.take(fd, key).map(|event| (event, key.0))
+    }
+
+    /// Remove the event at `key` which `fd` would receive
+    fn take(&mut self, fd: Fd, key: Key) -> Option<Event> {
+        let remaining = match self.by_fd.get_mut(&fd) {
+            Some(keys) => {
+                keys.remove(&key);
+                keys.len()
+            },
+            None => 0
+        };
+
+        if remaining == 0 {
+            self.by_fd.remove(&fd);
+        }
+
+        let event = self.order.remove(&key);
+        if let Some(Event::Connect(origin, _)) = event {
+            self.connecting.remove(&origin);
+        }
+
+        event
+    }
+
+    /// Move the next event of `fd` to a later time, behind the events already queued for then
+    pub fn defer(&mut self, fd: Fd, time: u64) {
+        if let Some((event, _)) = self.pop_due(fd) {
+            self.push(event, time);
+        }
+    }
+
+    /// Drop all events of `fd`
+    pub fn remove_fd(&mut self, fd: Fd) {
+        for key in self.by_fd.remove(&fd).unwrap_or_default() {
+            if let Some(Event::Connect(origin, _)) = self.order.remove(&key) {
+                self.connecting.remove(&origin);
+            }
+        }
+    }
+
+    /// Drop the pending connect of a connecting socket, which arrives at its listener
+    pub fn remove_connect(&mut self, origin: Fd) {
+        if let Some(key) = self.connecting.get(&origin).cloned() {
+            let listener = target(&self.order[&key]);
+            self.take(listener, key);
+        }
+    }
+
+    /// All events in the order of their delivery
+    pub fn iter<'a>(&'a self) -> impl Iterator<Item=(&'a Event, u64)> + 'a {
+        self.order.iter().map(|(key, event)| (event, key.0))
+    }
+
+    pub fn len(&self) -> usize {
+        self.order.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+}
@@ -0,0 +1,98 @@
+use std::env;
+use std::fmt;
+use std::fs::File;
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use bincode::serialize;
+
+use clock;
+use dissect::Dissector;
+use state::{Addr, Fd};
+
+/// A socket call of a real run, as seen by the recorded process
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum Record {
+    Bind(Fd, Addr),
+    /// Connect from a local address to a remote one
+    Connect(Fd, Addr, Addr),
+    /// Accept on a listener, with the new connection and the address of the peer
+    Accept(Fd, Fd, Addr),
+    Send(Fd, Vec<u8>),
+    Recv(Fd, Vec<u8>)
+}
+
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Record::Bind(fd, addr) => write!(f, "bind {} {}", fd, addr),
+            Record::Connect(fd, local, remote) => write!(f, "connect {} {} -> {}", fd, local, remote),
+            Record::Accept(listener, fd, peer) => write!(f, "accept {} {} {}", listener, fd, peer),
+            Record::Send(fd, buf) => write!(f, "send {} {} bytes", fd, buf.len()),
+            Record::Recv(fd, buf) => write!(f, "recv {} {} bytes", fd, buf.len())
+        }
+    }
+}
+
+/// Decoded frames of both directions of the recorded streams
+struct Trace {
+    file: File,
+    sent: Dissector,
+    received: Dissector
+}
+
+/// Records the socket calls of a real run
+///
+/// Configured with `PEERSIM_RECORD=<path>`, all calls are passed through to the kernel and
+/// logged with the microseconds since the start of the process. The recording can be
+/// replayed with `peersim-replay`. With `PEERSIM_TRACE=<path>` a readable trace with the
+/// decoded frames is written as well.
+pub struct Recorder {
+    start: Instant,
+    file: Mutex<File>,
+    trace: Option<Mutex<Trace>>
+}
+
+impl Recorder {
+    pub fn from_env() -> Option<Recorder> {
+        let path = env::var("PEERSIM_RECORD").ok()?;
+        let file = File::create(&path)
+            .unwrap_or_else(|err| panic!("Could not create recording {}: {}", path, err));
+
+        let trace = env::var("PEERSIM_TRACE").ok()
+            .and_then(|path| File::create(path).ok())
+            .map(|file| Mutex::new(Trace { file, sent: Dissector::new(), received: Dissector::new() }));
+
+        Some(Recorder {
+            start: clock::real(Instant::now),
+            file: Mutex::new(file),
+            trace
+        })
+    }
+
+    pub fn record(&self, record: Record) {
+        let elapsed = clock::real(|| self.start.elapsed());
+        let time = elapsed.as_secs() * 1_000_000 + elapsed.subsec_micros() as u64;
+
+        if let Some(ref trace) = self.trace {
+            let mut trace = trace.lock().unwrap();
+            let mut line = format!("{:>10} {}", time, record);
+
+            let frames = match record {
+                Record::Send(fd, ref buf) => trace.sent.feed(fd, buf),
+                Record::Recv(fd, ref buf) => trace.received.feed(fd, buf),
+                _ => Vec::new()
+            };
+
+            for frame in frames {
+                line.push_str(&format!(" | {}", frame));
+            }
+
+            writeln!(trace.file, "{}", line).unwrap();
+        }
+
+        let buf = serialize(&(time, record)).unwrap();
+        self.file.lock().unwrap().write_all(&buf).unwrap();
+    }
+}
@@ -0,0 +1,253 @@
+use std::env;
+use std::io::{self, Write};
+use std::os::unix::net::UnixStream;
+use std::sync::Mutex;
+use std::net::Ipv4Addr;
+
+use libc::c_int;
+use bincode::{serialize, deserialize_from};
+use scenario::DnsError;
+
+use clock;
+use output::Line;
+
+use state::{Addr, Fd, EpollId, SocketOption};
+
+/// Environment variable with the socket path of the coordinator
+pub const COORDINATOR_ENV: &str = "PEERSIM_COORDINATOR";
+
+/// A call of a preloaded process, file descriptors are local to the process and made
+/// global by the coordinator
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Request {
+    /// Register a new process, answered with its id and if the clocks are simulated
+    Hello,
+    /// Open the second connection of a process, used for the blocking `epoll_wait`
+    Attach(u32),
+    Bind(Fd, Addr),
+    SetOption(Fd, SocketOption),
+    /// Simulated option of a socket, by level and name of `getsockopt`
+    GetOption(Fd, c_int, c_int),
+    Listen(Fd, c_int),
+    Shutdown(Fd),
+    Close(Fd),
+    Connect(Fd, Addr),
+    /// Accept on a listener, with the already created socket of the new connection
+    Accept(Fd, Fd),
+    Send(Fd, Vec<u8>),
+    Recv(Fd),
+    SockName(Fd),
+    PeerName(Fd),
+    TakeError(Fd),
+    EpollCtl(Fd, u32, EpollId),
+    Resolve(String),
+    Clock(clock::Kind),
+    /// Store output lines of the application in the trace
+    Output(Vec<Line>),
+    /// Wait for the next event of the process, for at most the timeout in milliseconds
+    EpollWait(c_int)
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Response {
+    /// Id of a new process, if the clocks are simulated and the seed of the scenario
+    Process(u32, bool, u64),
+    Done,
+    /// Address of a bound socket, with the port picked for port zero
+    Bound(Result<Addr, c_int>),
+    Accepted(bool),
+    Option(Option<c_int>),
+    Sent(Result<(), c_int>),
+    Data(Result<Vec<u8>, c_int>),
+    SockName(Addr),
+    PeerName(Option<Addr>),
+    Error(Option<c_int>),
+    Resolved(Option<Result<Vec<Ipv4Addr>, DnsError>>),
+    Clock(Option<u64>),
+    /// The next event, with the simulated time and the node it is delivered to
+    Epoll(Option<(EpollId, c_int)>, u64, Option<Addr>)
+}
+
+pub fn write_message<T: ::serde::Serialize>(stream: &mut UnixStream, msg: &T) -> io::Result<()> {
+    // a single write per message, bincode would issue one for every field
+    let buf = serialize(msg).map_err(io::Error::other)?;
+
+    stream.write_all(&buf)
+}
+
+pub fn read_message<T: ::serde::de::DeserializeOwned>(stream: &mut UnixStream) -> io::Result<T> {
+    deserialize_from(stream).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+/// Connection of a preloaded process to the coordinator
+///
+/// Set `PEERSIM_COORDINATOR=<socket>` to run the nodes of a process in the network of a
+/// `peersim-coordinator`. Instead of simulating its own network, each hook is forwarded to
+/// the coordinator, which owns the event queue of all processes. `epoll_wait` blocks on a
+/// connection of its own, so other threads can keep on calling.
+pub struct Remote {
+    clocks: bool,
+    seed: u64,
+    calls: Mutex<UnixStream>,
+    epoll: Mutex<UnixStream>
+}
+
+impl Remote {
+    pub fn from_env() -> Option<Remote> {
+        let path = env::var(COORDINATOR_ENV).ok()?;
+
+        let connect = || UnixStream::connect(&path)
+            .unwrap_or_else(|err| panic!("Could not connect to the coordinator at {}: {}", path, err));
+
+        let mut calls = connect();
+        let (process, clocks, seed) = match Remote::exchange(&mut calls, &Request::Hello) {
+            Response::Process(id, clocks, seed) => (id, clocks, seed),
+            x => unexpected(x)
+        };
+
+        let mut epoll = connect();
+        Remote::exchange(&mut epoll, &Request::Attach(process));
+
+        println!("Coordinator: joined {} as process {}", path, process);
+
+        Some(Remote {
+            clocks,
+            seed,
+            calls: Mutex::new(calls),
+            epoll: Mutex::new(epoll)
+        })
+    }
+
+    fn exchange(stream: &mut UnixStream, req: &Request) -> Response {
+        write_message(stream, req)
+            .and_then(|_| read_message(stream))
+            .unwrap_or_else(|err| panic!("Lost the connection to the coordinator: {}", err))
+    }
+
+    fn call(&self, req: Request) -> Response {
+        Remote::exchange(&mut self.calls.lock().unwrap(), &req)
+    }
+
+    /// Seed of the scenario simulated by the coordinator
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn bind(&self, fd: Fd, addr: Addr) -> Result<Addr, c_int> {
+        match self.call(Request::Bind(fd, addr)) {
+            Response::Bound(x) => x,
+            x => unexpected(x)
+        }
+    }
+
+    pub fn set_option(&self, fd: Fd, option: SocketOption) {
+        self.call(Request::SetOption(fd, option));
+    }
+
+    pub fn get_option(&self, fd: Fd, level: c_int, name: c_int) -> Option<c_int> {
+        match self.call(Request::GetOption(fd, level, name)) {
+            Response::Option(x) => x,
+            x => unexpected(x)
+        }
+    }
+
+    pub fn listen(&self, fd: Fd, backlog: c_int) {
+        self.call(Request::Listen(fd, backlog));
+    }
+
+    pub fn shutdown(&self, fd: Fd) -> Result<(), c_int> {
+        match self.call(Request::Shutdown(fd)) {
+            Response::Sent(x) => x,
+            x => unexpected(x)
+        }
+    }
+
+    pub fn close(&self, fd: Fd) {
+        self.call(Request::Close(fd));
+    }
+
+    pub fn connect(&self, fd: Fd, addr: Addr) {
+        self.call(Request::Connect(fd, addr));
+    }
+
+    /// Accept the next connection of a listener as `new_fd`, false if there is none
+    pub fn accept(&self, fd: Fd, new_fd: Fd) -> bool {
+        match self.call(Request::Accept(fd, new_fd)) {
+            Response::Accepted(x) => x,
+            x => unexpected(x)
+        }
+    }
+
+    pub fn send(&self, fd: Fd, buf: &[u8]) -> Result<(), c_int> {
+        match self.call(Request::Send(fd, buf.to_vec())) {
+            Response::Sent(x) => x,
+            x => unexpected(x)
+        }
+    }
+
+    pub fn recv(&self, fd: Fd) -> Result<Vec<u8>, c_int> {
+        match self.call(Request::Recv(fd)) {
+            Response::Data(x) => x,
+            x => unexpected(x)
+        }
+    }
+
+    pub fn sockname(&self, fd: Fd) -> Addr {
+        match self.call(Request::SockName(fd)) {
+            Response::SockName(x) => x,
+            x => unexpected(x)
+        }
+    }
+
+    pub fn peername(&self, fd: Fd) -> Option<Addr> {
+        match self.call(Request::PeerName(fd)) {
+            Response::PeerName(x) => x,
+            x => unexpected(x)
+        }
+    }
+
+    pub fn take_error(&self, fd: Fd) -> Option<c_int> {
+        match self.call(Request::TakeError(fd)) {
+            Response::Error(x) => x,
+            x => unexpected(x)
+        }
+    }
+
+    pub fn resolve(&self, name: &str) -> Option<Result<Vec<Ipv4Addr>, DnsError>> {
+        match self.call(Request::Resolve(name.to_string())) {
+            Response::Resolved(x) => x,
+            x => unexpected(x)
+        }
+    }
+
+    /// Clock of the node running in this process, without a round trip for host clocks
+    pub fn clock(&self, kind: clock::Kind) -> Option<u64> {
+        if !self.clocks {
+            return None;
+        }
+
+        match self.call(Request::Clock(kind)) {
+            Response::Clock(x) => x,
+            x => unexpected(x)
+        }
+    }
+
+    pub fn output(&self, lines: Vec<Line>) {
+        self.call(Request::Output(lines));
+    }
+
+    pub fn epoll_ctl(&self, fd: Fd, events: u32, id: EpollId) {
+        self.call(Request::EpollCtl(fd, events, id));
+    }
+
+    pub fn epoll_wait(&self, timeout: c_int) -> (Option<(EpollId, c_int)>, u64, Option<Addr>) {
+        match Remote::exchange(&mut self.epoll.lock().unwrap(), &Request::EpollWait(timeout)) {
+            Response::Epoll(x, time, node) => (x, time, node),
+            x => unexpected(x)
+        }
+    }
+}
+
+fn unexpected(res: Response) -> ! {
+    panic!("Unexpected answer of the coordinator: {:?}", res)
+}
@@ -0,0 +1,31 @@
+/// Small deterministic random number generator (splitmix64)
+///
+/// Everything random inside the simulator is drawn from here, so a simulation can be
+/// repeated with the same seed.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        Rng(seed)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E3779B97F4A7C15);
+
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+
+        z ^ (z >> 31)
+    }
+
+    /// Uniform number in `0..n`, returns zero for an empty range
+    pub fn below(&mut self, n: u64) -> u64 {
+        if n == 0 {
+            return 0;
+        }
+
+        self.next_u64() % n
+    }
+}
@@ -1,14 +1,36 @@
-use std::collections::VecDeque;
-use libc::{c_uint, c_int, EPOLLIN, EPOLLOUT, socket, AF_INET, sockaddr_in, in_addr};
-use std::net::SocketAddrV4;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use libc::{c_uint, c_int, EPOLLIN, EPOLLOUT, EPOLLERR, EPOLLHUP, AF_INET, sockaddr_in, in_addr, EWOULDBLOCK, ECONNREFUSED, ENOTCONN, ETIMEDOUT, ECONNRESET, EPIPE, EPOLLRDHUP, SOL_SOCKET, SO_REUSEADDR, SO_REUSEPORT};
+use std::net::{Ipv4Addr, SocketAddrV4};
 use std::collections::HashMap;
-use std::cmp::Reverse;
+use std::cmp;
+use std::env;
+use std::fmt;
+use std::fs::File;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::process;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
-use priority_queue::PriorityQueue;
+use scenario::{DnsError, Overflow, Scenario, DEFAULT_CPU};
 
-type Addr = SocketAddrV4;
-type Fd = c_int;
-type EpollId = u64;
+use dissect::Dissector;
+use rng::Rng;
+use checkpoint::{Checkpoint, Checkpoints, Snapshot, scenario_source};
+use explore::Explore;
+use queue::{self, Queue};
+use pace::Pace;
+use nat::Nat;
+use ports::{Ports, Reuse};
+use backlog::Backlogs;
+use options::{Options, MSS};
+use byzantine::Adversary;
+use metrics::{Counters, Metrics};
+use clock;
+use output::Line;
+
+pub type Addr = SocketAddrV4;
+pub type Fd = c_int;
+pub type EpollId = u64;
 
 #[derive(Clone)]
 pub struct Node {
@@ -17,144 +39,1096 @@ pub struct Node {
     latency: u64
 }
 
-#[derive(Hash, PartialEq, Eq, Clone, Debug)]
+#[derive(Hash, PartialEq, Eq, PartialOrd, Ord, Clone, Debug, Serialize, Deserialize)]
 pub enum Event {
     SendPacket(Fd, Vec<u8>),
-    Connect(Fd, Fd)
+    Connect(Fd, Fd),
+    /// The peer shut down its sending side, reads return the end of the stream
+    Fin(Fd),
+    /// The peer aborted the connection
+    Reset(Fd)
 }
 
-#[derive(Clone)]
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Event::SendPacket(fd, buf) => write!(f, "send_packet {} {} bytes", fd, buf.len()),
+            Event::Connect(from, to) => write!(f, "connect {} {}", from, to),
+            Event::Fin(fd) => write!(f, "fin {}", fd),
+            Event::Reset(fd) => write!(f, "reset {}", fd)
+        }
+    }
+}
+
+/// Socket options which change how the simulated network treats a socket
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub enum SocketOption {
+    ReuseAddr(bool),
+    ReusePort(bool),
+    /// `SO_LINGER` timeout in seconds if enabled, zero aborts the connection on close
+    Linger(Option<c_int>),
+    NoDelay(bool),
+    KeepAlive(bool),
+    KeepIdle(c_int),
+    KeepInterval(c_int),
+    KeepCount(c_int),
+    SendBuffer(c_int),
+    RecvBuffer(c_int)
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub enum Log {
     AddNode(Addr, u32),
-    SendPacket(Fd, Vec<u8>),
-    Connect(Fd, Addr)
+    /// Packet to a file descriptor, sent by a node
+    SendPacket(Fd, Option<Addr>, Vec<u8>),
+    Connect(Fd, Addr),
+    /// End of the stream to a file descriptor, sent by a node
+    Fin(Fd, Option<Addr>),
+    /// Abort of the connection of a file descriptor, sent by a node
+    Reset(Fd, Option<Addr>),
+    Refused(Fd, Addr),
+    /// A connect found the accept queue of the listener at the address full
+    Overflow(Fd, Addr),
+    /// A connect gave up after its SYN was dropped too often
+    TimedOut(Fd, Addr),
+    Resolve(String, Result<Vec<Ipv4Addr>, DnsError>),
+    /// A line the application wrote to stdout or stderr while a node was running
+    Output(Option<Addr>, c_int, String)
 }
 
+impl fmt::Display for Log {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Log::AddNode(addr, latency) => write!(f, "add_node {} latency {}", addr, latency),
+            Log::SendPacket(fd, node, buf) => write!(f, "send_packet {} {} bytes from {}", fd, buf.len(),
+                node.map(|x| x.to_string()).unwrap_or_else(|| "-".into())),
+            Log::Connect(fd, addr) => write!(f, "connect {} {}", fd, addr),
+            Log::Fin(fd, node) => write!(f, "fin {} from {}", fd,
+                node.map(|x| x.to_string()).unwrap_or_else(|| "-".into())),
+            Log::Reset(fd, node) => write!(f, "reset {} from {}", fd,
+                node.map(|x| x.to_string()).unwrap_or_else(|| "-".into())),
+            Log::Refused(fd, addr) => write!(f, "refused {} {}", fd, addr),
+            Log::Overflow(fd, addr) => write!(f, "overflow {} {}", fd, addr),
+            Log::TimedOut(fd, addr) => write!(f, "timed_out {} {}", fd, addr),
+            Log::Resolve(name, Ok(addrs)) => write!(f, "resolve {} {}", name,
+                addrs.iter().map(|x| x.to_string()).collect::<Vec<_>>().join(", ")),
+            Log::Resolve(name, Err(err)) => write!(f, "resolve {} {:?}", name, err),
+            Log::Output(node, fd, text) => write!(f, "output {} {} {}",
+                node.map(|x| x.to_string()).unwrap_or_else(|| "-".into()),
+                if *fd == 2 { "stderr" } else { "stdout" }, text)
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct State {
     nodes: HashMap<Addr, Node>,
     connections: HashMap<Fd, Fd>,
-    events: PriorityQueue<Event, Reverse<u64>>,
+    events: Queue,
     epoll_notify: VecDeque<(Fd, c_int)>,
-    epoll: Vec<(Fd, c_int, EpollId)>,
+    epoll: HashMap<Fd, (c_int, EpollId)>,
+    listeners: HashMap<Fd, Addr>,
     timer: u64,
-    logs: Vec<Log>
+    logs: Vec<(u64, Log)>,
+    dissector: Dissector,
+    trace: Option<Arc<Mutex<File>>>,
+    scenario: Option<Scenario>,
+    rng: Rng,
+    owner: HashMap<Fd, Addr>,
+    current: Option<Addr>,
+    last_delivery: HashMap<Fd, u64>,
+    checkpoints: Checkpoints,
+    explore: Option<Explore>,
+    until: Option<u64>,
+    pace: Option<Pace>,
+    nat: Nat,
+    /// Remote address of a connection as seen by the node, rewritten by NAT boxes
+    peers: HashMap<Fd, Addr>,
+    /// Source address of a connect as seen by the accepting node
+    sources: HashMap<Fd, Addr>,
+    /// Socket errors reported by the next operation on the file descriptor
+    errors: HashMap<Fd, c_int>,
+    /// Wall clock time at the start of the simulation
+    epoch: u64,
+    /// Latest reading of a monotonic clock
+    monotonic: u64,
+    /// Simulated time till which the CPU of a node is busy
+    busy: HashMap<Addr, u64>,
+    ports: Ports,
+    reuse: HashMap<Fd, Reuse>,
+    backlogs: Backlogs,
+    /// `SO_LINGER` timeouts of the sockets which enabled it
+    linger: HashMap<Fd, c_int>,
+    /// Sockets which shut down their sending side
+    shut: BTreeSet<Fd>,
+    /// Sockets which read the end of the stream
+    eof: BTreeSet<Fd>,
+    /// Sockets whose peer closed the connection, with if its abort is already on the way back
+    hung_up: BTreeMap<Fd, bool>,
+    /// Sockets whose connection was aborted
+    reset: BTreeSet<Fd>,
+    options: BTreeMap<Fd, Options>,
+    /// Time at which the data a socket sent so far is acknowledged
+    acked: HashMap<Fd, u64>,
+    /// Packet held back by Nagle's algorithm and the time it leaves
+    held: HashMap<Fd, (queue::Key, u64)>,
+    /// Bytes on the way to a socket or waiting to be read
+    unread: HashMap<Fd, usize>,
+    /// Sockets which found the buffers of their connection full
+    blocked: BTreeSet<Fd>,
+    /// Time of the last traffic of a connection, for keepalive probes
+    activity: HashMap<Fd, u64>,
+    adversary: Adversary,
+    /// Traffic of every node, for the metrics
+    counters: BTreeMap<Addr, Counters>,
+    /// Events delivered so far
+    delivered: u64
 }
 
 impl State {
     pub fn new() -> State {
-        State { 
+        let checkpoints = Checkpoints::from_env();
+
+        let mut state = State::with_scenario(checkpoints.scenario());
+        state.checkpoints = checkpoints;
+        state.explore = Explore::from_env();
+        state.until = env::var("PEERSIM_UNTIL").ok().and_then(|x| x.parse().ok());
+        state.pace = Pace::from_env();
+
+        state
+    }
+
+    pub fn with_scenario(scenario: Option<Scenario>) -> State {
+        let epoch = match scenario.as_ref().and_then(|x| x.epoch) {
+            Some(epoch) => epoch * 1_000_000,
+            None => clock::real(|| SystemTime::now().duration_since(UNIX_EPOCH).unwrap())
+                .as_secs() * 1_000_000
+        };
+
+        State {
             nodes: HashMap::new(),
             connections: HashMap::new(),
-            events: PriorityQueue::new(),
+            events: Queue::new(),
             epoll_notify: VecDeque::new(),
-            epoll: Vec::new(),
+            epoll: HashMap::new(),
+            listeners: HashMap::new(),
             timer: 0,
-            logs: Vec::new()
+            logs: Vec::new(),
+            dissector: Dissector::new(),
+            trace: env::var("PEERSIM_TRACE").ok()
+                .and_then(|path| File::create(path).ok())
+                .map(|file| Arc::new(Mutex::new(file))),
+            rng: Rng::new(scenario.as_ref().map(|x| x.seed).unwrap_or(0)),
+            scenario,
+            owner: HashMap::new(),
+            current: None,
+            last_delivery: HashMap::new(),
+            checkpoints: Checkpoints::default(),
+            explore: None,
+            until: None,
+            pace: None,
+            nat: Nat::default(),
+            peers: HashMap::new(),
+            sources: HashMap::new(),
+            errors: HashMap::new(),
+            epoch,
+            monotonic: 0,
+            busy: HashMap::new(),
+            ports: Ports::default(),
+            reuse: HashMap::new(),
+            backlogs: Backlogs::default(),
+            linger: HashMap::new(),
+            shut: BTreeSet::new(),
+            eof: BTreeSet::new(),
+            hung_up: BTreeMap::new(),
+            reset: BTreeSet::new(),
+            options: BTreeMap::new(),
+            acked: HashMap::new(),
+            held: HashMap::new(),
+            unread: HashMap::new(),
+            blocked: BTreeSet::new(),
+            activity: HashMap::new(),
+            adversary: Adversary::default(),
+            counters: BTreeMap::new(),
+            delivered: 0
+        }
+    }
+
+    /// Move the simulated time forward and handle pending checkpoints
+    pub fn advance(&mut self, time: u64) {
+        self.jump(time);
+
+        if self.until.map(|x| self.timer >= x).unwrap_or(false) {
+            println!("Simulation reached {}, stopping", self.timer);
+            process::exit(0);
+        }
+
+        if let Some((time, path)) = self.checkpoints.save.clone() {
+            if self.timer >= time {
+                self.checkpoints.save = None;
+
+                let checkpoint = Checkpoint {
+                    scenario: scenario_source(),
+                    snapshot: self.snapshot(),
+                    logs: self.logs.clone()
+                };
+
+                match checkpoint.save(&path) {
+                    Ok(_) => println!("Checkpoint: saved {} at {}", path, self.timer),
+                    Err(err) => println!("Checkpoint: could not save {}: {}", path, err)
+                }
+            }
+        }
+
+        let reached = self.checkpoints.restore.as_ref()
+            .map(|x| self.timer >= x.snapshot.timer)
+            .unwrap_or(false);
+
+        if reached {
+            let checkpoint = self.checkpoints.restore.take().unwrap();
+            let snapshot = self.snapshot();
+
+            if snapshot == checkpoint.snapshot {
+                println!("Checkpoint: replay reached {} with digest {:x}", self.timer, snapshot.digest());
+            } else {
+                println!("Checkpoint: replay diverged at {}, digest {:x} instead of {:x}",
+                    self.timer, snapshot.digest(), checkpoint.snapshot.digest());
+            }
+
+            if let Some(fork) = self.checkpoints.fork.take() {
+                let same_nodes = match self.scenario {
+                    Some(ref scenario) => scenario.nodes().iter().map(|x| x.addr)
+                        .eq(fork.nodes().iter().map(|x| x.addr)),
+                    None => true
+                };
+
+                if !same_nodes {
+                    panic!("A fork has to keep the nodes of the checkpoint");
+                }
+
+                println!("Checkpoint: continue with forked scenario at {}", self.timer);
+                self.scenario = Some(fork);
+            }
+        }
+    }
+
+    /// Serialisable copy of the whole network state
+    pub fn snapshot(&self) -> Snapshot {
+        let mut nodes = self.nodes.values()
+            .map(|x| (x.addr, x.fd, x.latency))
+            .collect::<Vec<_>>();
+        let mut owner = self.owner.iter().map(|(a, b)| (*a, *b)).collect::<Vec<_>>();
+        let mut last_delivery = self.last_delivery.iter().map(|(a, b)| (*a, *b)).collect::<Vec<_>>();
+        let mut busy = self.busy.iter().map(|(a, b)| (*a, *b)).collect::<Vec<_>>();
+        let mut peers = self.peers.iter().map(|(a, b)| (*a, *b)).collect::<Vec<_>>();
+        let mut sources = self.sources.iter().map(|(a, b)| (*a, *b)).collect::<Vec<_>>();
+        let mut reuse = self.reuse.iter().map(|(a, b)| (*a, *b)).collect::<Vec<_>>();
+        let mut linger = self.linger.iter().map(|(a, b)| (*a, *b)).collect::<Vec<_>>();
+        let mut errors = self.errors.iter().map(|(a, b)| (*a, *b)).collect::<Vec<_>>();
+        let mut held = self.held.iter().map(|(a, (b, c))| (*a, *b, *c)).collect::<Vec<_>>();
+        let mut acked = self.acked.iter().map(|(a, b)| (*a, *b)).collect::<Vec<_>>();
+        let mut unread = self.unread.iter().map(|(a, b)| (*a, *b)).collect::<Vec<_>>();
+        let mut activity = self.activity.iter().map(|(a, b)| (*a, *b)).collect::<Vec<_>>();
+
+        nodes.sort();
+        owner.sort();
+        last_delivery.sort();
+        busy.sort();
+        peers.sort();
+        sources.sort();
+        reuse.sort_by_key(|x| x.0);
+        linger.sort();
+        errors.sort();
+        held.sort();
+        acked.sort();
+        unread.sort();
+        activity.sort();
+
+        let mut events = self.pending_events();
+        events.sort_by(|a, b| (a.1, &a.0).cmp(&(b.1, &b.0)));
+
+        Snapshot {
+            timer: self.timer,
+            nodes,
+            connections: self.connections(),
+            events,
+            epoll_notify: self.epoll_notify.iter().cloned().collect(),
+            epoll: self.epoll_registrations(),
+            owner,
+            last_delivery,
+            busy,
+            rng: self.rng.clone(),
+            nat: self.nat.mappings(),
+            peers,
+            sources,
+            ports: self.ports.ports(),
+            backlogs: self.backlogs.clone(),
+            reuse,
+            linger,
+            options: self.options.iter().map(|(a, b)| (*a, *b)).collect(),
+            shut: self.shut.iter().cloned().collect(),
+            eof: self.eof.iter().cloned().collect(),
+            hung_up: self.hung_up.iter().map(|(a, b)| (*a, *b)).collect(),
+            reset: self.reset.iter().cloned().collect(),
+            errors,
+            held,
+            acked,
+            unread,
+            blocked: self.blocked.iter().cloned().collect(),
+            activity,
+            counters: self.counters.iter().map(|(a, b)| (*a, *b)).collect(),
+            delivered: self.delivered
         }
     }
 
+    /// Calculate the arrival time of something sent now from `from` to `to`
+    ///
+    /// When exploring schedules, the exploration strategy adds its delay on top.
+    /// Without a scenario everything arrives after `latency`. Otherwise nodes only start
+    /// talking after their join time, the link latency and jitter is taken from the scenario,
+    /// packets are held back until a partition healed and crashed nodes neither send nor
+    /// receive anything.
+    fn arrival(&mut self, from: Option<Addr>, to: Addr, latency: u64) -> Option<u64> {
+        let start = self.node_time(from);
+
+        self.arrival_at(start, from, to, latency)
+    }
+
+    /// Like `arrival`, for a packet leaving at `start`
+    fn arrival_at(&mut self, start: u64, from: Option<Addr>, to: Addr, latency: u64) -> Option<u64> {
+        let delay = self.explore.as_mut().map(|x| x.delay(from)).unwrap_or(0);
+
+        self.arrival_in_scenario(start, from, to, latency)
+            .map(|time| time + delay)
+    }
+
+    fn arrival_in_scenario(&mut self, start: u64, from: Option<Addr>, to: Addr, latency: u64) -> Option<u64> {
+        let mut time = start;
+
+        let scenario = match self.scenario {
+            Some(ref scenario) => scenario,
+            None => return Some(time + latency)
+        };
+        let rng = &mut self.rng;
+
+        for addr in from.iter().chain(Some(&to)) {
+            if let Some(node) = scenario.node_by_ip(addr.ip()) {
+                time = cmp::max(time, node.join);
+            }
+        }
+
+        let latency = match from {
+            Some(from) => {
+                if scenario.crashed(from.ip()).map(|x| x <= time).unwrap_or(false) {
+                    return None;
+                }
+
+                if let Some(until) = scenario.partitioned(from.ip(), to.ip(), time) {
+                    time = until;
+                }
+
+                scenario.latency(from.ip(), to.ip())
+                    .map(|x| x.latency + rng.below(x.jitter + 1))
+                    .unwrap_or(latency)
+            },
+            None => latency
+        };
+
+        if scenario.crashed(to.ip()).map(|x| x <= time + latency).unwrap_or(false) {
+            return None;
+        }
+
+        Some(time + latency)
+    }
+
+    /// Simulated time of a node, which runs ahead of the network while its CPU is busy
+    fn node_time(&self, node: Option<Addr>) -> u64 {
+        node.and_then(|x| self.busy.get(&x))
+            .map(|x| cmp::max(*x, self.timer))
+            .unwrap_or(self.timer)
+    }
+
+    /// Keep the CPU of a node busy with handling packets, bytes and `epoll_wait` wakeups
+    fn process(&mut self, node: Option<Addr>, packets: u64, bytes: u64, wakeups: u64) {
+        let node = match node {
+            Some(node) => node,
+            None => return
+        };
+
+        let cost = match self.scenario {
+            Some(ref scenario) => scenario.cpu_of(node.ip()).cost(packets, bytes, wakeups),
+            None => DEFAULT_CPU.cost(packets, bytes, wakeups)
+        };
+
+        let time = self.node_time(Some(node)) + cost;
+        self.busy.insert(node, time);
+    }
+
+    /// Set the node whose file descriptor is handled right now
+    fn set_current(&mut self, fd: Fd) {
+        if let Some(addr) = self.owner.get(&fd) {
+            self.current = Some(*addr);
+        }
+    }
+
+    /// The node which is running right now
+    pub fn current(&self) -> Option<Addr> {
+        self.current
+    }
+
+    /// Switch the running node, used when several processes share a single state
+    pub fn set_current_node(&mut self, node: Option<Addr>) {
+        self.current = node;
+    }
+
+    /// Record a log entry and write it to the trace file
+    ///
+    /// Packets are passed through the dissector of the receiving stream, every frame
+    /// completed by this packet is appended to the trace line.
+    fn log(&mut self, log: Log) {
+        let time = self.timer;
+        self.log_at(time, log);
+    }
+
+    fn log_at(&mut self, time: u64, log: Log) {
+        if let Some(ref trace) = self.trace {
+            let line = trace_line(&mut self.dissector, time, &log);
+
+            writeln!(trace.lock().unwrap(), "{}", line).unwrap();
+        }
+
+        self.logs.push((time, log));
+    }
+
+    /// Store the output lines of the application in the trace
+    pub fn log_output(&mut self, lines: Vec<Line>) {
+        for line in lines {
+            self.log_at(line.time, Log::Output(line.node, line.fd, line.text));
+        }
+    }
+
+    /// Annotated trace of all logs recorded so far
+    pub fn trace(&self) -> Vec<String> {
+        let mut dissector = Dissector::new();
+
+        self.logs.iter()
+            .map(|(time, log)| trace_line(&mut dissector, *time, log))
+            .collect()
+    }
+
     pub fn add_node(&mut self, fd: Fd, addr: Addr) {
         //println!(" ===> a new node was created with addr {} ({})", addr, fd);
 
-        self.logs.push(Log::AddNode(addr.clone(), 200));
+        self.owner.insert(fd, addr);
+        self.current = Some(addr);
+        self.listeners.insert(fd, addr);
+
+        // sockets sharing a port with `SO_REUSEPORT` belong to the same node
+        if self.nodes.contains_key(&addr) {
+            return;
+        }
+
+        self.log(Log::AddNode(addr, 200));
         self.nodes.insert(
-            addr.clone(), 
+            addr,
             Node { fd, addr, latency: 200 }
         );
     }
 
+    /// Bind a listening socket, port zero is replaced by a free ephemeral port
+    pub fn bind(&mut self, fd: Fd, addr: Addr) -> Result<Addr, c_int> {
+        let reuse = self.reuse.get(&fd).cloned().unwrap_or_default();
+        let addr = self.ports.bind(fd, addr, reuse, self.timer)?;
+        self.add_node(fd, addr);
+
+        Ok(addr)
+    }
+
+    pub fn set_option(&mut self, fd: Fd, option: SocketOption) {
+        let reuse = self.reuse.entry(fd).or_default();
+
+        match option {
+            SocketOption::ReuseAddr(x) => reuse.addr = x,
+            SocketOption::ReusePort(x) => reuse.port = x,
+            SocketOption::Linger(Some(x)) => {
+                self.linger.insert(fd, x);
+            },
+            SocketOption::Linger(None) => {
+                self.linger.remove(&fd);
+            },
+            SocketOption::KeepAlive(true) if !self.activity.contains_key(&fd) => {
+                let now = self.node_time(self.owner.get(&fd).cloned());
+                self.activity.insert(fd, now);
+                self.options.entry(fd).or_default().set(option);
+            },
+            _ => self.options.entry(fd).or_default().set(option)
+        }
+    }
+
+    /// Value of a simulated option for `getsockopt`, `None` if the kernel keeps it
+    pub fn get_option(&self, fd: Fd, level: c_int, name: c_int) -> Option<c_int> {
+        let reuse = self.reuse.get(&fd).cloned().unwrap_or_default();
+
+        match (level, name) {
+            (SOL_SOCKET, SO_REUSEADDR) => Some(reuse.addr as c_int),
+            (SOL_SOCKET, SO_REUSEPORT) => Some(reuse.port as c_int),
+            _ => self.options(fd).get(level, name)
+        }
+    }
+
+    fn options(&self, fd: Fd) -> Options {
+        self.options.get(&fd).cloned().unwrap_or_default()
+    }
+
+    /// Bound the accept queue of a listening socket
+    pub fn listen(&mut self, fd: Fd, backlog: c_int) {
+        self.backlogs.listen(fd, backlog);
+    }
+
+    /// Shut down the sending side of a connection, the peer reads the end of the stream after
+    /// the packets already sent
+    pub fn shutdown(&mut self, fd: Fd) -> Result<(), c_int> {
+        if !self.connections.contains_key(&fd) && !self.hung_up.contains_key(&fd) {
+            return Err(ENOTCONN);
+        }
+
+        if self.shut.insert(fd) {
+            self.send_control(fd, false);
+        }
+
+        Ok(())
+    }
+
+    /// Send the end of the stream or an abort to the peer of a connection, in order with the
+    /// packets already sent
+    fn send_control(&mut self, fd: Fd, reset: bool) {
+        let dest = match self.connections.get(&fd).cloned() {
+            Some(dest) => dest,
+            None => return
+        };
+
+        let from = self.owner.get(&fd).cloned();
+        self.log(if reset { Log::Reset(dest, from) } else { Log::Fin(dest, from) });
+
+        let start = self.node_time(from);
+        if let Some(time) = self.stream_arrival(start, from, dest) {
+            self.events.push(if reset { Event::Reset(dest) } else { Event::Fin(dest) }, time);
+        }
+    }
+
+    /// Close a connection, with `SO_LINGER` zero the peer sees it aborted
+    fn disconnect(&mut self, fd: Fd) {
+        let abort = self.linger.remove(&fd) == Some(0);
+        if abort {
+            self.send_control(fd, true);
+        } else if !self.shut.contains(&fd) {
+            self.send_control(fd, false);
+        }
+
+        // packets still on the way to the closed socket are lost, as is its connect
+        self.events.remove_fd(fd);
+        self.events.remove_connect(fd);
+
+        if let Some(dest) = self.connections.remove(&fd) {
+            self.connections.remove(&dest);
+            self.hung_up.insert(dest, false);
+        }
+
+        self.shut.remove(&fd);
+        self.eof.remove(&fd);
+        self.hung_up.remove(&fd);
+        self.reset.remove(&fd);
+        self.last_delivery.remove(&fd);
+        self.options.remove(&fd);
+        self.acked.remove(&fd);
+        self.held.remove(&fd);
+        self.unread.remove(&fd);
+        self.blocked.remove(&fd);
+        self.activity.remove(&fd);
+        self.adversary.close(fd);
+        self.dissector.reset(fd);
+    }
+
+    /// Forget a closed socket, a node without listening sockets can't be reached anymore
+    pub fn close(&mut self, fd: Fd) {
+        self.reuse.remove(&fd);
+        self.backlogs.close(fd);
+        self.disconnect(fd);
+
+        let addr = match self.ports.release(fd, self.timer) {
+            Some(addr) => addr,
+            None => return
+        };
+
+        self.listeners.remove(&fd);
+        match self.ports.sockets(addr).first() {
+            Some(other) => if let Some(node) = self.nodes.get_mut(&addr) {
+                node.fd = *other;
+            },
+            None => {
+                self.nodes.remove(&addr);
+            }
+        }
+    }
+
     pub fn add_epoll_fd(&mut self, fd: Fd, events: c_uint, id: EpollId) {
-        self.epoll.push((fd, events as i32, id));
+        self.epoll.insert(fd, (events as i32, id));
     }
 
     pub fn find_epoll_fd(&self, fd: Fd) -> Option<(c_int, EpollId)> {
-        self.epoll.iter().filter_map(|x| {
-            if x.0 == fd {
-                Some((x.1, x.2))
-            } else {
-                None
-            }
-        }).next()
+        self.epoll.get(&fd).cloned()
     }
 
     pub fn addr_by_fd(&self, fd: Fd) -> Addr {
-        self.nodes.values().filter_map(|x| {
-            if x.fd == fd {
-                Some(x.addr.clone())
-            } else {
-                None
+        self.listeners[&fd]
+    }
+
+    /// Route a connection through NAT boxes and firewalls
+    ///
+    /// Returns the node receiving the connection and the source address it sees, or `None`
+    /// if the connection is refused.
+    fn route(&mut self, from: Option<Addr>, to: Addr) -> Option<(Addr, Option<Addr>)> {
+        let scenario = match self.scenario {
+            Some(ref scenario) => scenario,
+            None => return Some((to, from)).filter(|_| self.nodes.contains_key(&to))
+        };
+
+        // leaving the range of a NAT box maps the source to a public address
+        let source = match from {
+            Some(from) => match scenario.nat_of(from.ip()) {
+                Some(nat) if !scenario.same_range(from.ip(), to.ip()) => Some(self.nat.outbound(nat, from, to)),
+                _ => Some(from)
+            },
+            None => None
+        };
+
+        let target = match scenario.nat_by_public(to.ip()) {
+            Some(nat) => self.nat.inbound(nat, source, to)?,
+            None => {
+                // private addresses are not reachable from outside of the NAT
+                let outside = from.map(|from| !scenario.same_range(from.ip(), to.ip())).unwrap_or(true);
+                if scenario.nat_of(to.ip()).is_some() && outside {
+                    return None;
+                }
+
+                to
             }
-        }).next().unwrap()
+        };
+
+        if !self.nodes.contains_key(&target) || scenario.firewalled(from.as_ref().map(|x| x.ip()), target.ip()) {
+            return None;
+        }
+
+        Some((target, source))
     }
 
     pub fn connect_to_node(&mut self, fd: Fd, addr: Addr) {
         //println!(" ===> try to connect to addr {} ({})", addr, fd);
 
-        let (latency, to_fd) = self.nodes.get(&addr)
+        // the connecting socket belongs to the node which is currently running
+        let from = self.current;
+        if let Some(from) = from {
+            self.owner.insert(fd, from);
+        }
+
+        let (target, source) = match self.route(from, addr) {
+            Some(route) => route,
+            None => {
+                // the connect fails on the next operation or SO_ERROR
+                self.errors.insert(fd, ECONNREFUSED);
+                self.epoll_notify.push_back((fd, EPOLLOUT | EPOLLERR | EPOLLHUP));
+                self.log(Log::Refused(fd, addr));
+
+                return;
+            }
+        };
+
+        let (latency, to_fd) = self.nodes.get(&target)
             .map(|x| (x.latency, x.fd)).unwrap();
+        let to_fd = self.ports.listener(target).unwrap_or(to_fd);
+
+        self.peers.insert(fd, addr);
+        if let Some(source) = source {
+            self.sources.insert(fd, source);
+        }
 
         // push event with file descriptors (later used by accept)
-        self.events.push(Event::Connect(fd, to_fd), Reverse(self.timer + latency));
-        self.logs.push(Log::Connect(fd, addr));
+        if let Some(time) = self.arrival(from, target, latency) {
+            self.events.push(Event::Connect(fd, to_fd), time);
+        }
+
+        self.log(Log::Connect(fd, addr));
     }
 
-    pub fn accept(&mut self, fd: Fd) -> Option<Fd> {
+    /// Accept the next connection of a listener, `new_fd` creates the file descriptor of it
+    pub fn accept<F: FnOnce() -> Fd>(&mut self, fd: Fd, new_fd: F) -> Option<Fd> {
         self.find_connect_event(fd).map(|(origin, dest)| {
             //println!(" ===> accept connect from {} to {}", origin, dest);
 
             // create a new valid Fd (pseudo connection)
-            let new_fd = unsafe { socket(AF_INET, 524289, 0) };
+            let new_fd = new_fd();
+            self.ports.accepted(dest);
+            self.backlogs.accepted(dest, origin);
+
+            // accepted sockets inherit the options of the listener
+            if let Some(options) = self.options.get(&dest).cloned() {
+                self.options.insert(new_fd, options);
+            }
+
+            self.activity.insert(origin, self.timer);
+            self.activity.insert(new_fd, self.timer);
 
             // add the new connection
             self.connections.insert(origin, new_fd);
             self.connections.insert(new_fd, origin);
 
+            if let Some(addr) = self.owner.get(&dest).cloned() {
+                self.owner.insert(new_fd, addr);
+            }
+
+            if let Some(source) = self.sources.remove(&origin) {
+                self.peers.insert(new_fd, source);
+            }
+
             self.epoll_notify.push_back((origin, EPOLLOUT));
             self.epoll_notify.push_back((new_fd, EPOLLOUT));
 
-            // increase timer
-            self.timer += 200;
-            
+            let node = self.owner.get(&dest).cloned();
+            self.process(node, 1, 0, 0);
+
             new_fd
         })
+    }
+
+    /// Resolve a host name through the simulated DNS of the scenario
+    ///
+    /// Names missing in the scenario don't exist, without a scenario there is no simulated
+    /// DNS at all and `None` is returned. Resolving keeps the running node busy for the delay
+    /// of the host, the other nodes and the queued events go on in the meantime.
+    pub fn resolve(&mut self, name: &str) -> Option<Result<Vec<Ipv4Addr>, DnsError>> {
+        let (res, delay) = match self.scenario.as_ref()?.host(name) {
+            Some(host) => match host.error {
+                Some(err) => (Err(err), host.delay),
+                None => (Ok(host.addrs.clone()), host.delay)
+            },
+            None => (Err(DnsError::Nxdomain), 0)
+        };
+
+        let node = self.current;
+        let time = self.node_time(node) + delay;
+        if let Some(node) = node {
+            self.busy.insert(node, time);
+        }
+
+        self.log_at(time, Log::Resolve(name.to_string(), res.clone()));
+
+        Some(res)
+    }
+
+    /// Seed of the scenario, zero without one
+    pub fn seed(&self) -> u64 {
+        self.scenario.as_ref().map(|x| x.seed).unwrap_or(0)
+    }
+
+    /// Clock of the running node in microseconds, `None` if the nodes read the host clock
+    ///
+    /// The nodes of a process share its runtime and timers, so monotonic clocks never go back
+    /// even if the next node is busy till an earlier time or its clock drifts slower.
+    pub fn clock(&mut self, kind: clock::Kind) -> Option<u64> {
+        let scenario = self.scenario.as_ref().filter(|x| x.simulated_clocks())?;
+        let clock = self.current.and_then(|x| scenario.clock_of(x.ip()));
+
+        let time = clock::read(kind, self.node_time(self.current), self.epoch, clock);
+        if kind == clock::Kind::Monotonic {
+            self.monotonic = cmp::max(self.monotonic, time);
+            return Some(self.monotonic);
+        }
+
+        Some(time)
+    }
+
+    /// Let the simulated time pass while all nodes are waiting
+    ///
+    /// With simulated clocks the timers of the nodes only expire if the time moves on. When
+    /// nothing happens within `micros`, the time jumps forward and true is returned.
+    pub fn idle(&mut self, micros: u64) -> bool {
+        if !self.scenario.as_ref().map(|x| x.simulated_clocks()).unwrap_or(false) {
+            return false;
+        }
+
+        let deadline = self.timer + micros;
+        if self.events.peek().map(|x| x.1 <= deadline).unwrap_or(false) {
+            return false;
+        }
+
+        if let Some(ref pace) = self.pace {
+            if pace.release(deadline) > clock::real(Instant::now) {
+                return false;
+            }
         }
 
+        self.advance(deadline);
+
+        true
+    }
+
+    /// Local address of a socket, nodes behind a NAT see their private address
     pub fn get_sockname(&self, fd: Fd) -> Addr {
-        self.addr_by_fd(fd)
+        match self.owner.get(&fd) {
+            Some(addr) if !self.listeners.contains_key(&fd) => *addr,
+            _ => self.addr_by_fd(fd)
+        }
+    }
+
+    /// Remote address of a connection, as rewritten by NAT boxes on the way
+    pub fn get_peername(&self, fd: Fd) -> Option<Addr> {
+        self.peers.get(&fd).cloned()
     }
 
-    pub fn recv_from(&mut self, fd: Fd) -> Option<Vec<u8>> {
+    /// Take the pending error of a socket, like `SO_ERROR`
+    pub fn take_error(&mut self, fd: Fd) -> Option<c_int> {
+        self.errors.remove(&fd)
+    }
+
+    /// Receive the next packet of a connection, `EWOULDBLOCK` if there is none
+    pub fn recv_from(&mut self, fd: Fd) -> Result<Vec<u8>, c_int> {
+        if let Some(err) = self.take_error(fd) {
+            return Err(err);
+        }
+
         //println!("RECV! {}", fd);
-        self.find_send_event(fd).map(|buf| {
-            self.timer += 200;
-            //println!(" ===> recv packets in {} {:?}", fd, buf);
+        let event = match self.find_send_event(fd) {
+            Some(event) => event,
+            // after the end of the stream or an abort was read, reads return the end again
+            None if self.eof.contains(&fd) || self.reset.contains(&fd) => return Ok(Vec::new()),
+            None => return Err(EWOULDBLOCK)
+        };
 
-            buf
-        })
+        let node = self.owner.get(&fd).cloned();
+        self.activity.insert(fd, self.timer);
+
+        match event {
+            Event::SendPacket(_, buf) => {
+                self.process(node, 1, buf.len() as u64, 0);
+                //println!(" ===> recv packets in {} {:?}", fd, buf);
+
+                if let Some(node) = node {
+                    let counters = self.counters.entry(node).or_default();
+                    counters.received_messages += 1;
+                    counters.received_bytes += buf.len() as u64;
+                }
+
+                if let Some(unread) = self.unread.get_mut(&fd) {
+                    *unread = unread.saturating_sub(buf.len());
+                }
+
+                // the buffers have room again
+                if let Some(sender) = self.connections.get(&fd).cloned() {
+                    if self.blocked.remove(&sender) {
+                        self.epoll_notify.push_back((sender, EPOLLOUT));
+                    }
+                }
+
+                Ok(buf)
+            },
+            Event::Reset(_) => {
+                self.process(node, 1, 0, 0);
+                self.abort(fd);
+
+                Err(ECONNRESET)
+            },
+            _ => {
+                self.process(node, 1, 0, 0);
+                self.eof.insert(fd);
+
+                Ok(Vec::new())
+            }
+        }
+    }
+
+    /// Drop a connection aborted by the peer, the data not yet read is lost
+    fn abort(&mut self, fd: Fd) {
+        self.events.remove_fd(fd);
+        self.unread.remove(&fd);
+        self.hung_up.remove(&fd);
+        self.reset.insert(fd);
+
+        if let Some(dest) = self.connections.remove(&fd) {
+            self.connections.remove(&dest);
+        }
     }
 
-    pub fn send_to(&mut self, fd: Fd, buf: &[u8]) -> Option<()> {
-        if let Some(dest) = self.connections.get(&fd) {
-            //println!(" ===> send packet from {} to {} {:?}", fd, dest, buf);
+    pub fn send_to(&mut self, fd: Fd, buf: &[u8]) -> Result<(), c_int> {
+        if let Some(err) = self.take_error(fd) {
+            return Err(err);
+        }
+
+        // an abort which arrived is reported first
+        let now = self.node_time(self.owner.get(&fd).cloned());
+        let reset = match self.events.peek_due(fd) {
+            Some((Event::Reset(_), time)) => time <= now,
+            _ => false
+        };
 
-            self.logs.push(Log::SendPacket(*dest, buf.into()));
+        if reset {
+            self.find_send_event(fd);
+            self.abort(fd);
+
+            return Err(ECONNRESET);
+        }
+
+        if self.shut.contains(&fd) || self.reset.contains(&fd) {
+            return Err(EPIPE);
+        }
+
+        // the closed peer answers with an abort after a round trip, the packet is lost
+        if let Some(answered) = self.hung_up.get(&fd).cloned() {
+            if !answered {
+                let from = self.owner.get(&fd).cloned();
+                let now = self.node_time(from);
+                let there = match self.peers.get(&fd).cloned() {
+                    Some(to) => self.arrival(from, to, 200).unwrap_or(now + 200),
+                    None => now + 200
+                };
+
+                self.events.push(Event::Reset(fd), there + (there - now));
+                self.hung_up.insert(fd, true);
+            }
 
-            // notify fd to be writable again
             self.epoll_notify.push_back((fd, EPOLLOUT));
 
-            self.events.push(Event::SendPacket(*dest, buf.into()), Reverse(self.timer+200));
+            return Ok(());
+        }
 
-            return Some(());
-        } else {
-            return None;
+        let dest = match self.connections.get(&fd).cloned() {
+            Some(dest) => dest,
+            None => return Err(ENOTCONN)
+        };
+
+        //println!(" ===> send packet from {} to {} {:?}", fd, dest, buf);
+
+        // data the peer didn't read yet fills the send buffer and the receive buffer of the
+        // peer, a single write always fits into empty buffers
+        let unread = self.unread.get(&dest).cloned().unwrap_or(0);
+        let capacity = (self.options(fd).sndbuf + self.options(dest).rcvbuf) as usize;
+        if unread > 0 && unread + buf.len() > capacity {
+            self.blocked.insert(fd);
+
+            return Err(EWOULDBLOCK);
+        }
+
+        // notify fd to be writable again
+        self.epoll_notify.push_back((fd, EPOLLOUT));
+
+        // adversarial nodes send mutated frames instead
+        let mutated = self.mutate(fd, buf);
+        let buf = match mutated {
+            Some(ref buf) if buf.is_empty() => return Ok(()),
+            Some(ref buf) => buf.as_slice(),
+            None => buf
+        };
+
+        let from = self.owner.get(&fd).cloned();
+        let now = self.node_time(from);
+        self.log(Log::SendPacket(dest, from, buf.into()));
+
+        if let Some(from) = from {
+            let counters = self.counters.entry(from).or_default();
+            counters.sent_messages += 1;
+            counters.sent_bytes += buf.len() as u64;
         }
+
+        self.unread.insert(dest, unread + buf.len());
+        self.activity.insert(fd, now);
+
+        // Nagle's algorithm: a small write waits till the data on the way was acknowledged,
+        // writes meanwhile join it
+        if !self.options(fd).nodelay {
+            if let Some((key, departure)) = self.held.get(&fd).cloned() {
+                if departure > now && self.events.append(key, buf) {
+                    return Ok(());
+                }
+            }
+
+            let acked = self.acked.get(&fd).cloned().unwrap_or(0);
+            if acked > now && buf.len() < MSS {
+                if let Some(time) = self.stream_arrival(acked, from, dest) {
+                    let key = self.events.push(Event::SendPacket(dest, buf.into()), time);
+                    self.held.insert(fd, (key, acked));
+                    self.acked.insert(fd, time + (time - acked));
+                }
+
+                return Ok(());
+            }
+        }
+
+        if let Some(time) = self.stream_arrival(now, from, dest) {
+            self.events.push(Event::SendPacket(dest, buf.into()), time);
+
+            // the acknowledgement takes as long back
+            self.acked.insert(fd, time + (time - now));
+        }
+
+        Ok(())
+    }
+
+    /// The bytes an adversarial node sends instead of a write, `None` for honest nodes
+    fn mutate(&mut self, fd: Fd, buf: &[u8]) -> Option<Vec<u8>> {
+        let ip = *self.owner.get(&fd)?.ip();
+        let attacks = self.scenario.as_ref()?.byzantine_of(&ip)?.attacks.clone();
+
+        Some(self.adversary.mutate(fd, &attacks, buf))
+    }
+
+    /// Arrival of the next packet of a stream leaving at `start`, packets of a stream arrive
+    /// in order even with jitter
+    fn stream_arrival(&mut self, start: u64, from: Option<Addr>, dest: Fd) -> Option<u64> {
+        let time = match self.owner.get(&dest).cloned() {
+            Some(to) => self.arrival_at(start, from, to, 200),
+            None => Some(start + 200)
+        }?;
+
+        let time = match self.last_delivery.get(&dest) {
+            Some(last) => cmp::max(time, last + 1),
+            None => time
+        };
+
+        self.last_delivery.insert(dest, time);
+
+        Some(time)
     }
 
     pub fn next_epoll_id(&mut self) -> Option<(EpollId, c_int)> {
+        self.next_epoll_id_where(|_| true)
+    }
+
+    /// Like `next_epoll_id`, but only if the next event is for a file descriptor accepted
+    /// by the filter
+    pub fn next_epoll_id_where<F: Fn(Fd) -> bool>(&mut self, filter: F) -> Option<(EpollId, c_int)> {
         let mut ret = None;
 
+        // a busy node handles the event only after it finished its current work, connects
+        // arriving meanwhile wait in the accept queue
+        self.admit_connects();
+        while let Some((fd, time)) = self.events.peek().map(|(event, time)| (queue::target(event), time)) {
+            match self.owner.get(&fd).and_then(|x| self.busy.get(x)).cloned() {
+                Some(busy) if busy > time => {
+                    self.events.defer(fd, busy);
+                    self.admit_connects();
+                },
+                _ => break
+            }
+        }
+
+        match self.events.peek() {
+            Some((event, _)) if !filter(queue::target(event)) => return None,
+            _ => {}
+        }
+
+        if self.next_release().is_some() {
+            return None;
+        }
+
         // wait till epoll_ctl was called and we have a epoll id
         match self.events.peek() {
             Some((Event::Connect(_, b),_)) => {
@@ -167,24 +1141,88 @@ impl State {
                     ret = Some((id.1, EPOLLIN));
                 }
             },
+            Some((Event::Fin(a),_)) => {
+                if let Some(id) = self.find_epoll_fd(*a) {
+                    ret = Some((id.1, EPOLLIN | EPOLLRDHUP));
+                }
+            },
+            Some((Event::Reset(a),_)) => {
+                if let Some(id) = self.find_epoll_fd(*a) {
+                    ret = Some((id.1, EPOLLIN | EPOLLOUT | EPOLLERR | EPOLLHUP | EPOLLRDHUP));
+                }
+            },
             None => {}
         }
 
-        if let Some((id, _)) = ret {
-            //println!(" ===> wake up {} with events {}", id, self.events().join(","));
+        match self.events.peek().map(|(event, time)| (queue::target(event), time)) {
+            Some((fd, time)) if ret.is_some() => {
+                self.set_current(fd);
+
+                let node = self.owner.get(&fd).cloned();
+                self.process(node, 0, 0, 1);
+
+                if let Some(ref mut pace) = self.pace {
+                    pace.released(time);
+                }
+            },
+            _ => {}
         }
 
         ret
     }
 
+    /// Take the delivered event right away if it is the end of a stream or an abort
+    ///
+    /// Both carry no data and the reading half of the socket may already be gone, so nobody
+    /// would consume them. Returns if the event was taken, the next read or write reports it.
+    pub fn take_hang_up(&mut self) -> bool {
+        let fd = match self.events.peek() {
+            Some((Event::Fin(fd), _)) | Some((Event::Reset(fd), _)) => *fd,
+            _ => return false
+        };
+
+        let node = self.owner.get(&fd).cloned();
+        self.process(node, 1, 0, 0);
+
+        match self.find_send_event(fd) {
+            Some(Event::Reset(_)) => {
+                self.abort(fd);
+                self.errors.insert(fd, ECONNRESET);
+            },
+            _ => {
+                self.eof.insert(fd);
+            }
+        }
+
+        true
+    }
+
+    /// Wall clock time till which the next event is held back in paced mode
+    pub fn next_release(&self) -> Option<Instant> {
+        let release = self.pace.as_ref()?.release(self.events.peek()?.1);
+
+        if release > clock::real(Instant::now) {
+            Some(release)
+        } else {
+            None
+        }
+    }
+
     pub fn next_epoll_notify(&mut self) -> Option<(EpollId, c_int)> {
-        let res = self.epoll_notify.front().and_then(|x| {
-            self.find_epoll_fd(x.0).map(|y| (y.1, x.1))
-        });
+        self.next_epoll_notify_where(|_| true)
+    }
+
+    /// Like `next_epoll_notify`, but only for file descriptors accepted by the filter
+    pub fn next_epoll_notify_where<F: Fn(Fd) -> bool>(&mut self, filter: F) -> Option<(EpollId, c_int)> {
+        let pos = self.epoll_notify.iter().position(|x| filter(x.0))?;
+        let (fd, events) = self.epoll_notify[pos];
+
+        let res = self.find_epoll_fd(fd).map(|y| (y.1, events));
 
         if res.is_some() {
         //println!("{:?}", self.epoll_notify);
-            self.epoll_notify.pop_front();
+            self.epoll_notify.remove(pos);
+            self.set_current(fd);
         }
 
         res
@@ -194,10 +1232,78 @@ impl State {
         self.epoll_notify.clone()
     }
 
+    pub fn time(&self) -> u64 {
+        self.timer
+    }
+
+    /// Progress and traffic of the simulation so far
+    pub fn metrics(&self) -> Metrics {
+        let mut connections = BTreeMap::new();
+        for fd in self.connections.keys() {
+            if let Some(node) = self.owner.get(fd) {
+                *connections.entry(*node).or_insert(0) += 1;
+            }
+        }
+
+        Metrics {
+            time: self.timer,
+            queued: self.events.len(),
+            delivered: self.delivered,
+            nodes: self.counters.clone(),
+            connections
+        }
+    }
+
+    /// The event which will be delivered next
+    pub fn next_event(&self) -> Option<(Event, u64)> {
+        self.events.peek().map(|(event, time)| (event.clone(), time))
+    }
+
+    /// All queued events ordered by their delivery time
+    pub fn pending_events(&self) -> Vec<(Event, u64)> {
+        self.events.iter()
+            .map(|(event, time)| (event.clone(), time))
+            .collect()
+    }
+
+    pub fn connections(&self) -> Vec<(Fd, Fd)> {
+        let mut connections = self.connections.iter()
+            .map(|(a, b)| (*a, *b))
+            .collect::<Vec<(Fd, Fd)>>();
+        connections.sort();
+
+        connections
+    }
+
+    pub fn listeners(&self) -> Vec<(Addr, Fd)> {
+        let mut listeners = self.listeners.iter()
+            .map(|(fd, addr)| (*addr, *fd))
+            .collect::<Vec<(Addr, Fd)>>();
+        listeners.sort_by_key(|x| x.1);
+
+        listeners
+    }
+
+    pub fn epoll_registrations(&self) -> Vec<(Fd, c_int, EpollId)> {
+        let mut epoll = self.epoll.iter()
+            .map(|(fd, (events, id))| (*fd, *events, *id))
+            .collect::<Vec<(Fd, c_int, EpollId)>>();
+        epoll.sort();
+
+        epoll
+    }
+
+    /// The node a file descriptor belongs to
+    pub fn owner(&self, fd: Fd) -> Option<Addr> {
+        self.owner.get(&fd).cloned()
+    }
+
     pub fn events(&self) -> Vec<String> {
-        self.events.clone().into_sorted_iter().map(|(x, time)| match x {
-            Event::SendPacket(x,_) => format!("send_packet({},{})", x, time.0),
-            Event::Connect(a,b) => format!("connect({},{})", a, b)
+        self.events.iter().map(|(x, time)| match x {
+            Event::SendPacket(x,_) => format!("send_packet({},{})", x, time),
+            Event::Connect(a,b) => format!("connect({},{})", a, b),
+            Event::Fin(x) => format!("fin({},{})", x, time),
+            Event::Reset(x) => format!("reset({},{})", x, time)
         }).collect::<Vec<String>>()
     }
 
@@ -205,80 +1311,158 @@ impl State {
         self.events.is_empty()
     }
 
-    pub fn find_connect_event(&mut self, fd: Fd) -> Option<(Fd, Fd)> {
-        let mut res = None;
-        let mut removed = Vec::new();
-        let mut first_time = match self.events.peek() {
-            Some(x) => (x.1).0,
-            None => return None
-        };
+    /// Move the clock forward, keepalive probes which fell due meanwhile are sent
+    fn jump(&mut self, time: u64) {
+        self.timer = cmp::max(self.timer, time);
+        self.probe_connections();
+    }
 
-        'outer: loop {
-            if let Some((first, time)) = self.events.pop() {
-                match first {
-                    Event::Connect(a, b) => {
-                        if first_time == time.0 && b == fd {
-                            res = Some((a, b));
-
-                            break 'outer;
-                        }
-                    },
-                    _ => {}
-                }
+    /// Send the keepalive probes of idle connections up to the current time
+    ///
+    /// A probe is answered unless the node of the peer crashed or a partition lies between
+    /// them, an answer counts as traffic. After `keep_count` unanswered probes the connection
+    /// fails with `ETIMEDOUT`.
+    fn probe_connections(&mut self) {
+        let mut answered = Vec::new();
+        let mut failed = Vec::new();
+
+        {
+            let scenario = match self.scenario {
+                Some(ref scenario) => scenario,
+                None => return
+            };
+
+            for (fd, options) in self.options.iter().filter(|x| x.1.keepalive) {
+                let peer = self.connections.get(fd).and_then(|x| self.owner.get(x));
+                let (from, to) = match (self.owner.get(fd), peer) {
+                    (Some(from), Some(to)) => (from.ip(), to.ip()),
+                    _ => continue
+                };
+
+                let mut last = self.activity.get(fd).cloned().unwrap_or(0);
+                let mut probe = last + options.idle_time();
+                let mut lost = 0;
 
-                removed.push((first, time));
+                while probe <= self.timer {
+                    let dead = scenario.crashed(to).map(|x| x <= probe).unwrap_or(false)
+                        || scenario.partitioned(from, to, probe).is_some();
 
-                if first_time != time.0 {
-                    break;
+                    if !dead {
+                        last = probe;
+                        lost = 0;
+                        probe = last + options.idle_time();
+                    } else if lost + 1 >= options.keep_count {
+                        failed.push((*fd, probe));
+                        break;
+                    } else {
+                        lost += 1;
+                        probe += options.probe_interval();
+                    }
                 }
-            } else {
-                break;
+
+                answered.push((*fd, last));
             }
         }
 
-        for (elm, time) in removed {
-            self.events.push(elm, time);
+        for (fd, last) in answered {
+            self.activity.insert(fd, last);
         }
 
-        res
+        for (fd, time) in failed {
+            // the peer is gone, later reads return the end of the stream
+            let addr = self.peers.get(&fd).cloned().or_else(|| self.owner.get(&fd).cloned());
+            if let Some(addr) = addr {
+                self.log_at(time, Log::TimedOut(fd, addr));
+            }
+
+            self.abort(fd);
+            self.errors.insert(fd, ETIMEDOUT);
+            self.epoll_notify.push_back((fd, EPOLLIN | EPOLLOUT | EPOLLERR | EPOLLHUP));
+        }
     }
 
-    pub fn find_send_event(&mut self, fd: Fd) -> Option<Vec<u8>> {
-        let mut res = None;
-        let mut removed = Vec::new();
-        let mut first_time = match self.events.peek() {
-            Some(x) => (x.1).0,
-            None => return None
-        };
+    /// Let the connects arriving next enter the accept queues of their listeners
+    ///
+    /// Connects beyond the backlog are dropped like their SYN, the connecting node sends it
+    /// again after a growing timeout and gives up with `ETIMEDOUT`. If the scenario says so,
+    /// they are refused at once instead.
+    fn admit_connects(&mut self) {
+        while let Some((origin, listener, time)) = match self.events.peek() {
+            Some((Event::Connect(a, b), time)) => Some((*a, *b, time)),
+            _ => None
+        } {
+            if self.backlogs.arrive(listener, origin) {
+                return;
+            }
 
-        'outer: loop {
-            if let Some((first, time)) = self.events.pop() {
-                match first {
-                    Event::SendPacket(a, ref b) => {
-                        if first_time == time.0 && a == fd {
-                            res = Some(b.clone());
-
-                            break 'outer;
-                        }
-                    },
-                    _ => {}
-                }
+            self.events.pop_due(listener);
 
-                removed.push((first, time));
+            // the address the node connected to
+            let addr = match self.peers.get(&origin) {
+                Some(addr) => *addr,
+                None => continue
+            };
+            self.log_at(time, Log::Overflow(origin, addr));
 
-                if first_time != time.0 {
-                    break;
-                }
-            } else {
-                break;
+            let overflow = self.scenario.as_ref().map(|x| x.overflow).unwrap_or_default();
+            let retry = match overflow {
+                Overflow::Retry => self.backlogs.retry(origin),
+                Overflow::Refuse => None
+            };
+
+            if let Some(delay) = retry {
+                self.events.push(Event::Connect(origin, listener), time + delay);
+                continue;
             }
+
+            let err = match overflow {
+                Overflow::Retry => {
+                    self.log_at(time, Log::TimedOut(origin, addr));
+                    ETIMEDOUT
+                },
+                Overflow::Refuse => ECONNREFUSED
+            };
+
+            // the connect fails on the next operation or SO_ERROR
+            self.errors.insert(origin, err);
+            self.epoll_notify.push_back((origin, EPOLLOUT | EPOLLERR | EPOLLHUP));
         }
+    }
 
-        for (elm, time) in removed {
-            self.events.push(elm, time);
+    pub fn find_connect_event(&mut self, fd: Fd) -> Option<(Fd, Fd)> {
+        self.admit_connects();
+
+        match self.events.peek_due(fd) {
+            Some((Event::Connect(..), _)) => {},
+            _ => return None
         }
 
-        res
+        self.events.pop_due(fd).and_then(|(event, time)| match event {
+            Event::Connect(a, b) => {
+                // the clock jumps to the delivered event
+                self.advance(time);
+                self.delivered += 1;
+
+                Some((a, b))
+            },
+            _ => None
+        })
+    }
+
+    /// Take the next packet, end of the stream or abort which is due for a connection
+    pub fn find_send_event(&mut self, fd: Fd) -> Option<Event> {
+        match self.events.peek_due(fd) {
+            Some((Event::Connect(..), _)) | None => return None,
+            _ => {}
+        }
+
+        self.events.pop_due(fd).map(|(event, time)| {
+            // the clock jumps to the delivered event
+            self.advance(time);
+            self.delivered += 1;
+
+            event
+        })
     }
 
     pub fn get_logs(&self) -> Vec<u8> {
@@ -288,12 +1472,29 @@ impl State {
     }
 }
 
+fn trace_line(dissector: &mut Dissector, time: u64, log: &Log) -> String {
+    let mut line = format!("{:>10} {}", time, log);
+
+    if let Log::SendPacket(fd, _, buf) = log {
+        for frame in dissector.feed(*fd, buf) {
+            line.push_str(&format!(" | {}", frame));
+        }
+
+        let pending = dissector.pending(*fd);
+        if pending > 0 {
+            line.push_str(&format!(" | ({} bytes pending)", pending));
+        }
+    }
+
+    line
+}
+
 pub fn empty_addr() -> sockaddr_in {
     to_sockaddr(SocketAddrV4::new([127,0,0,1].into(), 8000))
 }
 
 pub fn to_sockaddr(addr: Addr) -> sockaddr_in{
-    let ip_addr = u32::from(addr.ip().clone()).to_be();
+    let ip_addr = u32::from(*addr.ip()).to_be();
 
     sockaddr_in {
         sin_family: AF_INET as u16,
@@ -0,0 +1,84 @@
+//! End-to-end test of a tokio application under the preload library
+//!
+//! Uses the library and the `echo` and `scrape` examples built by `cargo test`.
+
+use std::env;
+use std::fs;
+use std::io::Read;
+use std::path::PathBuf;
+use std::process::{self, Command, ExitStatus, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Wall clock time after which a stuck simulation is killed
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Directory of the build, the test itself lives in `deps` below it
+fn build_dir() -> PathBuf {
+    let exe = env::current_exe().unwrap();
+
+    exe.parent().and_then(|x| x.parent()).unwrap().to_path_buf()
+}
+
+/// Run an example under the preload library, returns its exit status and stdout
+fn run(name: &str, envs: &[(&str, &PathBuf)]) -> (ExitStatus, String) {
+    let dir = build_dir();
+    let example = dir.join("examples").join(name);
+    assert!(example.exists(), "build the example with `cargo test` first");
+
+    let trace = dir.join(format!("{}.trace", name));
+    let mut child = Command::new(&example)
+        .env("LD_PRELOAD", dir.join("libpeersim.so"))
+        .env("PEERSIM_TRACE", &trace)
+        .envs(envs.iter().cloned())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    let start = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait().unwrap() {
+            break status;
+        }
+
+        if start.elapsed() > TIMEOUT {
+            let _ = child.kill();
+            panic!("{} is stuck, trace: {}", name, fs::read_to_string(&trace).unwrap_or_default());
+        }
+
+        thread::sleep(Duration::from_millis(10));
+    };
+
+    let mut stdout = String::new();
+    child.stdout.take().unwrap().read_to_string(&mut stdout).unwrap();
+
+    (status, stdout)
+}
+
+#[test]
+fn tokio_echo() {
+    let (status, stdout) = run("echo", &[]);
+
+    assert!(status.success(), "echo failed: {}", stdout);
+    assert!(stdout.contains("echo hello simulator"), "unexpected output: {}", stdout);
+
+    // both directions went through the simulated network
+    let trace = fs::read_to_string(build_dir().join("echo.trace")).unwrap();
+    assert!(trace.contains("add_node 127.0.0.1:7000"), "{}", trace);
+    assert_eq!(trace.matches("send_packet").count(), 2, "{}", trace);
+}
+
+#[test]
+fn scraped_fds_are_simulated_again() {
+    let socket = env::temp_dir().join(format!("peersim-metrics-{}", process::id()));
+    let (status, stdout) = run("scrape", &[("PEERSIM_METRICS", &socket)]);
+    let _ = fs::remove_file(&socket);
+
+    assert!(status.success(), "scrape failed: {}", stdout);
+    assert!(stdout.contains("scraped HTTP/1.1 200 OK"), "unexpected output: {}", stdout);
+    assert!(stdout.contains("echo hello simulator"), "unexpected output: {}", stdout);
+
+    // the connection reusing the file descriptors of the scrape was simulated
+    let trace = fs::read_to_string(build_dir().join("scrape.trace")).unwrap();
+    assert_eq!(trace.matches("send_packet").count(), 2, "{}", trace);
+}
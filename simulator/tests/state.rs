@@ -0,0 +1,907 @@
+//! Tests of the simulated network, driving `State` directly without the preload library
+
+// the modules are shared with the preload library and only partly used here
+#![allow(dead_code, unused_variables)]
+
+extern crate libc;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate bincode;
+extern crate scenario;
+
+#[path = "../src/state.rs"]
+mod state;
+#[path = "../src/dissect.rs"]
+mod dissect;
+#[path = "../src/rng.rs"]
+mod rng;
+#[path = "../src/debugger.rs"]
+mod debugger;
+#[path = "../src/checkpoint.rs"]
+mod checkpoint;
+#[path = "../src/explore.rs"]
+mod explore;
+#[path = "../src/queue.rs"]
+mod queue;
+#[path = "../src/pace.rs"]
+mod pace;
+#[path = "../src/nat.rs"]
+mod nat;
+#[path = "../src/clock.rs"]
+mod clock;
+#[path = "../src/output.rs"]
+mod output;
+#[path = "../src/ports.rs"]
+mod ports;
+#[path = "../src/backlog.rs"]
+mod backlog;
+#[path = "../src/options.rs"]
+mod options;
+#[path = "../src/byzantine.rs"]
+mod byzantine;
+#[path = "../src/metrics.rs"]
+mod metrics;
+
+use std::env;
+use std::fs;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::process::{self, Command};
+
+use libc::{EPOLLIN, EPOLLOUT, EPOLLERR, EPOLLHUP, EPOLLRDHUP, EWOULDBLOCK, ECONNREFUSED, ENOTCONN, EADDRINUSE, ECONNRESET, EPIPE, ETIMEDOUT, SOL_SOCKET, SO_SNDBUF, SO_RCVBUF,
+    SO_KEEPALIVE, SO_REUSEADDR, SO_TYPE, IPPROTO_TCP, TCP_NODELAY, TCP_KEEPIDLE, TCP_KEEPCNT};
+
+use scenario::{regions, Scenario};
+use state::{Addr, Event, Fd, SocketOption, State};
+
+const A: Fd = 3;
+const B: Fd = 4;
+const C: Fd = 5;
+
+fn addr(ip: [u8; 4]) -> Addr {
+    SocketAddrV4::new(Ipv4Addr::from(ip), 8000)
+}
+
+/// Two nodes listening on `A` and `B`, with `B` running
+fn two_nodes() -> State {
+    let mut state = State::with_scenario(None);
+    state.add_node(A, addr([10, 0, 0, 1]));
+    state.add_node(B, addr([10, 0, 0, 2]));
+
+    state
+}
+
+/// Connect from `10` of the running node to the first node and accept it as `11`
+fn connected() -> State {
+    let mut state = two_nodes();
+    state.connect_to_node(10, addr([10, 0, 0, 1]));
+    assert_eq!(state.accept(A, || 11), Some(11));
+
+    // packets leave right away, without Nagle's algorithm holding them back
+    state.set_option(10, SocketOption::NoDelay(true));
+    state.set_option(11, SocketOption::NoDelay(true));
+
+    state
+}
+
+#[test]
+fn add_node_registers_a_listener() {
+    let state = two_nodes();
+
+    assert_eq!(state.listeners(), vec![(addr([10, 0, 0, 1]), A), (addr([10, 0, 0, 2]), B)]);
+    assert_eq!(state.owner(A), Some(addr([10, 0, 0, 1])));
+    assert_eq!(state.current(), Some(addr([10, 0, 0, 2])));
+    assert_eq!(state.get_sockname(B), addr([10, 0, 0, 2]));
+    assert!(state.next_event().is_none());
+}
+
+#[test]
+fn connect_arrives_after_the_latency() {
+    let mut state = two_nodes();
+    state.connect_to_node(10, addr([10, 0, 0, 1]));
+
+    assert_eq!(state.next_event(), Some((Event::Connect(10, A), 200)));
+    assert_eq!(state.owner(10), Some(addr([10, 0, 0, 2])));
+    assert_eq!(state.time(), 0);
+}
+
+#[test]
+fn accept_pairs_both_ends() {
+    let state = connected();
+
+    assert_eq!(state.time(), 200);
+    assert_eq!(state.connections(), vec![(10, 11), (11, 10)]);
+    assert_eq!(state.owner(11), Some(addr([10, 0, 0, 1])));
+    assert_eq!(state.get_peername(11), Some(addr([10, 0, 0, 2])));
+    assert_eq!(state.get_sockname(11), addr([10, 0, 0, 1]));
+    assert!(state.next_event().is_none());
+}
+
+#[test]
+fn accept_without_connect() {
+    let mut state = two_nodes();
+
+    assert_eq!(state.accept(A, || panic!("no connection to accept")), None);
+}
+
+#[test]
+fn accept_only_the_due_listener() {
+    let mut state = two_nodes();
+    state.connect_to_node(10, addr([10, 0, 0, 1]));
+
+    assert_eq!(state.accept(B, || 11), None);
+    assert_eq!(state.accept(A, || 11), Some(11));
+}
+
+#[test]
+fn connects_at_the_same_time_keep_their_order() {
+    let mut state = two_nodes();
+    state.connect_to_node(10, addr([10, 0, 0, 1]));
+    state.connect_to_node(12, addr([10, 0, 0, 1]));
+
+    assert_eq!(state.accept(A, || 11), Some(11));
+    assert_eq!(state.accept(A, || 13), Some(13));
+    assert_eq!(state.connections(), vec![(10, 11), (11, 10), (12, 13), (13, 12)]);
+}
+
+#[test]
+fn refused_connect_reports_an_error() {
+    let mut state = two_nodes();
+    state.add_epoll_fd(10, (EPOLLIN | EPOLLOUT) as u32, 7);
+    state.connect_to_node(10, addr([10, 0, 0, 9]));
+
+    assert!(state.next_event().is_none());
+    assert_eq!(state.next_epoll_notify(), Some((7, EPOLLOUT | EPOLLERR | EPOLLHUP)));
+    assert_eq!(state.recv_from(10), Err(ECONNREFUSED));
+    assert_eq!(state.recv_from(10), Err(EWOULDBLOCK));
+}
+
+#[test]
+fn send_without_connection() {
+    let mut state = two_nodes();
+
+    assert_eq!(state.send_to(10, b"hello"), Err(ENOTCONN));
+}
+
+#[test]
+fn packets_arrive_in_order() {
+    let mut state = connected();
+    state.send_to(10, b"first").unwrap();
+    state.send_to(10, b"second").unwrap();
+
+    // both are sent at 200, the second one is kept behind the first
+    assert_eq!(state.pending_events(), vec![
+        (Event::SendPacket(11, b"first".to_vec()), 400),
+        (Event::SendPacket(11, b"second".to_vec()), 401)
+    ]);
+
+    assert_eq!(state.recv_from(11), Ok(b"first".to_vec()));
+    assert_eq!(state.time(), 400);
+    assert_eq!(state.recv_from(11), Ok(b"second".to_vec()));
+    assert_eq!(state.time(), 401);
+    assert_eq!(state.recv_from(11), Err(EWOULDBLOCK));
+}
+
+#[test]
+fn packets_flow_both_ways() {
+    let mut state = connected();
+    state.send_to(10, b"ping").unwrap();
+    assert_eq!(state.recv_from(11), Ok(b"ping".to_vec()));
+
+    state.send_to(11, b"pong").unwrap();
+    assert_eq!(state.recv_from(10), Ok(b"pong".to_vec()));
+    assert_eq!(state.recv_from(11), Err(EWOULDBLOCK));
+}
+
+#[test]
+fn recv_only_the_next_due_packet() {
+    let mut state = connected();
+    state.send_to(11, b"late").unwrap();
+    state.send_to(10, b"early").unwrap();
+
+    // the first node is busy with the accept, so its packet leaves later
+    assert_eq!(state.recv_from(10), Err(EWOULDBLOCK));
+    assert_eq!(state.recv_from(11), Ok(b"early".to_vec()));
+    assert_eq!(state.recv_from(10), Ok(b"late".to_vec()));
+}
+
+#[test]
+fn busy_node_handles_events_later() {
+    let mut state = connected();
+    state.add_epoll_fd(11, EPOLLIN as u32, 5);
+    state.send_to(10, b"first").unwrap();
+    state.send_to(10, b"second").unwrap();
+
+    // the accept kept the first node busy till 400, handling a packet takes 200
+    assert_eq!(state.next_epoll_id(), Some((5, EPOLLIN)));
+    assert_eq!(state.recv_from(11), Ok(b"first".to_vec()));
+
+    assert_eq!(state.next_epoll_id(), Some((5, EPOLLIN)));
+    assert_eq!(state.next_event(), Some((Event::SendPacket(11, b"second".to_vec()), 600)));
+    assert_eq!(state.recv_from(11), Ok(b"second".to_vec()));
+    assert_eq!(state.time(), 600);
+}
+
+#[test]
+fn epoll_waits_for_the_registration() {
+    let mut state = two_nodes();
+    state.connect_to_node(10, addr([10, 0, 0, 1]));
+
+    assert_eq!(state.next_epoll_id(), None);
+
+    state.add_epoll_fd(A, EPOLLIN as u32, 42);
+    assert_eq!(state.next_epoll_id(), Some((42, EPOLLIN | EPOLLOUT)));
+    assert_eq!(state.current(), Some(addr([10, 0, 0, 1])));
+}
+
+#[test]
+fn epoll_filter_skips_other_processes() {
+    let mut state = two_nodes();
+    state.add_epoll_fd(A, EPOLLIN as u32, 42);
+    state.connect_to_node(10, addr([10, 0, 0, 1]));
+
+    assert_eq!(state.next_epoll_id_where(|fd| fd != A), None);
+    assert_eq!(state.next_epoll_id_where(|fd| fd == A), Some((42, EPOLLIN | EPOLLOUT)));
+}
+
+#[test]
+fn epoll_notifies_writable_connections() {
+    let mut state = connected();
+    state.add_epoll_fd(10, (EPOLLIN | EPOLLOUT) as u32, 1);
+    state.add_epoll_fd(11, (EPOLLIN | EPOLLOUT) as u32, 2);
+
+    assert_eq!(state.next_epoll_notify(), Some((1, EPOLLOUT)));
+    assert_eq!(state.current(), Some(addr([10, 0, 0, 2])));
+    assert_eq!(state.next_epoll_notify(), Some((2, EPOLLOUT)));
+    assert_eq!(state.current(), Some(addr([10, 0, 0, 1])));
+    assert_eq!(state.next_epoll_notify(), None);
+
+    state.send_to(10, b"hello").unwrap();
+    assert_eq!(state.next_epoll_notify(), Some((1, EPOLLOUT)));
+}
+
+#[test]
+fn epoll_notify_waits_for_the_registration() {
+    let mut state = connected();
+
+    assert_eq!(state.next_epoll_notify(), None);
+    assert_eq!(state.get_epoll().len(), 2);
+
+    state.add_epoll_fd(10, EPOLLOUT as u32, 1);
+    assert_eq!(state.next_epoll_notify(), Some((1, EPOLLOUT)));
+    assert_eq!(state.next_epoll_notify_where(|fd| fd != 11), None);
+}
+
+#[test]
+fn trace_shows_the_sending_node() {
+    let mut state = connected();
+    state.send_to(10, b"hello").unwrap();
+
+    let trace = state.trace();
+    assert_eq!(trace.len(), 4);
+    assert_eq!(trace[0].trim(), "0 add_node 10.0.0.1:8000 latency 200");
+    assert_eq!(trace[2].trim(), "0 connect 10 10.0.0.1:8000");
+    assert!(trace[3].trim().starts_with("200 send_packet 11 5 bytes from 10.0.0.2:8000"));
+}
+
+#[test]
+fn bind_twice_is_refused() {
+    let mut state = State::with_scenario(None);
+
+    assert_eq!(state.bind(A, addr([10, 0, 0, 1])), Ok(addr([10, 0, 0, 1])));
+    assert_eq!(state.bind(B, addr([10, 0, 0, 1])), Err(EADDRINUSE));
+    assert_eq!(state.bind(B, SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 8001)),
+        Ok(SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 8001)));
+    assert_eq!(state.bind(C, addr([0, 0, 0, 0])), Err(EADDRINUSE));
+}
+
+#[test]
+fn bind_port_zero_picks_an_ephemeral_port() {
+    let mut state = State::with_scenario(None);
+    let any = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 0);
+
+    assert_eq!(state.bind(A, any).map(|x| x.port()), Ok(32768));
+    assert_eq!(state.bind(B, any).map(|x| x.port()), Ok(32769));
+    assert_eq!(state.get_sockname(B).port(), 32769);
+}
+
+#[test]
+fn reuseport_spreads_connections() {
+    let mut state = State::with_scenario(None);
+    state.set_option(A, SocketOption::ReusePort(true));
+    state.set_option(C, SocketOption::ReusePort(true));
+    state.bind(A, addr([10, 0, 0, 1])).unwrap();
+    state.bind(C, addr([10, 0, 0, 1])).unwrap();
+    state.bind(B, addr([10, 0, 0, 2])).unwrap();
+
+    state.connect_to_node(10, addr([10, 0, 0, 1]));
+    state.connect_to_node(12, addr([10, 0, 0, 1]));
+
+    assert_eq!(state.accept(A, || 11), Some(11));
+    assert_eq!(state.accept(C, || 13), Some(13));
+}
+
+#[test]
+fn closed_listener_lingers_in_time_wait() {
+    let mut state = State::with_scenario(None);
+    state.bind(A, addr([10, 0, 0, 1])).unwrap();
+    state.bind(B, addr([10, 0, 0, 2])).unwrap();
+    state.connect_to_node(10, addr([10, 0, 0, 1]));
+    state.accept(A, || 11).unwrap();
+    state.close(A);
+
+    assert_eq!(state.bind(12, addr([10, 0, 0, 1])), Err(EADDRINUSE));
+
+    state.set_option(12, SocketOption::ReuseAddr(true));
+    assert_eq!(state.bind(12, addr([10, 0, 0, 1])), Ok(addr([10, 0, 0, 1])));
+}
+
+#[test]
+fn half_close_ends_the_stream_of_one_side() {
+    let mut state = connected();
+    state.send_to(10, b"last").unwrap();
+    state.shutdown(10).unwrap();
+
+    assert_eq!(state.send_to(10, b"more"), Err(EPIPE));
+    assert_eq!(state.recv_from(11), Ok(b"last".to_vec()));
+    assert_eq!(state.recv_from(11), Ok(Vec::new()));
+    assert_eq!(state.recv_from(11), Ok(Vec::new()));
+
+    // the other side can still send
+    state.send_to(11, b"reply").unwrap();
+    assert_eq!(state.recv_from(10), Ok(b"reply".to_vec()));
+}
+
+#[test]
+fn shutdown_without_connection() {
+    let mut state = two_nodes();
+
+    assert_eq!(state.shutdown(10), Err(ENOTCONN));
+}
+
+#[test]
+fn linger_zero_aborts_the_connection() {
+    let mut state = connected();
+    state.send_to(10, b"data").unwrap();
+    state.set_option(10, SocketOption::Linger(Some(0)));
+    state.close(10);
+
+    assert_eq!(state.recv_from(11), Ok(b"data".to_vec()));
+    assert_eq!(state.recv_from(11), Err(ECONNRESET));
+    assert_eq!(state.recv_from(11), Ok(Vec::new()));
+    assert_eq!(state.send_to(11, b"late"), Err(EPIPE));
+    assert!(state.trace().iter().any(|x| x.trim() == "200 reset 11 from 10.0.0.2:8000"));
+}
+
+#[test]
+fn writing_to_a_closed_peer_breaks_the_pipe() {
+    let mut state = connected();
+    state.close(10);
+    assert_eq!(state.recv_from(11), Ok(Vec::new()));
+
+    // the closed socket answers the first packet with an abort after a round trip
+    state.send_to(11, b"hello").unwrap();
+    state.send_to(11, b"again").unwrap();
+    assert_eq!(state.pending_events(), vec![(Event::Reset(11), 1000)]);
+
+    assert_eq!(state.recv_from(11), Err(ECONNRESET));
+    assert_eq!(state.send_to(11, b"late"), Err(EPIPE));
+}
+
+#[test]
+fn abort_is_taken_on_delivery() {
+    let mut state = connected();
+    state.add_epoll_fd(11, (EPOLLIN | EPOLLOUT) as u32, 2);
+    state.close(10);
+    assert_eq!(state.recv_from(11), Ok(Vec::new()));
+    assert!(!state.take_hang_up());
+
+    // nobody reads the abort, it is taken right away and reported by the next write
+    state.send_to(11, b"hello").unwrap();
+    assert_eq!(state.next_epoll_id(), Some((2, EPOLLIN | EPOLLOUT | EPOLLERR | EPOLLHUP | EPOLLRDHUP)));
+    assert!(state.take_hang_up());
+    assert_eq!(state.pending_events(), vec![]);
+
+    assert_eq!(state.send_to(11, b"late"), Err(ECONNRESET));
+    assert_eq!(state.recv_from(11), Ok(Vec::new()));
+}
+
+#[test]
+fn nagle_holds_small_writes_back() {
+    let mut state = two_nodes();
+    state.connect_to_node(10, addr([10, 0, 0, 1]));
+    state.accept(A, || 11).unwrap();
+
+    // the later writes wait for the acknowledgement of the first one and leave together
+    state.send_to(10, b"a").unwrap();
+    state.send_to(10, b"b").unwrap();
+    state.send_to(10, b"c").unwrap();
+
+    assert_eq!(state.pending_events(), vec![
+        (Event::SendPacket(11, b"a".to_vec()), 400),
+        (Event::SendPacket(11, b"bc".to_vec()), 800)
+    ]);
+}
+
+#[test]
+fn full_buffers_block_the_sender() {
+    let mut state = connected();
+    state.add_epoll_fd(10, EPOLLOUT as u32, 1);
+    state.set_option(10, SocketOption::SendBuffer(1));
+    state.set_option(11, SocketOption::RecvBuffer(1));
+
+    // Linux doubles the sizes and keeps them above a minimum
+    assert_eq!(state.get_option(10, SOL_SOCKET, SO_SNDBUF), Some(4608));
+    assert_eq!(state.get_option(11, SOL_SOCKET, SO_RCVBUF), Some(2304));
+
+    state.send_to(10, &[0; 6000]).unwrap();
+    assert_eq!(state.send_to(10, &[0; 1000]), Err(EWOULDBLOCK));
+    while state.next_epoll_notify_where(|fd| fd == 10).is_some() {}
+
+    // reading makes room again
+    assert_eq!(state.recv_from(11).map(|x| x.len()), Ok(6000));
+    assert_eq!(state.next_epoll_notify_where(|fd| fd == 10), Some((1, EPOLLOUT)));
+    state.send_to(10, &[0; 1000]).unwrap();
+}
+
+#[test]
+fn socket_options_are_reported_back() {
+    let mut state = connected();
+    state.set_option(10, SocketOption::KeepAlive(true));
+    state.set_option(10, SocketOption::KeepIdle(60));
+
+    assert_eq!(state.get_option(10, IPPROTO_TCP, TCP_NODELAY), Some(1));
+    assert_eq!(state.get_option(10, SOL_SOCKET, SO_KEEPALIVE), Some(1));
+    assert_eq!(state.get_option(10, IPPROTO_TCP, TCP_KEEPIDLE), Some(60));
+    assert_eq!(state.get_option(10, IPPROTO_TCP, TCP_KEEPCNT), Some(9));
+    assert_eq!(state.get_option(10, SOL_SOCKET, SO_REUSEADDR), Some(0));
+    assert_eq!(state.get_option(10, SOL_SOCKET, SO_TYPE), None);
+}
+
+const CRASH: &str = r#"
+[[nodes]]
+name = "a"
+addr = "10.0.0.1:8000"
+
+[[nodes]]
+name = "b"
+addr = "10.0.0.2:8000"
+
+[[faults]]
+kind = "crash"
+node = "b"
+at = 1000
+"#;
+
+#[test]
+fn keepalive_detects_a_crashed_peer() {
+    let mut state = State::with_scenario(Some(CRASH.parse().unwrap()));
+    state.add_node(A, addr([10, 0, 0, 1]));
+    state.add_node(B, addr([10, 0, 0, 2]));
+    state.connect_to_node(10, addr([10, 0, 0, 1]));
+    state.accept(A, || 11).unwrap();
+
+    state.set_option(11, SocketOption::KeepAlive(true));
+    state.set_option(11, SocketOption::KeepIdle(1));
+    state.set_option(11, SocketOption::KeepInterval(1));
+    state.set_option(11, SocketOption::KeepCount(3));
+
+    // probes from one second after the accept on, the third one unanswered gives up
+    state.advance(3_000_000);
+    assert_eq!(state.recv_from(11), Err(EWOULDBLOCK));
+
+    state.advance(3_000_200);
+    assert_eq!(state.recv_from(11), Err(ETIMEDOUT));
+    assert_eq!(state.recv_from(11), Ok(Vec::new()));
+    assert!(state.trace().iter().any(|x| x.trim() == "3000200 timed_out 11 10.0.0.2:8000"));
+}
+
+/// Connect four times from the running node to the first node, which listens with `backlog`
+/// and accepts the first connection as `11`
+fn storm(mut state: State, backlog: i32) -> State {
+    state.listen(A, backlog);
+    state.add_epoll_fd(A, EPOLLIN as u32, 42);
+    for fd in &[10, 12, 14, 16] {
+        state.connect_to_node(*fd, addr([10, 0, 0, 1]));
+    }
+
+    assert_eq!(state.next_epoll_id(), Some((42, EPOLLIN | EPOLLOUT)));
+    assert_eq!(state.accept(A, || 11), Some(11));
+
+    // the accept keeps the node busy till 400, meanwhile the other connects fill the queue
+    assert_eq!(state.next_epoll_id(), Some((42, EPOLLIN | EPOLLOUT)));
+
+    state
+}
+
+#[test]
+fn full_accept_queue_drops_the_syn() {
+    let mut state = storm(two_nodes(), 1);
+
+    // the queue holds backlog + 1 connections, the last SYN is sent again after a second
+    assert_eq!(state.pending_events(), vec![
+        (Event::Connect(12, A), 400),
+        (Event::Connect(14, A), 400),
+        (Event::Connect(16, A), 1_000_200)
+    ]);
+    assert!(state.trace().iter().any(|x| x.trim() == "200 overflow 16 10.0.0.1:8000"));
+
+    assert_eq!(state.accept(A, || 13), Some(13));
+    assert_eq!(state.accept(A, || 15), Some(15));
+    assert_eq!(state.accept(A, || 17), Some(17));
+    assert_eq!(state.time(), 1_000_200);
+}
+
+#[test]
+fn full_accept_queue_refuses_if_the_scenario_says_so() {
+    let mut state = State::with_scenario(Some("overflow = \"refuse\"".parse().unwrap()));
+    state.add_node(A, addr([10, 0, 0, 1]));
+    state.add_node(B, addr([10, 0, 0, 2]));
+
+    let mut state = storm(state, 0);
+
+    assert_eq!(state.pending_events(), vec![(Event::Connect(12, A), 400)]);
+    assert_eq!(state.recv_from(14), Err(ECONNREFUSED));
+    assert_eq!(state.recv_from(16), Err(ECONNREFUSED));
+}
+
+#[test]
+fn resolving_keeps_only_the_running_node_busy() {
+    let scenario = r#"
+        [[hosts]]
+        name = "seed.example.org"
+        addrs = ["10.0.0.1"]
+        delay = 1000
+    "#;
+
+    let mut state = State::with_scenario(Some(scenario.parse().unwrap()));
+    state.add_node(A, addr([10, 0, 0, 1]));
+    state.add_node(B, addr([10, 0, 0, 2]));
+    state.connect_to_node(10, addr([10, 0, 0, 1]));
+
+    assert_eq!(state.resolve("seed.example.org"), Some(Ok(vec![Ipv4Addr::new(10, 0, 0, 1)])));
+    assert_eq!(state.time(), 0);
+
+    // the queued connect arrives in time, the next one leaves after the lookup
+    state.connect_to_node(12, addr([10, 0, 0, 1]));
+    assert_eq!(state.pending_events(), vec![(Event::Connect(10, A), 200), (Event::Connect(12, A), 1200)]);
+    assert_eq!(state.accept(A, || 11), Some(11));
+    assert_eq!(state.time(), 200);
+}
+
+#[test]
+fn closed_connect_leaves_the_accept_queue() {
+    let mut state = storm(two_nodes(), 1);
+    state.close(12);
+    state.connect_to_node(18, addr([10, 0, 0, 1]));
+
+    // without the closed connect there is room for the new one
+    assert_eq!(state.pending_events(), vec![
+        (Event::Connect(14, A), 400),
+        (Event::Connect(18, A), 600),
+        (Event::Connect(16, A), 1_000_200)
+    ]);
+    assert_eq!(state.accept(A, || 15), Some(15));
+    assert_eq!(state.accept(A, || 19), Some(19));
+    assert_eq!(state.connections(), vec![(10, 11), (11, 10), (14, 15), (15, 14), (18, 19), (19, 18)]);
+}
+
+#[test]
+fn monotonic_clock_never_goes_back() {
+    let mut state = State::with_scenario(Some("epoch = 1500000000".parse().unwrap()));
+    state.add_node(A, addr([10, 0, 0, 1]));
+    state.add_node(B, addr([10, 0, 0, 2]));
+    state.connect_to_node(10, addr([10, 0, 0, 1]));
+    state.accept(A, || 11).unwrap();
+
+    // the accept keeps the first node busy till 400, the second one is still at 200
+    state.set_current_node(Some(addr([10, 0, 0, 1])));
+    assert_eq!(state.clock(clock::Kind::Monotonic), Some(1_000_400));
+    state.set_current_node(Some(addr([10, 0, 0, 2])));
+
+    assert_eq!(state.clock(clock::Kind::Realtime), Some(1_500_000_000_000_200));
+    assert_eq!(state.clock(clock::Kind::Monotonic), Some(1_000_400));
+}
+
+const REGIONS: &str = r#"
+seed = 3
+default_latency = "lan"
+
+[latency.lan]
+latency = 100
+
+[latency.wan]
+latency = 30000
+
+[[ranges]]
+name = "eu"
+network = "10.1.0.0/16"
+
+[[ranges]]
+name = "us"
+network = "10.2.0.0/16"
+
+[[links]]
+from = "eu"
+to = "us"
+latency = "wan"
+
+[[nodes]]
+name = "late"
+addr = "10.1.0.2:8000"
+join = 5000
+
+[[faults]]
+kind = "partition"
+between = ["eu", "us"]
+at = 100000
+until = 200000
+"#;
+
+fn regions() -> State {
+    let scenario = REGIONS.parse::<Scenario>().unwrap();
+
+    let mut state = State::with_scenario(Some(scenario));
+    state.add_node(A, addr([10, 1, 0, 1]));
+    state.add_node(B, addr([10, 2, 0, 1]));
+    state.add_node(C, addr([10, 1, 0, 2]));
+
+    state
+}
+
+#[test]
+fn scenario_links_set_the_latency() {
+    let mut state = regions();
+    state.set_current_node(Some(addr([10, 2, 0, 1])));
+    state.connect_to_node(10, addr([10, 1, 0, 1]));
+    assert_eq!(state.next_event(), Some((Event::Connect(10, A), 30000)));
+
+    state.accept(A, || 11).unwrap();
+    state.set_current_node(Some(addr([10, 1, 0, 1])));
+    state.connect_to_node(12, addr([10, 1, 0, 2]));
+
+    // within a range the default latency applies, after the accept kept the node busy
+    assert_eq!(state.next_event(), Some((Event::Connect(12, C), 30300)));
+}
+
+#[test]
+fn scenario_nodes_talk_after_joining() {
+    let mut state = regions();
+    state.set_current_node(Some(addr([10, 1, 0, 1])));
+    state.connect_to_node(10, addr([10, 1, 0, 2]));
+
+    assert_eq!(state.next_event(), Some((Event::Connect(10, C), 5100)));
+}
+
+#[test]
+fn scenario_partition_holds_packets_back() {
+    let mut state = regions();
+    state.advance(150000);
+    state.set_current_node(Some(addr([10, 2, 0, 1])));
+    state.connect_to_node(10, addr([10, 1, 0, 1]));
+
+    assert_eq!(state.next_event(), Some((Event::Connect(10, A), 230000)));
+}
+
+#[test]
+fn region_presets_spread_the_nodes() {
+    let scenario = regions::preset(&["eu", "apac"], 3).unwrap().parse::<Scenario>().unwrap();
+    let nodes = scenario.nodes();
+
+    assert_eq!(nodes.iter().map(|x| x.name.as_str()).collect::<Vec<_>>(), vec!["eu 0", "apac 0", "eu 1"]);
+    assert_eq!(nodes[0].contact, None);
+    assert_eq!(nodes[1].contact, Some(nodes[0].addr));
+
+    let latency = scenario.latency(nodes[1].addr.ip(), nodes[2].addr.ip()).unwrap();
+    assert_eq!((latency.latency, latency.jitter), (125000, 12500));
+    assert_eq!(scenario.latency(nodes[0].addr.ip(), nodes[2].addr.ip()).unwrap().latency, 10000);
+    assert!(regions::preset(&["mars"], 3).is_err());
+}
+
+/// Connect from the byzantine node to an honest one, with `attacks` as TOML array
+fn byzantine(attacks: &str) -> State {
+    let scenario = format!(r#"
+[[nodes]]
+name = "honest"
+addr = "10.0.0.1:8000"
+
+[[nodes]]
+name = "liar"
+addr = "10.0.0.2:8000"
+
+[[byzantine]]
+node = "liar"
+attacks = {}
+"#, attacks);
+
+    let mut state = State::with_scenario(Some(scenario.parse().unwrap()));
+    state.add_node(A, addr([10, 0, 0, 1]));
+    state.add_node(B, addr([10, 0, 0, 2]));
+    state.connect_to_node(10, addr([10, 0, 0, 1]));
+    state.accept(A, || 11).unwrap();
+    state.set_option(10, SocketOption::NoDelay(true));
+
+    state
+}
+
+fn presence(id: &str, ip: [u8; 4]) -> dissect::PeerPresence {
+    dissect::PeerPresence { id: id.into(), addr: addr(ip).into(), writer: None }
+}
+
+#[test]
+fn byzantine_node_drops_pushes_and_lies_about_peers() {
+    let mut state = byzantine(r#"["drop-push", "lie-peers"]"#);
+    state.send_to(10, &dissect::encode(&dissect::Packet::Push(vec![1, 2, 3]))).unwrap();
+    assert_eq!(state.pending_events(), Vec::new());
+
+    // the answer is only mutated once it is complete
+    let answer = dissect::encode(&dissect::Packet::GetPeers(Some(vec![presence("Node 5", [10, 0, 0, 5])])));
+    state.send_to(10, &answer[..4]).unwrap();
+    assert_eq!(state.pending_events(), Vec::new());
+    state.send_to(10, &answer[4..]).unwrap();
+
+    let buf = state.recv_from(11).unwrap();
+    let frames = dissect::Dissector::new().feed(11, &buf);
+    assert_eq!(frames.iter().map(|x| x.to_string()).collect::<Vec<_>>(), vec!["GetPeers([Liar 0@192.0.2.1:8000])"]);
+}
+
+#[test]
+fn byzantine_node_replays_frames_with_malformed_headers() {
+    let mut state = byzantine(r#"["replay", "malformed"]"#);
+    let join = dissect::encode(&dissect::Packet::Join(presence("liar", [10, 0, 0, 2])));
+    state.send_to(10, &join).unwrap();
+    state.send_to(10, &dissect::encode(&dissect::Packet::Push(vec![7]))).unwrap();
+
+    let first = state.recv_from(11).unwrap();
+    assert_eq!(dissect::parse_header(&first), Some((dissect::VERSION, 5, 0xffff_ffff)));
+    assert_eq!(first[5..], join[2..]);
+
+    // the second packet carries the push and the join again
+    let second = state.recv_from(11).unwrap();
+    assert!(second.ends_with(&first));
+}
+
+#[test]
+fn honest_nodes_send_their_frames_untouched() {
+    let mut state = byzantine(r#"["drop-push"]"#);
+    let push = dissect::encode(&dissect::Packet::Push(vec![1]));
+    state.send_to(11, &push).unwrap();
+
+    assert_eq!(state.recv_from(10), Ok(push));
+}
+
+#[test]
+fn dissector_starts_over_on_a_reused_fd() {
+    let push = dissect::encode(&dissect::Packet::Push(vec![1]));
+    let mut dissector = dissect::Dissector::new();
+
+    assert!(dissector.feed(11, &push[..3]).is_empty());
+    assert!(!dissector.feed(12, &[0xfc, 0]).is_empty());
+    dissector.reset(11);
+    dissector.reset(12);
+
+    // neither the fragment nor the foreign protocol of the closed sockets remain
+    assert_eq!(dissector.pending(11), 0);
+    assert_eq!(dissector.feed(11, &push).len(), 1);
+    assert_eq!(dissector.feed(12, &push).len(), 1);
+}
+
+#[test]
+fn partial_output_is_completed_at_exit() {
+    let mut output = output::Output::new(true);
+    let node = Some(addr([10, 0, 0, 1]));
+
+    assert_eq!(output.write(1, b"first\nsec", 200, node), b"[       200 10.0.0.1:8000] first\n".to_vec());
+    assert_eq!(output.write(1, b"ond", 300, node), Vec::<u8>::new());
+    assert_eq!(output.flush(400, None), vec![(1, b"[       400 -] second\n".to_vec())]);
+    assert_eq!(output.take_pending().len(), 2);
+    assert!(output.flush(500, None).is_empty());
+}
+
+#[test]
+fn metrics_count_the_traffic_of_every_node() {
+    let mut state = connected();
+    state.send_to(10, b"hello").unwrap();
+    state.send_to(10, b"again").unwrap();
+    assert_eq!(state.recv_from(11), Ok(b"hello".to_vec()));
+
+    let metrics = state.metrics();
+    assert_eq!((metrics.time, metrics.queued, metrics.delivered), (400, 1, 2));
+    assert_eq!(metrics.nodes[&addr([10, 0, 0, 2])], metrics::Counters {
+        sent_messages: 2,
+        sent_bytes: 10,
+        ..Default::default()
+    });
+    assert_eq!(metrics.nodes[&addr([10, 0, 0, 1])].received_bytes, 5);
+    assert_eq!(metrics.connections.values().sum::<usize>(), 2);
+
+    let text = metrics.render(0.0);
+    assert!(text.contains("# TYPE peersim_queue_depth gauge\npeersim_queue_depth 1\n"));
+    assert!(text.contains("peersim_node_sent_bytes_total{node=\"10.0.0.2:8000\"} 10\n"));
+}
+
+/// Run by `delivered_events_stop_the_run_and_save_a_checkpoint` in a process of its own,
+/// as reaching `PEERSIM_UNTIL` exits the process
+#[test]
+#[ignore]
+fn until_and_checkpoint_child() {
+    if env::var("PEERSIM_UNTIL").is_err() {
+        return;
+    }
+
+    // neither a scenario epoch nor host names, only delivered events move the clock
+    let mut state = State::new();
+    state.add_node(A, addr([10, 0, 0, 1]));
+    state.add_node(B, addr([10, 0, 0, 2]));
+    state.connect_to_node(10, addr([10, 0, 0, 1]));
+    assert_eq!(state.accept(A, || 11), Some(11));
+
+    state.set_option(10, SocketOption::NoDelay(true));
+    state.send_to(10, b"hello").unwrap();
+    let _ = state.recv_from(11);
+
+    unreachable!("the run didn't stop at {}", state.time());
+}
+
+#[test]
+fn checkpoints_keep_the_socket_state() {
+    let mut state = connected();
+    state.bind(C, addr([10, 0, 0, 3])).unwrap();
+    state.listen(C, 1);
+    state.set_option(10, SocketOption::KeepAlive(true));
+    state.set_option(11, SocketOption::SendBuffer(8192));
+    state.send_to(10, b"hello").unwrap();
+    state.shutdown(10).unwrap();
+
+    let snapshot = state.snapshot();
+    assert!(snapshot.options.iter().any(|&(fd, x)| fd == 11 && x.sndbuf == 16384));
+    assert_eq!(snapshot.ports, vec![(addr([10, 0, 0, 3]), vec![C], false, 0)]);
+    assert_eq!(snapshot.shut, vec![10]);
+    assert_eq!(snapshot.unread, vec![(11, 5)]);
+
+    let path = env::temp_dir().join(format!("peersim-snapshot-{}", process::id()));
+    let checkpoint = checkpoint::Checkpoint { scenario: None, snapshot: snapshot.clone(), logs: Vec::new() };
+    checkpoint.save(path.to_str().unwrap()).unwrap();
+    let loaded = checkpoint::Checkpoint::load(path.to_str().unwrap()).unwrap();
+    let _ = fs::remove_file(&path);
+
+    assert_eq!(loaded.snapshot, snapshot);
+    assert_eq!(loaded.snapshot.digest(), snapshot.digest());
+
+    // a changed option alone is a divergence
+    state.set_option(11, SocketOption::NoDelay(false));
+    assert_ne!(state.snapshot().digest(), snapshot.digest());
+}
+
+#[test]
+fn delivered_events_stop_the_run_and_save_a_checkpoint() {
+    let path = env::temp_dir().join(format!("peersim-checkpoint-{}", process::id()));
+
+    let output = Command::new(env::current_exe().unwrap())
+        .args(["until_and_checkpoint_child", "--exact", "--ignored", "--nocapture"])
+        .env("PEERSIM_UNTIL", "400")
+        .env("PEERSIM_CHECKPOINT", format!("200:{}", path.display()))
+        .env_remove(scenario::SCENARIO_ENV)
+        .output()
+        .unwrap();
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}", stdout);
+    assert!(stdout.contains("Checkpoint: saved"), "{}", stdout);
+    assert!(stdout.contains("Simulation reached 400, stopping"), "{}", stdout);
+
+    let checkpoint = checkpoint::Checkpoint::load(path.to_str().unwrap()).unwrap();
+    let _ = fs::remove_file(&path);
+    assert_eq!(checkpoint.snapshot.timer, 200);
+}
+
+#[test]
+fn pct_delays_stay_inside_large_windows() {
+    let window = u64::MAX / 2;
+    let mut explore = explore::Explore::new(1, explore::Strategy::Pct { depth: 2 }, window, 10);
+    let node = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 1);
+
+    for _ in 0..10 {
+        assert!(explore.delay(Some(node)) <= window);
+    }
+}